@@ -0,0 +1,432 @@
+//! Writing and reading MJPEG streams in AVI files with the OpenDML extensions for files over
+//! 1 GB.
+//!
+//! The layout is what ffmpeg writes and every player reads:
+//!
+//! ```text
+//! RIFF 'AVI ' hdrl(avih, strl(strh, strf, indx), odml(dmlh)) movi(00dc.. ix00) idx1
+//! RIFF 'AVIX' movi(00dc.. ix00)
+//! ...
+//! ```
+//!
+//! Players without OpenDML support see the first RIFF with its `idx1` as a complete file.
+
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::time::Duration;
+
+/// Entries reserved in the super index, each one stands for a RIFF of up to `riff_limit`.
+const SUPER_INDEX_ENTRIES: usize = 256;
+const AVIIF_KEYFRAME: u32 = 0x10;
+const AVIF_HASINDEX: u32 = 0x10;
+const AVIF_TRUSTCKTYPE: u32 = 0x800;
+
+pub(crate) struct AviWriter<W: Write + Seek> {
+    out: W,
+    size: (u32, u32),
+    fourcc: [u8; 4],
+    /// Position of the first RIFF in `out`.
+    start: u64,
+    /// Position of the next byte written to `out`.
+    pos: u64,
+    /// Start a new RIFF when the current one would grow beyond this many bytes.
+    pub(crate) riff_limit: u64,
+    riff_start: u64,
+    /// Position of the 'movi' fourcc of the current RIFF, chunk offsets are relative to it.
+    movi_start: u64,
+    /// Data offset relative to `movi_start` and length of the chunks in the current RIFF.
+    chunks: Vec<(u32, u32)>,
+    /// Offset, size and frame count of the `ix00` chunk of every finished RIFF.
+    super_index: Vec<(u64, u32, u32)>,
+    first_riff_frames: u32,
+    frames: u32,
+    max_chunk_len: u32,
+    bytes: u64,
+    first_timestamp: Option<Duration>,
+    last_timestamp: Duration,
+}
+
+impl<W: Write + Seek> AviWriter<W> {
+    /// Writes the headers with placeholders which [AviWriter::finish] fills in.
+    pub(crate) fn new(mut out: W, size: (u32, u32), fourcc: [u8; 4]) -> io::Result<Self> {
+        let pos = out.stream_position()?;
+        let mut avi = Self {
+            out,
+            size,
+            fourcc,
+            start: pos,
+            pos,
+            riff_limit: 1 << 30,
+            riff_start: pos,
+            movi_start: 0,
+            chunks: Vec::new(),
+            super_index: Vec::new(),
+            first_riff_frames: 0,
+            frames: 0,
+            max_chunk_len: 0,
+            bytes: 0,
+            first_timestamp: None,
+            last_timestamp: Duration::ZERO,
+        };
+        avi.write(b"RIFF\0\0\0\0AVI ")?;
+        let header = avi.header();
+        avi.write(&header)?;
+        avi.start_movi()?;
+        Ok(avi)
+    }
+
+    pub(crate) fn size(&self) -> (u32, u32) {
+        self.size
+    }
+
+    pub(crate) fn frames(&self) -> u32 {
+        self.frames
+    }
+
+    /// Appends one JPEG as a frame captured at `timestamp`.
+    pub(crate) fn write_frame(&mut self, jpeg: &[u8], timestamp: Duration) -> io::Result<()> {
+        let len: u32 = jpeg.len().try_into().map_err(|_| too_large())?;
+        let padded = len as u64 + len as u64 % 2;
+        // room for the chunk, its ix00 and idx1 entries and the ix00 header
+        let index_len = (self.chunks.len() as u64 + 1) * 24 + 32;
+        if !self.chunks.is_empty()
+            && self.pos - self.riff_start + 8 + padded + index_len > self.riff_limit
+        {
+            if self.super_index.len() + 1 == SUPER_INDEX_ENTRIES {
+                return Err(too_large());
+            }
+            self.finish_riff()?;
+            self.riff_start = self.pos;
+            self.write(b"RIFF\0\0\0\0AVIX")?;
+            self.start_movi()?;
+        }
+
+        self.chunks.push(((self.pos + 8 - self.movi_start) as u32, len));
+        self.write(b"00dc")?;
+        self.write(&len.to_le_bytes())?;
+        self.write(jpeg)?;
+        if len % 2 == 1 {
+            self.write(&[0])?;
+        }
+
+        self.frames += 1;
+        self.max_chunk_len = self.max_chunk_len.max(len);
+        self.bytes += len as u64;
+        self.first_timestamp.get_or_insert(timestamp);
+        self.last_timestamp = timestamp;
+        Ok(())
+    }
+
+    /// Writes the indexes, fills in the headers and returns the writer positioned at the end.
+    pub(crate) fn finish(mut self) -> io::Result<W> {
+        self.finish_riff()?;
+        let end = self.pos;
+        self.out.seek(SeekFrom::Start(self.start + 12))?;
+        let header = self.header();
+        self.out.write_all(&header)?;
+        self.out.seek(SeekFrom::Start(end))?;
+        self.out.flush()?;
+        Ok(self.out)
+    }
+
+    fn write(&mut self, data: &[u8]) -> io::Result<()> {
+        self.out.write_all(data)?;
+        self.pos += data.len() as u64;
+        Ok(())
+    }
+
+    fn start_movi(&mut self) -> io::Result<()> {
+        self.write(b"LIST\0\0\0\0")?;
+        self.movi_start = self.pos;
+        self.write(b"movi")
+    }
+
+    /// Ends the movi list of the current RIFF with a standard index, adds idx1 to the first
+    /// RIFF and writes the list and RIFF sizes.
+    fn finish_riff(&mut self) -> io::Result<()> {
+        let ix_pos = self.pos;
+        let mut ix = Vec::with_capacity(32 + self.chunks.len() * 8);
+        ix.extend(b"ix00");
+        ix.extend((24 + self.chunks.len() as u32 * 8).to_le_bytes());
+        ix.extend(2u16.to_le_bytes()); // wLongsPerEntry
+        ix.push(0); // bIndexSubType
+        ix.push(1); // bIndexType: AVI_INDEX_OF_CHUNKS
+        ix.extend((self.chunks.len() as u32).to_le_bytes());
+        ix.extend(b"00dc");
+        ix.extend(self.movi_start.to_le_bytes()); // qwBaseOffset
+        ix.extend(0u32.to_le_bytes());
+        for &(offset, len) in &self.chunks {
+            ix.extend(offset.to_le_bytes());
+            ix.extend(len.to_le_bytes()); // bit 31 clear: key frame
+        }
+        self.write(&ix)?;
+        self.patch_size(self.movi_start - 4, self.pos)?;
+
+        if self.super_index.is_empty() {
+            self.first_riff_frames = self.chunks.len() as u32;
+            let mut idx1 = Vec::with_capacity(8 + self.chunks.len() * 16);
+            idx1.extend(b"idx1");
+            idx1.extend((self.chunks.len() as u32 * 16).to_le_bytes());
+            for &(offset, len) in &self.chunks {
+                idx1.extend(b"00dc");
+                idx1.extend(AVIIF_KEYFRAME.to_le_bytes());
+                idx1.extend((offset - 8).to_le_bytes()); // points at the chunk header
+                idx1.extend(len.to_le_bytes());
+            }
+            self.write(&idx1)?;
+        }
+        self.patch_size(self.riff_start + 4, self.pos)?;
+
+        self.super_index.push((ix_pos, ix.len() as u32, self.chunks.len() as u32));
+        self.chunks.clear();
+        Ok(())
+    }
+
+    /// Writes the size of the chunk whose size field is at `at` and which ends at `end`.
+    fn patch_size(&mut self, at: u64, end: u64) -> io::Result<()> {
+        self.out.seek(SeekFrom::Start(at))?;
+        self.out.write_all(&((end - at - 4) as u32).to_le_bytes())?;
+        self.out.seek(SeekFrom::Start(self.pos))?;
+        Ok(())
+    }
+
+    /// Average time between frames, 30 fps until there are two frames with timestamps.
+    fn frame_duration_us(&self) -> u32 {
+        let elapsed = self.last_timestamp.saturating_sub(self.first_timestamp.unwrap_or_default());
+        match self.frames {
+            n if n > 1 && !elapsed.is_zero() => {
+                (elapsed.as_micros() / (n as u128 - 1)).clamp(1, u32::MAX as u128) as u32
+            }
+            _ => 33_333,
+        }
+    }
+
+    /// The hdrl list, always of the same length so it can be rewritten in place.
+    fn header(&self) -> Vec<u8> {
+        let (w, h) = self.size;
+        let us_per_frame = self.frame_duration_us();
+        let seconds = (self.frames.max(1) as u64 * us_per_frame as u64).div_ceil(1_000_000);
+        let bytes_per_sec = (self.bytes / seconds).min(u32::MAX as u64) as u32;
+
+        let mut avih = Vec::with_capacity(56);
+        for value in [
+            us_per_frame,
+            bytes_per_sec,
+            0, // dwPaddingGranularity
+            AVIF_HASINDEX | AVIF_TRUSTCKTYPE,
+            self.first_riff_frames,
+            0, // dwInitialFrames
+            1, // dwStreams
+            self.max_chunk_len,
+            w,
+            h,
+            0,
+            0,
+            0,
+            0,
+        ] {
+            avih.extend(value.to_le_bytes());
+        }
+
+        let mut strh = Vec::with_capacity(56);
+        strh.extend(b"vids");
+        strh.extend(self.fourcc);
+        // dwFlags, wPriority and wLanguage, dwInitialFrames, dwScale, dwRate, dwStart,
+        // dwLength, dwSuggestedBufferSize
+        for value in [0, 0, 0, us_per_frame, 1_000_000, 0, self.frames, self.max_chunk_len] {
+            strh.extend(value.to_le_bytes());
+        }
+        strh.extend(u32::MAX.to_le_bytes()); // dwQuality: default
+        strh.extend(0u32.to_le_bytes()); // dwSampleSize: varies
+        for value in [0, 0, w as u16, h as u16] {
+            strh.extend(value.to_le_bytes()); // rcFrame
+        }
+
+        let mut strf = Vec::with_capacity(40);
+        strf.extend(40u32.to_le_bytes());
+        strf.extend(w.to_le_bytes());
+        strf.extend(h.to_le_bytes());
+        strf.extend(1u16.to_le_bytes()); // biPlanes
+        strf.extend(24u16.to_le_bytes()); // biBitCount
+        strf.extend(self.fourcc);
+        strf.extend((w * h * 3).to_le_bytes());
+        strf.extend([0; 16]);
+
+        let mut indx = Vec::with_capacity(24 + SUPER_INDEX_ENTRIES * 16);
+        indx.extend(4u16.to_le_bytes()); // wLongsPerEntry
+        indx.push(0); // bIndexSubType
+        indx.push(0); // bIndexType: AVI_INDEX_OF_INDEXES
+        indx.extend((self.super_index.len() as u32).to_le_bytes());
+        indx.extend(b"00dc");
+        indx.extend([0; 12]);
+        for &(offset, size, frames) in &self.super_index {
+            indx.extend(offset.to_le_bytes());
+            indx.extend(size.to_le_bytes());
+            indx.extend(frames.to_le_bytes());
+        }
+        indx.resize(24 + SUPER_INDEX_ENTRIES * 16, 0);
+
+        let mut dmlh = self.frames.to_le_bytes().to_vec();
+        dmlh.resize(248, 0);
+
+        let strl =
+            list(b"strl", &[chunk(b"strh", &strh), chunk(b"strf", &strf), chunk(b"indx", &indx)]);
+        let odml = list(b"odml", &[chunk(b"dmlh", &dmlh)]);
+        list(b"hdrl", &[chunk(b"avih", &avih), strl, odml])
+    }
+}
+
+/// Reads the frames of the MJPEG video stream of an AVI file. Frames are found by walking the
+/// movi lists of all RIFFs, so files without or with broken indexes play as well.
+pub(crate) struct AviReader<R: Read + Seek> {
+    input: R,
+    size: (u32, u32),
+    /// A frame lasts `scale / rate` seconds.
+    scale: u32,
+    rate: u32,
+    /// Offset and length of every frame.
+    frames: Vec<(u64, u32)>,
+}
+
+impl<R: Read + Seek> AviReader<R> {
+    pub(crate) fn new(mut input: R) -> io::Result<Self> {
+        let end = input.seek(SeekFrom::End(0))?;
+        let mut avi = Self { input, size: (0, 0), scale: 0, rate: 0, frames: Vec::new() };
+        let mut stream = None;
+        let mut pos = 0;
+        while pos + 12 <= end {
+            let (id, len) = avi.chunk_header(pos)?;
+            let form = avi.fourcc()?;
+            if &id != b"RIFF" || !(form == *b"AVI " || pos > 0 && form == *b"AVIX") {
+                return Err(invalid_data("no AVI file"));
+            }
+            let riff_end = (pos + 8 + len as u64).min(end);
+            avi.walk(pos + 12, riff_end, &mut stream)?;
+            pos = riff_end + len as u64 % 2;
+        }
+        if stream.is_none() {
+            return Err(invalid_data("no MJPEG video stream"));
+        }
+        Ok(avi)
+    }
+
+    pub(crate) fn size(&self) -> (u32, u32) {
+        self.size
+    }
+
+    pub(crate) fn frames(&self) -> usize {
+        self.frames.len()
+    }
+
+    pub(crate) fn timestamp(&self, index: usize) -> Duration {
+        let nanos = index as u128 * self.scale as u128 * 1_000_000_000 / self.rate.max(1) as u128;
+        Duration::from_nanos(nanos as u64)
+    }
+
+    /// The JPEG of frame `index`.
+    pub(crate) fn read_frame(&mut self, index: usize) -> io::Result<Vec<u8>> {
+        let (offset, len) = self.frames[index];
+        let mut jpeg = vec![0; len as usize];
+        self.input.seek(SeekFrom::Start(offset))?;
+        self.input.read_exact(&mut jpeg)?;
+        Ok(jpeg)
+    }
+
+    fn chunk_header(&mut self, pos: u64) -> io::Result<([u8; 4], u32)> {
+        self.input.seek(SeekFrom::Start(pos))?;
+        let id = self.fourcc()?;
+        Ok((id, u32::from_le_bytes(self.fourcc()?)))
+    }
+
+    fn fourcc(&mut self) -> io::Result<[u8; 4]> {
+        let mut fourcc = [0; 4];
+        self.input.read_exact(&mut fourcc)?;
+        Ok(fourcc)
+    }
+
+    /// Collects the frames of the chunks from `pos` to `end`. `stream` is the two digit
+    /// number of the video stream once the header list is read.
+    fn walk(&mut self, mut pos: u64, end: u64, stream: &mut Option<[u8; 2]>) -> io::Result<()> {
+        while pos + 8 <= end {
+            let (id, len) = self.chunk_header(pos)?;
+            let data_end = (pos + 8 + len as u64).min(end);
+            match &id {
+                b"LIST" if len >= 4 => match &self.fourcc()? {
+                    b"hdrl" => {
+                        if data_end < pos + 12 {
+                            return Err(invalid_data("truncated header list"));
+                        }
+                        let mut hdrl = vec![0; (data_end - pos - 12) as usize];
+                        self.input.read_exact(&mut hdrl)?;
+                        *stream = self.read_header(&hdrl)?;
+                    }
+                    b"movi" | b"rec " => self.walk(pos + 12, data_end, stream)?,
+                    _ => {}
+                },
+                [a, b, b'd', b'c'] if Some([*a, *b]) == *stream => {
+                    self.frames.push((pos + 8, (data_end - pos - 8) as u32));
+                }
+                _ => {}
+            }
+            pos = data_end + len as u64 % 2;
+        }
+        Ok(())
+    }
+
+    /// Finds the first video stream in the header list and returns its number.
+    fn read_header(&mut self, hdrl: &[u8]) -> io::Result<Option<[u8; 2]>> {
+        let u32_at = |data: &[u8], pos: usize| {
+            data.get(pos..pos + 4).map_or(0, |b| u32::from_le_bytes(b.try_into().unwrap()))
+        };
+        let strls =
+            sub_chunks(hdrl).filter(|(id, data)| id == b"LIST" && data.starts_with(b"strl"));
+        for (index, (_, strl)) in strls.enumerate() {
+            let strl: Vec<_> = sub_chunks(&strl[4..]).collect();
+            let strh = strl.iter().find(|(id, _)| id == b"strh").map_or(&[][..], |c| c.1);
+            let strf = strl.iter().find(|(id, _)| id == b"strf").map_or(&[][..], |c| c.1);
+            if !strh.starts_with(b"vids") {
+                continue;
+            }
+            if !strf.get(16..20).is_some_and(|c| c.eq_ignore_ascii_case(b"MJPG")) {
+                return Err(invalid_data("only MJPEG video is supported"));
+            }
+            let height = u32_at(strf, 8) as i32;
+            self.size = (u32_at(strf, 4), height.unsigned_abs());
+            (self.scale, self.rate) = (u32_at(strh, 20), u32_at(strh, 24));
+            let digits = format!("{:02}", index % 100).into_bytes();
+            return Ok(Some([digits[0], digits[1]]));
+        }
+        Ok(None)
+    }
+}
+
+/// The chunks in `data` as id and contents.
+fn sub_chunks(mut data: &[u8]) -> impl Iterator<Item = ([u8; 4], &[u8])> {
+    std::iter::from_fn(move || {
+        let id: [u8; 4] = data.get(..4)?.try_into().unwrap();
+        let len = u32::from_le_bytes(data.get(4..8)?.try_into().unwrap()) as usize;
+        let contents = &data[8..(8 + len).min(data.len())];
+        data = &data[(8 + len + len % 2).min(data.len())..];
+        Some((id, contents))
+    })
+}
+
+fn chunk(id: &[u8; 4], data: &[u8]) -> Vec<u8> {
+    let mut out = id.to_vec();
+    out.extend((data.len() as u32).to_le_bytes());
+    out.extend(data);
+    out
+}
+
+fn list(id: &[u8; 4], children: &[Vec<u8>]) -> Vec<u8> {
+    let mut data = id.to_vec();
+    children.iter().for_each(|child| data.extend(child));
+    chunk(b"LIST", &data)
+}
+
+fn invalid_data(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+fn too_large() -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, "too large for AVI")
+}
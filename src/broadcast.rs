@@ -0,0 +1,180 @@
+//! Capturing once for any number of network clients, shared by the `http` and `rtsp` servers.
+
+use std::collections::VecDeque;
+use std::io;
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::time::Duration;
+
+use crate::{Camera, Frame, PixelFormat};
+
+/// The latest encoded frame of a capture loop, handed to clients waiting for it.
+pub(crate) struct Broadcast {
+    state: Mutex<State>,
+    changed: Condvar,
+}
+
+pub(crate) struct Encoded {
+    pub(crate) data: Vec<u8>,
+    pub(crate) timestamp: Duration,
+}
+
+#[derive(Default)]
+pub(crate) struct State {
+    pub(crate) stopped: bool,
+    /// Clients waiting for frames.
+    pub(crate) clients: usize,
+    pub(crate) device: String,
+    pub(crate) size: (u32, u32),
+    /// Native format of the camera frames.
+    pub(crate) format: Option<PixelFormat>,
+    frame: Option<Arc<Encoded>>,
+    /// Number of frames captured so far.
+    sequence: u64,
+    timestamps: VecDeque<Duration>,
+}
+
+impl State {
+    /// Frame rate of the last second of frames.
+    pub(crate) fn fps(&self) -> f64 {
+        match (self.timestamps.front(), self.timestamps.back()) {
+            (Some(first), Some(last)) if last > first => {
+                (self.timestamps.len() - 1) as f64 / (*last - *first).as_secs_f64()
+            }
+            _ => 0.0,
+        }
+    }
+
+    /// Keeps the timestamps of the last second for [State::fps]. Starts over when they go
+    /// backwards, like after the camera restarted or changed the device.
+    fn push_timestamp(&mut self, timestamp: Duration) {
+        if self.timestamps.back().is_some_and(|&last| timestamp < last) {
+            self.timestamps.clear();
+        }
+        self.timestamps.push_back(timestamp);
+        while self.timestamps.len() > 2
+            && timestamp.saturating_sub(self.timestamps[0]) > Duration::from_secs(1)
+        {
+            self.timestamps.pop_front();
+        }
+    }
+}
+
+impl Broadcast {
+    pub(crate) fn new() -> Self {
+        Self { state: Mutex::default(), changed: Condvar::new() }
+    }
+
+    pub(crate) fn state(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap()
+    }
+
+    pub(crate) fn stop(&self) {
+        self.state().stopped = true;
+        self.changed.notify_all();
+    }
+
+    /// Counts as client until dropped and receives the frames captured from now on.
+    pub(crate) fn client(&self) -> Client<'_> {
+        let mut state = self.state();
+        state.clients += 1;
+        self.changed.notify_all();
+        Client { broadcast: self, sequence: state.sequence }
+    }
+
+    /// Captures and encodes frames while there are clients, until stopped. Starts the camera
+    /// when the first client arrives and stops it when the last one leaves. Stops the
+    /// broadcast when it returns, also when the camera fails.
+    pub(crate) fn run(
+        &self,
+        camera: &Camera,
+        encode: impl FnMut(&Frame) -> io::Result<Vec<u8>>,
+    ) -> io::Result<()> {
+        let result = self.capture(camera, encode);
+        self.stop();
+        result
+    }
+
+    fn capture(
+        &self,
+        camera: &Camera,
+        mut encode: impl FnMut(&Frame) -> io::Result<Vec<u8>>,
+    ) -> io::Result<()> {
+        self.state().device = camera.device_name();
+        loop {
+            let mut state = self.state();
+            while state.clients == 0 && !state.stopped {
+                state = self.changed.wait(state).unwrap();
+            }
+            if state.stopped {
+                return Ok(());
+            }
+            drop(state);
+
+            camera.start();
+            self.state().timestamps.clear();
+            while self.is_capturing() {
+                let frame = camera
+                    .wait_for_frame()
+                    .ok_or_else(|| io::Error::other("camera delivered no frame"))?;
+                let encoded = Encoded { data: encode(&frame)?, timestamp: frame.timestamp() };
+
+                let mut state = self.state();
+                state.frame = Some(Arc::new(encoded));
+                state.sequence += 1;
+                state.size = frame.size_u32();
+                state.format = Some(frame.native().map_or(frame.format(), |data| data.format()));
+                state.push_timestamp(frame.timestamp());
+                self.changed.notify_all();
+            }
+            camera.stop();
+        }
+    }
+
+    fn is_capturing(&self) -> bool {
+        let state = self.state();
+        state.clients > 0 && !state.stopped
+    }
+}
+
+pub(crate) struct Client<'a> {
+    broadcast: &'a Broadcast,
+    sequence: u64,
+}
+
+impl Client<'_> {
+    /// Waits for a frame newer than the last one, `None` when the broadcast stops.
+    pub(crate) fn next_frame(&mut self) -> Option<Arc<Encoded>> {
+        let mut state = self.broadcast.state();
+        while !state.stopped && (state.sequence <= self.sequence || state.frame.is_none()) {
+            state = self.broadcast.changed.wait(state).unwrap();
+        }
+        self.sequence = state.sequence;
+        state.frame.clone().filter(|_| !state.stopped)
+    }
+}
+
+impl Drop for Client<'_> {
+    fn drop(&mut self) {
+        self.broadcast.state().clients -= 1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fps_survives_timestamps_going_backwards() {
+        let mut state = State::default();
+        for ms in [0, 100, 200, 1300] {
+            state.push_timestamp(Duration::from_millis(ms));
+        }
+        assert_eq!(state.timestamps.len(), 2);
+        assert_eq!(state.fps(), 1.0 / 1.1);
+        // a restarted camera counts from zero again
+        state.push_timestamp(Duration::from_millis(50));
+        state.push_timestamp(Duration::from_millis(100));
+        assert_eq!(state.timestamps, [Duration::from_millis(50), Duration::from_millis(100)]);
+        assert_eq!(state.fps(), 20.0);
+    }
+}
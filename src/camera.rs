@@ -7,59 +7,562 @@ use super::win_mf as backend;
 #[cfg(target_os = "linux")]
 use super::linux_v4l2 as backend;
 
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::replay::{OwnedFrame, ReplayCamera};
+#[cfg(all(feature = "shm", target_os = "linux"))]
+use crate::shm::{ShmCamera, ShmFrame};
+use crate::subscribe::Fanout;
+use crate::{Colorimetry, FrameBuf, FramePool, PixelFormat};
+#[cfg(target_os = "linux")]
+use crate::{DeviceInfo, IoMode, Mode, ModeConfig, ReconnectEvent, ReconnectPolicy, UvcMetadata};
+
 #[derive(Debug)]
 pub struct Camera {
-    inner: backend::Camera,
+    inner: CameraInner,
+    fanout: Fanout,
+}
+
+#[derive(Debug)]
+enum CameraInner {
+    Device(Box<backend::Camera>),
+    Replay(ReplayCamera),
+    #[cfg(all(feature = "shm", target_os = "linux"))]
+    Shm(ShmCamera),
 }
 
 #[derive(Debug)]
 pub struct Frame {
-    inner: backend::Frame,
+    inner: FrameInner,
+}
+
+#[derive(Debug)]
+enum FrameInner {
+    Device(backend::Frame),
+    /// Shared by the clones of a frame for subscribers.
+    Owned(Arc<OwnedFrame>),
+    #[cfg(all(feature = "shm", target_os = "linux"))]
+    Shm(ShmFrame),
 }
 
 pub struct FrameData<'a> {
-    inner: backend::FrameData<'a>,
+    inner: FrameDataInner<'a>,
+    size: (u32, u32),
+    format: PixelFormat,
+    stride: usize,
+}
+
+/// One plane of a [Frame::native] buffer, like the luma or the chroma plane of NV12, see
+/// [Frame::native_planes].
+#[derive(Debug)]
+pub struct NativePlane<'a> {
+    data: &'a [u8],
+    stride: usize,
+    #[cfg(target_os = "linux")]
+    dmabuf: Option<(std::os::fd::BorrowedFd<'a>, usize)>,
+}
+
+enum FrameDataInner<'a> {
+    Device(backend::FrameData<'a>),
+    Bytes(&'a [u8]),
 }
 
 impl Camera {
     pub fn new_default_device() -> Self {
-        Self { inner: backend::Camera::new_default_device() }
+        Self::from_inner(CameraInner::Device(Box::new(backend::Camera::new_default_device())))
+    }
+
+    /// The capture devices with their stable ids, see [DeviceInfo::stable_id].
+    #[cfg(target_os = "linux")]
+    pub fn devices() -> Vec<DeviceInfo> {
+        backend::devices()
+    }
+
+    /// Opens the device that `id` names, see [DeviceInfo::matches]. Fails with
+    /// [NotFound](std::io::ErrorKind::NotFound) when no device matches.
+    #[cfg(target_os = "linux")]
+    pub fn open_by_id(id: &str) -> std::io::Result<Self> {
+        Ok(Self::from_inner(CameraInner::Device(Box::new(backend::Camera::open_by_id(id)?))))
+    }
+
+    pub(crate) fn from_replay(replay: ReplayCamera) -> Self {
+        Self::from_inner(CameraInner::Replay(replay))
+    }
+
+    #[cfg(all(feature = "shm", target_os = "linux"))]
+    pub(crate) fn from_shm(shm: ShmCamera) -> Self {
+        Self::from_inner(CameraInner::Shm(shm))
+    }
+
+    /// The first device of a fake, for tests.
+    #[cfg(all(test, target_os = "linux"))]
+    pub(crate) fn first_in(
+        devices: std::sync::Arc<backend::fake::FakeDevices>,
+    ) -> std::io::Result<Self> {
+        Ok(Self::from_inner(CameraInner::Device(Box::new(backend::Camera::first_in(devices)?))))
+    }
+
+    fn from_inner(inner: CameraInner) -> Self {
+        Self { inner, fanout: Fanout::default() }
+    }
+
+    pub(crate) fn fanout(&self) -> &Fanout {
+        &self.fanout
     }
 
     pub fn start(&self) {
-        self.inner.start();
+        match &self.inner {
+            CameraInner::Device(camera) => camera.start(),
+            CameraInner::Replay(camera) => camera.start(),
+            #[cfg(all(feature = "shm", target_os = "linux"))]
+            CameraInner::Shm(camera) => camera.start(),
+        }
     }
 
     pub fn stop(&self) {
-        self.inner.stop();
+        match &self.inner {
+            CameraInner::Device(camera) => camera.stop(),
+            CameraInner::Replay(camera) => camera.stop(),
+            #[cfg(all(feature = "shm", target_os = "linux"))]
+            CameraInner::Shm(camera) => camera.stop(),
+        }
     }
 
     pub fn wait_for_frame(&self) -> Option<Frame> {
-        self.inner.wait_for_frame().map(|inner| Frame { inner })
+        let inner = match &self.inner {
+            CameraInner::Device(camera) => FrameInner::Device(camera.wait_for_frame()?),
+            CameraInner::Replay(camera) => FrameInner::Owned(Arc::new(camera.wait_for_frame()?)),
+            #[cfg(all(feature = "shm", target_os = "linux"))]
+            CameraInner::Shm(camera) => FrameInner::Shm(camera.wait_for_frame()?),
+        };
+        Some(Frame { inner })
+    }
+
+    /// Like [Camera::wait_for_frame], telling why there is no frame. With a
+    /// [ReconnectPolicy] it fails with [Interrupted](std::io::ErrorKind::Interrupted) while
+    /// the device is being reconnected, the next call may have a frame again. It fails with
+    /// [NotConnected](std::io::ErrorKind::NotConnected) once the policy gave up, when the
+    /// camera isn't started or a replayed file ended, and once after the device failed to
+    /// start streaming.
+    pub fn try_wait_for_frame(&self) -> std::io::Result<Frame> {
+        #[cfg(target_os = "linux")]
+        if let CameraInner::Device(camera) = &self.inner {
+            return Ok(Frame { inner: FrameInner::Device(camera.try_wait_for_frame()?) });
+        }
+        self.wait_for_frame().ok_or_else(|| std::io::ErrorKind::NotConnected.into())
     }
 
+    /// Switches to the next device. Does nothing for a replayed file.
     pub fn change_device(&mut self) {
-        self.inner.change_device();
+        if let CameraInner::Device(camera) = &mut self.inner {
+            camera.change_device();
+        }
+    }
+
+    /// Forces how the Linux backend gets frames from the driver, from the next [Camera::start].
+    /// With `None` it streams through mapped buffers and falls back to `read()` for drivers
+    /// without streaming. Does nothing for a replayed file.
+    #[cfg(target_os = "linux")]
+    pub fn set_io_mode(&self, mode: Option<IoMode>) {
+        if let CameraInner::Device(camera) = &self.inner {
+            camera.set_io_mode(mode);
+        }
+    }
+
+    /// The formats of the device with the frame sizes it offers for each. Empty for a
+    /// replayed file.
+    #[cfg(target_os = "linux")]
+    pub fn modes(&self) -> Vec<Mode> {
+        match &self.inner {
+            CameraInner::Device(camera) => camera.modes(),
+            _ => Vec::new(),
+        }
+    }
+
+    /// Switches the device to the format, size and frame rate of `config`, restarting it if
+    /// it was started. Sizes snap to the closest one of [Camera::modes], frame rates as with
+    /// [Camera::set_frame_rate]. Returns what the driver applied, which may still differ.
+    #[cfg(target_os = "linux")]
+    pub fn set_mode(&self, config: ModeConfig) -> std::io::Result<ModeConfig> {
+        match &self.inner {
+            CameraInner::Device(camera) => camera.set_mode(config),
+            _ => Err(std::io::ErrorKind::Unsupported.into()),
+        }
+    }
+
+    /// Frames per second as numerator and denominator, `None` when the driver doesn't say.
+    #[cfg(target_os = "linux")]
+    pub fn frame_rate(&self) -> Option<(u32, u32)> {
+        match &self.inner {
+            CameraInner::Device(camera) => camera.frame_rate(),
+            _ => None,
+        }
+    }
+
+    /// Asks for `num / den` frames per second, restarting the camera if it was started. The
+    /// rate snaps to the closest one the device offers for the current format and size.
+    /// Returns the rate the driver applied.
+    #[cfg(target_os = "linux")]
+    pub fn set_frame_rate(&self, num: u32, den: u32) -> std::io::Result<(u32, u32)> {
+        match &self.inner {
+            CameraInner::Device(camera) => camera.set_frame_rate((num, den)),
+            _ => Err(std::io::ErrorKind::Unsupported.into()),
+        }
+    }
+
+    /// Streams the metadata node of a UVC camera along with the video from the next
+    /// [Camera::start], giving each frame its payload headers, see [Frame::uvc_metadata].
+    /// Frames have none when the device has no metadata node or it can't be opened.
+    #[cfg(target_os = "linux")]
+    pub fn set_uvc_metadata(&self, enabled: bool) {
+        if let CameraInner::Device(camera) = &self.inner {
+            camera.set_uvc_metadata(enabled);
+        }
+    }
+
+    /// Reopens the device when it goes away while started, like after an USB reset, and
+    /// streams again in the same mode and with the settings of this camera. Off with `None`,
+    /// the default. [Camera::wait_for_frame] then keeps waiting while the device is gone and
+    /// returns `None` only after giving up or [Camera::stop]. See [Camera::try_wait_for_frame]
+    /// and [Camera::reconnect_events].
+    #[cfg(target_os = "linux")]
+    pub fn set_reconnect_policy(&self, policy: Option<ReconnectPolicy>) {
+        if let CameraInner::Device(camera) = &self.inner {
+            camera.set_reconnect_policy(policy);
+        }
+    }
+
+    /// Receives what happens while reconnecting, from now on. A replayed file has none.
+    #[cfg(target_os = "linux")]
+    pub fn reconnect_events(&self) -> std::sync::mpsc::Receiver<ReconnectEvent> {
+        match &self.inner {
+            CameraInner::Device(camera) => camera.reconnect_events(),
+            _ => std::sync::mpsc::channel().1,
+        }
+    }
+
+    /// Buffers the Linux backend streams with from the next [Camera::start], 4 by default.
+    /// Fewer buffers mean less latency, more of them ride out stalls of the application.
+    #[cfg(target_os = "linux")]
+    pub fn set_buffer_count(&self, count: u32) {
+        if let CameraInner::Device(camera) = &self.inner {
+            camera.set_buffer_count(count);
+        }
+    }
+
+    /// With `true` [Camera::wait_for_frame] takes all frames the driver filled meanwhile and
+    /// returns only the newest, for previews which shouldn't lag behind. Needs
+    /// [IoMode::Mmap], see [Frame::sequence] for the skipped frames.
+    #[cfg(target_os = "linux")]
+    pub fn set_drain_to_newest(&self, drain: bool) {
+        if let CameraInner::Device(camera) = &self.inner {
+            camera.set_drain_to_newest(drain);
+        }
+    }
+
+    /// How the Linux backend gets frames while the camera is started.
+    #[cfg(target_os = "linux")]
+    pub fn io_mode(&self) -> Option<IoMode> {
+        match &self.inner {
+            CameraInner::Device(camera) => camera.io_mode(),
+            _ => None,
+        }
+    }
+
+    /// What identifies the device. None for a replayed file.
+    #[cfg(target_os = "linux")]
+    pub fn device_info(&self) -> Option<DeviceInfo> {
+        match &self.inner {
+            CameraInner::Device(camera) => Some(camera.info()),
+            _ => None,
+        }
+    }
+
+    /// Name of the device, or the file name of a replayed file.
+    pub fn device_name(&self) -> String {
+        match &self.inner {
+            CameraInner::Device(camera) => camera.device_name(),
+            CameraInner::Replay(camera) => camera.device_name(),
+            #[cfg(all(feature = "shm", target_os = "linux"))]
+            CameraInner::Shm(camera) => camera.device_name(),
+        }
     }
 }
 
 impl Frame {
-    pub fn data(&self) -> FrameData {
-        FrameData { inner: self.inner.data() }
+    pub(crate) fn from_owned(frame: Arc<OwnedFrame>) -> Self {
+        Self { inner: FrameInner::Owned(frame) }
+    }
+
+    /// The frame as [OwnedFrame] for other threads, copying the buffers of devices.
+    pub(crate) fn to_shared(&self) -> Arc<OwnedFrame> {
+        match &self.inner {
+            FrameInner::Owned(frame) => frame.clone(),
+            _ => Arc::new(OwnedFrame::copy_of(self)),
+        }
+    }
+
+    pub fn data(&self) -> FrameData<'_> {
+        let inner = match &self.inner {
+            FrameInner::Device(frame) => FrameDataInner::Device(frame.data()),
+            FrameInner::Owned(frame) => FrameDataInner::Bytes(frame.data().0),
+            #[cfg(all(feature = "shm", target_os = "linux"))]
+            FrameInner::Shm(frame) => FrameDataInner::Bytes(frame.data().0),
+        };
+        FrameData { inner, size: self.size_u32(), format: self.format(), stride: self.stride() }
     }
 
     pub fn size_u32(&self) -> (u32, u32) {
-        self.inner.size_u32()
+        match &self.inner {
+            FrameInner::Device(frame) => frame.size_u32(),
+            FrameInner::Owned(frame) => frame.native().size_u32(),
+            #[cfg(all(feature = "shm", target_os = "linux"))]
+            FrameInner::Shm(frame) => frame.size_u32(),
+        }
+    }
+
+    pub fn format(&self) -> PixelFormat {
+        match &self.inner {
+            FrameInner::Device(frame) => frame.format(),
+            FrameInner::Owned(_) => PixelFormat::Bgra,
+            #[cfg(all(feature = "shm", target_os = "linux"))]
+            FrameInner::Shm(_) => PixelFormat::Bgra,
+        }
+    }
+
+    /// The buffer as the device delivered it, before kamera converted it for [Frame::data].
+    /// For example the JPEG of a webcam delivering MJPEG. `None` on platforms where the
+    /// operating system does the conversion.
+    pub fn native(&self) -> Option<FrameData<'_>> {
+        let (inner, format, stride) = match &self.inner {
+            FrameInner::Device(frame) => {
+                let (data, format, stride) = frame.native()?;
+                (FrameDataInner::Device(data), format, stride)
+            }
+            FrameInner::Owned(frame) => {
+                let native = frame.native();
+                (FrameDataInner::Bytes(native.data_u8()), native.format(), native.stride())
+            }
+            #[cfg(all(feature = "shm", target_os = "linux"))]
+            FrameInner::Shm(frame) => {
+                let (data, format, stride) = frame.native();
+                (FrameDataInner::Bytes(data), format, stride)
+            }
+        };
+        Some(FrameData { inner, size: self.size_u32(), format, stride })
+    }
+
+    /// The dma-buf holding the [Frame::native] buffer, for hardware encoders and compositors.
+    /// Its planes are laid out as [PixelFormat::planes] of the native format, size and stride,
+    /// unless the format is multi-planar, see [Frame::native_planes].
+    /// The driver fills the buffer again only after the frame is dropped.
+    ///
+    /// `None` for other backends and for frames copied because the application held all
+    /// other buffers of the device.
+    #[cfg(target_os = "linux")]
+    pub fn dmabuf_fd(&self) -> Option<std::os::fd::BorrowedFd<'_>> {
+        match &self.inner {
+            FrameInner::Device(frame) => frame.dmabuf_fd(),
+            _ => None,
+        }
+    }
+
+    /// The planes of the [Frame::native] buffer with their own strides. Devices delivering
+    /// multi-planar formats like NV12M keep each plane in separate memory, [Frame::native]
+    /// then is a copy with the planes one after another. One plane for compressed formats,
+    /// none on platforms where the operating system does the conversion.
+    pub fn native_planes(&self) -> Vec<NativePlane<'_>> {
+        let (data, format, stride) = match &self.inner {
+            #[cfg(target_os = "linux")]
+            FrameInner::Device(frame) => return frame.native_planes(),
+            #[cfg(not(target_os = "linux"))]
+            FrameInner::Device(_) => return Vec::new(),
+            FrameInner::Owned(frame) => {
+                let native = frame.native();
+                (native.data_u8(), native.format(), native.stride())
+            }
+            #[cfg(all(feature = "shm", target_os = "linux"))]
+            FrameInner::Shm(frame) => frame.native(),
+        };
+        NativePlane::split(data, format, self.size_u32(), stride)
+    }
+
+    /// Bytes per row, including padding.
+    pub fn stride(&self) -> usize {
+        match &self.inner {
+            FrameInner::Device(frame) => frame.stride(),
+            FrameInner::Owned(frame) => frame.data().1,
+            #[cfg(all(feature = "shm", target_os = "linux"))]
+            FrameInner::Shm(frame) => frame.data().1,
+        }
+    }
+
+    /// Capture time as reported by the backend. The clock origin depends on the platform.
+    /// Replayed files start at zero.
+    pub fn timestamp(&self) -> Duration {
+        match &self.inner {
+            FrameInner::Device(frame) => frame.timestamp(),
+            FrameInner::Owned(frame) => frame.native().timestamp(),
+            #[cfg(all(feature = "shm", target_os = "linux"))]
+            FrameInner::Shm(frame) => frame.timestamp(),
+        }
+    }
+
+    /// Frame counter of the driver, gaps mean dropped frames. `None` where the platform
+    /// doesn't count.
+    pub fn sequence(&self) -> Option<u32> {
+        match &self.inner {
+            FrameInner::Device(frame) => frame.sequence(),
+            FrameInner::Owned(frame) => frame.sequence(),
+            #[cfg(all(feature = "shm", target_os = "linux"))]
+            FrameInner::Shm(frame) => frame.sequence(),
+        }
+    }
+
+    /// Time from the driver timestamp until [Camera::wait_for_frame] returned the frame.
+    /// `None` for other backends and for drivers whose timestamps use another clock.
+    pub fn latency(&self) -> Option<Duration> {
+        match &self.inner {
+            #[cfg(target_os = "linux")]
+            FrameInner::Device(frame) => frame.latency(),
+            _ => None,
+        }
+    }
+
+    /// The UVC payload headers of the frame with their SOF, PTS and SCR, see
+    /// [Camera::set_uvc_metadata]. Empty for other frames.
+    #[cfg(target_os = "linux")]
+    pub fn uvc_metadata(&self) -> &[UvcMetadata] {
+        match &self.inner {
+            FrameInner::Device(frame) => frame.uvc_metadata(),
+            _ => &[],
+        }
+    }
+
+    /// Color description of the [Frame::native] buffer.
+    pub fn colorimetry(&self) -> Colorimetry {
+        match &self.inner {
+            FrameInner::Device(frame) => frame.colorimetry(),
+            FrameInner::Owned(frame) => frame.colorimetry(),
+            #[cfg(all(feature = "shm", target_os = "linux"))]
+            FrameInner::Shm(frame) => frame.colorimetry(),
+        }
+    }
+
+    /// Copies the frame so it can outlive the backend buffer and be sent to other threads.
+    pub fn to_owned(&self) -> FrameBuf {
+        self.to_owned_with(None)
+    }
+
+    /// Like [Frame::to_owned] but reuses an allocation from `pool`.
+    pub fn to_owned_in(&self, pool: &FramePool) -> FrameBuf {
+        self.to_owned_with(Some(pool))
+    }
+
+    fn to_owned_with(&self, pool: Option<&FramePool>) -> FrameBuf {
+        let data = self.data();
+        FrameBuf::new_in(
+            pool,
+            data.data_u8(),
+            data.size,
+            data.format,
+            data.stride,
+            self.timestamp(),
+        )
+    }
+}
+
+impl<'a> NativePlane<'a> {
+    #[cfg(target_os = "linux")]
+    pub(crate) fn new(
+        data: &'a [u8],
+        stride: usize,
+        dmabuf: Option<(std::os::fd::BorrowedFd<'a>, usize)>,
+    ) -> Self {
+        Self { data, stride, dmabuf }
+    }
+
+    /// The planes of a buffer laid out like [PixelFormat::planes].
+    pub(crate) fn split(
+        data: &'a [u8],
+        format: PixelFormat,
+        size: (u32, u32),
+        stride: usize,
+    ) -> Vec<Self> {
+        let plane = |data, stride| Self {
+            data,
+            stride,
+            #[cfg(target_os = "linux")]
+            dmabuf: None,
+        };
+        if format.is_compressed() {
+            return vec![plane(data, stride)];
+        }
+        let planes = format.planes(size, stride).into_iter().map(|layout| {
+            let end = (layout.offset + layout.stride * layout.rows).min(data.len());
+            plane(&data[layout.offset.min(end)..end], layout.stride)
+        });
+        planes.collect()
+    }
+
+    pub fn data(&self) -> &'a [u8] {
+        self.data
+    }
+
+    /// Bytes per row, including padding.
+    pub fn stride(&self) -> usize {
+        self.stride
+    }
+
+    /// The dma-buf holding this plane, see [Frame::dmabuf_fd].
+    #[cfg(target_os = "linux")]
+    pub fn dmabuf_fd(&self) -> Option<std::os::fd::BorrowedFd<'a>> {
+        self.dmabuf.map(|(fd, _)| fd)
+    }
+
+    /// Where the plane starts in its dma-buf.
+    #[cfg(target_os = "linux")]
+    pub fn dmabuf_offset(&self) -> usize {
+        self.dmabuf.map_or(0, |(_, offset)| offset)
     }
 }
 
 impl<'a> FrameData<'a> {
+    pub(crate) fn from_bytes(
+        data: &'a [u8],
+        size: (u32, u32),
+        format: PixelFormat,
+        stride: usize,
+    ) -> Self {
+        Self { inner: FrameDataInner::Bytes(data), size, format, stride }
+    }
+
     pub fn data_u8(&self) -> &[u8] {
-        self.inner.data_u8()
+        match &self.inner {
+            FrameDataInner::Device(data) => data.data_u8(),
+            FrameDataInner::Bytes(data) => data,
+        }
     }
 
     pub fn data_u32(&self) -> &[u32] {
-        self.inner.data_u32()
+        match &self.inner {
+            FrameDataInner::Device(data) => data.data_u32(),
+            FrameDataInner::Bytes(data) => unsafe { data.align_to().1 },
+        }
+    }
+
+    pub fn size_u32(&self) -> (u32, u32) {
+        self.size
+    }
+
+    pub fn format(&self) -> PixelFormat {
+        self.format
+    }
+
+    /// Bytes per row, including padding.
+    pub fn stride(&self) -> usize {
+        self.stride
     }
 }
 
@@ -71,4 +574,5 @@ pub(crate) trait InnerCamera: std::fmt::Debug {
     fn stop(&self);
     fn wait_for_frame(&self) -> Option<Self::Frame>;
     fn change_device(&mut self);
+    fn device_name(&self) -> String;
 }
@@ -0,0 +1,324 @@
+//! Conversions from the frame pixel formats into tightly packed RGBA, RGB and luma buffers.
+
+use crate::PixelFormat;
+
+/// A pixel in the color model of its format, so YUV formats can be read without a round trip
+/// through RGB.
+#[derive(Clone, Copy)]
+enum Pixel {
+    Rgba([u8; 4]),
+    Yuv([u8; 3]),
+    Luma(u8),
+}
+
+/// Calls `f` with every pixel in row major order. Subsampled chroma is repeated.
+fn for_each_pixel(
+    data: &[u8],
+    size: (u32, u32),
+    format: PixelFormat,
+    stride: usize,
+    mut f: impl FnMut(Pixel),
+) {
+    if format == PixelFormat::Mjpeg {
+        let rgb = decode_jpeg(data, size);
+        let stride = size.0 as usize * 3;
+        return for_each_pixel(&rgb, size, PixelFormat::Rgb, stride, f);
+    }
+
+    let planes = format.planes(size, stride);
+    let row = |plane: usize, y: usize| {
+        let p = &planes[plane];
+        &data[p.offset + y * p.stride..p.offset + y * p.stride + p.row_len]
+    };
+    let w = size.0 as usize;
+    let yuv = |y, u, v| Pixel::Yuv([y, u, v]);
+
+    for y in 0..size.1 as usize {
+        match format {
+            PixelFormat::Bgra => {
+                row(0, y).chunks_exact(4).for_each(|p| f(Pixel::Rgba([p[2], p[1], p[0], p[3]])))
+            }
+            PixelFormat::Rgba => {
+                row(0, y).chunks_exact(4).for_each(|p| f(Pixel::Rgba([p[0], p[1], p[2], p[3]])))
+            }
+            PixelFormat::Rgb => {
+                row(0, y).chunks_exact(3).for_each(|p| f(Pixel::Rgba([p[0], p[1], p[2], 255])))
+            }
+            PixelFormat::Gray => row(0, y).iter().for_each(|&l| f(Pixel::Luma(l))),
+            PixelFormat::Yuyv => {
+                for (x, p) in row(0, y).chunks_exact(4).enumerate() {
+                    f(yuv(p[0], p[1], p[3]));
+                    if 2 * x + 1 < w {
+                        f(yuv(p[2], p[1], p[3]));
+                    }
+                }
+            }
+            PixelFormat::Nv12 => {
+                let (luma, uv) = (row(0, y), row(1, y / 2));
+                for x in 0..w {
+                    f(yuv(luma[x], uv[x / 2 * 2], uv[x / 2 * 2 + 1]));
+                }
+            }
+            PixelFormat::I420 | PixelFormat::I422 | PixelFormat::I444 => {
+                let chroma_y = if format == PixelFormat::I420 { y / 2 } else { y };
+                let (luma, u, v) = (row(0, y), row(1, chroma_y), row(2, chroma_y));
+                let shift = (format != PixelFormat::I444) as usize;
+                for x in 0..w {
+                    f(yuv(luma[x], u[x >> shift], v[x >> shift]));
+                }
+            }
+            PixelFormat::Mjpeg => unreachable!("decoded above"),
+        }
+    }
+}
+
+/// Calls `f` with the RGBA value of every pixel in row major order.
+fn for_each_rgba(
+    data: &[u8],
+    size: (u32, u32),
+    format: PixelFormat,
+    stride: usize,
+    mut f: impl FnMut([u8; 4]),
+) {
+    for_each_pixel(data, size, format, stride, |px| match px {
+        Pixel::Rgba(rgba) => f(rgba),
+        Pixel::Yuv([y, u, v]) => f(yuv_to_rgba(y, u, v)),
+        Pixel::Luma(l) => f([l, l, l, 255]),
+    })
+}
+
+/// Calls `f` with the BT.601 limited range YUV value of every pixel in row major order.
+fn for_each_yuv(
+    data: &[u8],
+    size: (u32, u32),
+    format: PixelFormat,
+    stride: usize,
+    mut f: impl FnMut([u8; 3]),
+) {
+    for_each_pixel(data, size, format, stride, |px| match px {
+        Pixel::Rgba(rgba) => f(rgb_to_yuv(rgba)),
+        Pixel::Yuv(yuv) => f(yuv),
+        Pixel::Luma(l) => f([l, 128, 128]),
+    })
+}
+
+/// Decodes a JPEG into exactly `size` RGB pixels. A broken JPEG or one of another size
+/// results in black where there are no decoded pixels, like a video player would show it.
+pub(crate) fn decode_jpeg(data: &[u8], (w, h): (u32, u32)) -> Vec<u8> {
+    let mut rgb = vec![0; w as usize * h as usize * 3];
+    let mut decoder = jpeg_decoder::Decoder::new(data);
+    let (Ok(pixels), Some(info)) = (decoder.decode(), decoder.info()) else { return rgb };
+    let (jw, jh) = (info.width as usize, info.height as usize);
+    let bpp = info.pixel_format.pixel_bytes();
+    for y in 0..jh.min(h as usize) {
+        for x in 0..jw.min(w as usize) {
+            let src = &pixels[(y * jw + x) * bpp..];
+            let dst = &mut rgb[(y * w as usize + x) * 3..][..3];
+            match info.pixel_format {
+                jpeg_decoder::PixelFormat::RGB24 => dst.copy_from_slice(&src[..3]),
+                jpeg_decoder::PixelFormat::L8 => dst.fill(src[0]),
+                jpeg_decoder::PixelFormat::L16 => dst.fill(src[0]),
+                jpeg_decoder::PixelFormat::CMYK32 => return rgb,
+            }
+        }
+    }
+    rgb
+}
+
+/// BT.601 limited range, which is what webcams deliver unless they say otherwise.
+fn yuv_to_rgba(y: u8, u: u8, v: u8) -> [u8; 4] {
+    let c = 298 * (y as i32 - 16);
+    let d = u as i32 - 128;
+    let e = v as i32 - 128;
+    let clamp = |x: i32| ((x + 128) >> 8).clamp(0, 255) as u8;
+    [clamp(c + 409 * e), clamp(c - 100 * d - 208 * e), clamp(c + 516 * d), 255]
+}
+
+/// Inverse of [yuv_to_rgba].
+fn rgb_to_yuv([r, g, b, _]: [u8; 4]) -> [u8; 3] {
+    let (r, g, b) = (r as i32, g as i32, b as i32);
+    let y = ((66 * r + 129 * g + 25 * b + 128) >> 8) + 16;
+    let u = ((-38 * r - 74 * g + 112 * b + 128) >> 8) + 128;
+    let v = ((112 * r - 94 * g - 18 * b + 128) >> 8) + 128;
+    [y as u8, u as u8, v as u8]
+}
+
+/// BT.601 luma in integer arithmetic.
+fn luma([r, g, b, _]: [u8; 4]) -> u8 {
+    ((77 * r as u32 + 150 * g as u32 + 29 * b as u32) >> 8) as u8
+}
+
+pub(crate) fn to_rgba(
+    data: &[u8],
+    size: (u32, u32),
+    format: PixelFormat,
+    stride: usize,
+) -> Vec<u8> {
+    let mut out = Vec::with_capacity(size.0 as usize * size.1 as usize * 4);
+    for_each_rgba(data, size, format, stride, |px| out.extend(px));
+    out
+}
+
+pub(crate) fn to_bgra(
+    data: &[u8],
+    size: (u32, u32),
+    format: PixelFormat,
+    stride: usize,
+) -> Vec<u8> {
+    let mut out = Vec::with_capacity(size.0 as usize * size.1 as usize * 4);
+    for_each_rgba(data, size, format, stride, |[r, g, b, a]| out.extend([b, g, r, a]));
+    out
+}
+
+pub(crate) fn to_rgb(data: &[u8], size: (u32, u32), format: PixelFormat, stride: usize) -> Vec<u8> {
+    let mut out = Vec::with_capacity(size.0 as usize * size.1 as usize * 3);
+    for_each_rgba(data, size, format, stride, |[r, g, b, _]| out.extend([r, g, b]));
+    out
+}
+
+pub(crate) fn to_luma(
+    data: &[u8],
+    size: (u32, u32),
+    format: PixelFormat,
+    stride: usize,
+) -> Vec<u8> {
+    let mut out = Vec::with_capacity(size.0 as usize * size.1 as usize);
+    match format {
+        PixelFormat::Gray
+        | PixelFormat::Nv12
+        | PixelFormat::I420
+        | PixelFormat::I422
+        | PixelFormat::I444 => {
+            let plane = format.planes(size, stride)[0];
+            for y in 0..plane.rows {
+                let start = plane.offset + y * plane.stride;
+                out.extend_from_slice(&data[start..start + plane.row_len]);
+            }
+        }
+        _ => for_each_rgba(data, size, format, stride, |px| out.push(luma(px))),
+    }
+    out
+}
+
+/// Tightly packed pixels of `target` and their stride, `None` for formats kamera can't
+/// produce like [PixelFormat::Mjpeg].
+pub(crate) fn to_format(
+    data: &[u8],
+    size: (u32, u32),
+    format: PixelFormat,
+    stride: usize,
+    target: PixelFormat,
+) -> Option<(Vec<u8>, usize)> {
+    let w = size.0 as usize;
+    Some(match target {
+        PixelFormat::Bgra => (to_bgra(data, size, format, stride), w * 4),
+        PixelFormat::Rgba => (to_rgba(data, size, format, stride), w * 4),
+        PixelFormat::Rgb => (to_rgb(data, size, format, stride), w * 3),
+        PixelFormat::Gray => (to_luma(data, size, format, stride), w),
+        PixelFormat::I420 | PixelFormat::I422 | PixelFormat::I444 => {
+            (to_planar_yuv(data, size, format, stride, target), w)
+        }
+        PixelFormat::Yuyv | PixelFormat::Nv12 | PixelFormat::Mjpeg => return None,
+    })
+}
+
+/// Tightly packed planes of `target`, which is [PixelFormat::I420], [PixelFormat::I422],
+/// [PixelFormat::I444] or [PixelFormat::Gray]. Subsampled chroma is the average of the pixels
+/// it covers, so chroma which is subsampled already comes out unchanged.
+pub(crate) fn to_planar_yuv(
+    data: &[u8],
+    size: (u32, u32),
+    format: PixelFormat,
+    stride: usize,
+    target: PixelFormat,
+) -> Vec<u8> {
+    let (w, h) = (size.0 as usize, size.1 as usize);
+    let (sx, sy) = match target {
+        PixelFormat::I420 => (2, 2),
+        PixelFormat::I422 => (2, 1),
+        PixelFormat::I444 | PixelFormat::Gray => (1, 1),
+        _ => panic!("{target:?} is no planar YUV format"),
+    };
+    let cw = w.div_ceil(sx);
+    let mut out = Vec::with_capacity(target.frame_len(size, w));
+    // sum of U, sum of V and number of pixels for each chroma sample
+    let mut sums = vec![[0u32; 3]; cw * h.div_ceil(sy)];
+    let mut i = 0;
+    for_each_yuv(data, size, format, stride, |[y, u, v]| {
+        out.push(y);
+        let sum = &mut sums[i / w / sy * cw + i % w / sx];
+        *sum = [sum[0] + u as u32, sum[1] + v as u32, sum[2] + 1];
+        i += 1;
+    });
+    if target != PixelFormat::Gray {
+        for c in 0..2 {
+            out.extend(sums.iter().map(|sum| ((sum[c] + sum[2] / 2) / sum[2]) as u8));
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bgra_with_padding_to_rgba_rgb_luma() {
+        #[rustfmt::skip]
+        let data = [
+            1, 2, 3, 4, 255, 255, 255, 255, 0, 0,
+            0, 0, 0, 255, 10, 20, 30, 40, 0, 0,
+        ];
+        let size = (2, 2);
+        assert_eq!(
+            to_rgba(&data, size, PixelFormat::Bgra, 10),
+            [3, 2, 1, 4, 255, 255, 255, 255, 0, 0, 0, 255, 30, 20, 10, 40]
+        );
+        assert_eq!(
+            to_rgb(&data, size, PixelFormat::Bgra, 10),
+            [3, 2, 1, 255, 255, 255, 0, 0, 0, 30, 20, 10]
+        );
+        assert_eq!(to_luma(&data, size, PixelFormat::Bgra, 10), [2, 255, 0, 21]);
+    }
+
+    #[test]
+    fn gray_to_rgba() {
+        assert_eq!(to_rgba(&[7, 9], (2, 1), PixelFormat::Gray, 2), [7, 7, 7, 255, 9, 9, 9, 255]);
+    }
+
+    #[test]
+    fn broken_jpeg_is_black() {
+        assert_eq!(to_bgra(b"no jpeg", (1, 1), PixelFormat::Mjpeg, 0), [0, 0, 0, 255]);
+    }
+
+    #[test]
+    fn yuv_formats_agree() {
+        // 2x2 pixels, black, white and two greys with neutral chroma
+        let yuyv = [16, 128, 235, 128, 100, 128, 180, 128];
+        let planar = [16, 235, 100, 180, 128, 128];
+        let rgb = to_rgb(&yuyv, (2, 2), PixelFormat::Yuyv, 4);
+        assert_eq!(&rgb[..6], [0, 0, 0, 255, 255, 255]);
+        assert_eq!(rgb, to_rgb(&planar, (2, 2), PixelFormat::Nv12, 2));
+        assert_eq!(rgb, to_rgb(&planar, (2, 2), PixelFormat::I420, 2));
+        assert_eq!(to_luma(&planar, (2, 2), PixelFormat::I420, 2), [16, 235, 100, 180]);
+    }
+
+    #[test]
+    fn planar_yuv_keeps_subsampled_chroma() {
+        // 3x2 pixels with a chroma pair per two pixels of a row
+        let yuyv = [10, 100, 20, 200, 30, 110, 0, 210, 40, 120, 50, 220, 60, 130, 0, 230];
+        assert_eq!(
+            to_planar_yuv(&yuyv, (3, 2), PixelFormat::Yuyv, 8, PixelFormat::I422),
+            [10, 20, 30, 40, 50, 60, 100, 110, 120, 130, 200, 210, 220, 230]
+        );
+        assert_eq!(
+            to_planar_yuv(&yuyv, (3, 2), PixelFormat::Yuyv, 8, PixelFormat::I420),
+            [10, 20, 30, 40, 50, 60, 110, 120, 210, 220]
+        );
+        let rgb = [255, 255, 255, 0, 0, 0];
+        assert_eq!(
+            to_planar_yuv(&rgb, (2, 1), PixelFormat::Rgb, 6, PixelFormat::I444),
+            [235, 16, 128, 128, 128, 128]
+        );
+    }
+}
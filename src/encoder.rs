@@ -0,0 +1,407 @@
+//! Encoding frames for snapshots, recordings and streams behind one trait, see [FrameEncoder].
+
+use std::io;
+
+use crate::{convert, snapshot, Frame, FrameBuf, FrameData, PixelFormat};
+
+/// Turns frames into encoded bytes. [Recorder](crate::Recorder) and the `http` server take
+/// any encoder, for example one using a hardware codec.
+///
+/// [Frame::encode_with] passes the native buffer when the encoder takes its format, so MJPEG
+/// from a webcam can go through untouched. Other frames are converted into the first of the
+/// [FrameEncoder::input_formats] kamera can produce: BGRA, RGBA, RGB, gray, I420, I422 or I444.
+pub trait FrameEncoder: Send {
+    /// Pixel formats [FrameEncoder::encode] takes, in the order of preference for conversions.
+    fn input_formats(&self) -> &[PixelFormat];
+
+    /// MIME type of the output, for example `image/jpeg`.
+    fn mime_type(&self) -> &'static str;
+
+    /// Codec of the output in video containers, for example `MJPG`.
+    fn fourcc(&self) -> [u8; 4];
+
+    /// Encodes a frame in one of the input formats. Video encoders may keep state between
+    /// frames.
+    fn encode(&mut self, frame: &FrameData<'_>) -> io::Result<Vec<u8>>;
+}
+
+/// Baseline JPEG, frames which are MJPEG already are passed through.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct JpegEncoder {
+    quality: u8,
+}
+
+impl JpegEncoder {
+    /// Quality from 1 to 100.
+    pub fn new(quality: u8) -> Self {
+        Self { quality: quality.clamp(1, 100) }
+    }
+}
+
+impl Default for JpegEncoder {
+    /// Quality 90.
+    fn default() -> Self {
+        Self::new(90)
+    }
+}
+
+impl FrameEncoder for JpegEncoder {
+    fn input_formats(&self) -> &[PixelFormat] {
+        &[PixelFormat::Mjpeg, PixelFormat::Rgb, PixelFormat::Gray]
+    }
+
+    fn mime_type(&self) -> &'static str {
+        "image/jpeg"
+    }
+
+    fn fourcc(&self) -> [u8; 4] {
+        *b"MJPG"
+    }
+
+    fn encode(&mut self, frame: &FrameData<'_>) -> io::Result<Vec<u8>> {
+        let (data, size) = (frame.data_u8(), frame.size_u32());
+        match frame.format() {
+            PixelFormat::Mjpeg => Ok(data.to_vec()),
+            format => snapshot::jpeg(data, size, format, frame.stride(), self.quality),
+        }
+    }
+}
+
+/// Lossless 8 bit PNG in RGB or gray.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct PngEncoder;
+
+impl FrameEncoder for PngEncoder {
+    fn input_formats(&self) -> &[PixelFormat] {
+        &[PixelFormat::Rgb, PixelFormat::Gray]
+    }
+
+    fn mime_type(&self) -> &'static str {
+        "image/png"
+    }
+
+    fn fourcc(&self) -> [u8; 4] {
+        *b"MPNG"
+    }
+
+    fn encode(&mut self, frame: &FrameData<'_>) -> io::Result<Vec<u8>> {
+        snapshot::png(frame.data_u8(), frame.size_u32(), frame.format(), frame.stride())
+    }
+}
+
+/// Lossless [QOI](https://qoiformat.org), much faster than PNG for a few more bytes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct QoiEncoder;
+
+impl FrameEncoder for QoiEncoder {
+    fn input_formats(&self) -> &[PixelFormat] {
+        &[PixelFormat::Rgb, PixelFormat::Rgba]
+    }
+
+    fn mime_type(&self) -> &'static str {
+        "image/qoi"
+    }
+
+    fn fourcc(&self) -> [u8; 4] {
+        *b"QOIF"
+    }
+
+    fn encode(&mut self, frame: &FrameData<'_>) -> io::Result<Vec<u8>> {
+        let channels = match frame.format() {
+            PixelFormat::Rgb => 3,
+            PixelFormat::Rgba => 4,
+            format => {
+                let msg = format!("QOI takes RGB or RGBA, not {format:?}");
+                return Err(io::Error::new(io::ErrorKind::InvalidInput, msg));
+            }
+        };
+        let (w, h) = frame.size_u32();
+        let row_len = w as usize * channels;
+        let rows = frame.data_u8().chunks(frame.stride()).take(h as usize);
+        let pixels = rows.flat_map(|row| row[..row_len].chunks_exact(channels));
+        Ok(qoi(
+            pixels.map(|px| [px[0], px[1], px[2], *px.get(3).unwrap_or(&255)]),
+            (w, h),
+            channels,
+        ))
+    }
+}
+
+fn qoi(pixels: impl Iterator<Item = [u8; 4]>, (w, h): (u32, u32), channels: usize) -> Vec<u8> {
+    let mut out = b"qoif".to_vec();
+    out.extend(w.to_be_bytes());
+    out.extend(h.to_be_bytes());
+    out.extend([channels as u8, 0]); // sRGB with linear alpha
+
+    let mut index = [[0u8; 4]; 64];
+    let mut previous = [0, 0, 0, 255];
+    let mut run = 0;
+    for px in pixels {
+        if px == previous {
+            run += 1;
+            if run == 62 {
+                out.push(0xC0 | (run - 1));
+                run = 0;
+            }
+            continue;
+        }
+        if run > 0 {
+            out.push(0xC0 | (run - 1));
+            run = 0;
+        }
+        let [r, g, b, a] = px;
+        let hash = (r as usize * 3 + g as usize * 5 + b as usize * 7 + a as usize * 11) % 64;
+        if index[hash] == px {
+            out.push(hash as u8);
+        } else if a != previous[3] {
+            out.extend([0xFF, r, g, b, a]);
+        } else {
+            let dr = r.wrapping_sub(previous[0]) as i8;
+            let dg = g.wrapping_sub(previous[1]) as i8;
+            let db = b.wrapping_sub(previous[2]) as i8;
+            let (dr_dg, db_dg) = (dr.wrapping_sub(dg), db.wrapping_sub(dg));
+            if [dr, dg, db].iter().all(|d| (-2..=1).contains(d)) {
+                out.push(0x40 | ((dr + 2) as u8) << 4 | ((dg + 2) as u8) << 2 | (db + 2) as u8);
+            } else if (-32..=31).contains(&dg)
+                && (-8..=7).contains(&dr_dg)
+                && (-8..=7).contains(&db_dg)
+            {
+                out.extend([0x80 | (dg + 32) as u8, ((dr_dg + 8) as u8) << 4 | (db_dg + 8) as u8]);
+            } else {
+                out.extend([0xFE, r, g, b]);
+            }
+        }
+        index[hash] = px;
+        previous = px;
+    }
+    if run > 0 {
+        out.push(0xC0 | (run - 1));
+    }
+    out.extend([0, 0, 0, 0, 0, 0, 0, 1]);
+    out
+}
+
+/// H.264 with [OpenH264](https://www.openh264.org) built from source, enabled with the
+/// `openh264` feature. Every frame becomes the NAL units of one access unit in Annex B byte
+/// stream format, the first ones start with SPS and PPS. Width and height must be even.
+#[cfg(feature = "openh264")]
+pub struct H264Encoder {
+    encoder: openh264::encoder::Encoder,
+}
+
+#[cfg(feature = "openh264")]
+impl H264Encoder {
+    /// Aims at `bitrate` bits per second. Frames are never skipped to reach it.
+    pub fn new(bitrate: u32) -> io::Result<Self> {
+        use openh264::encoder::{BitRate, Encoder, EncoderConfig};
+        let config = EncoderConfig::new().bitrate(BitRate::from_bps(bitrate)).skip_frames(false);
+        let api = openh264::OpenH264API::from_source();
+        Ok(Self { encoder: Encoder::with_api_config(api, config).map_err(io::Error::other)? })
+    }
+}
+
+#[cfg(feature = "openh264")]
+impl FrameEncoder for H264Encoder {
+    fn input_formats(&self) -> &[PixelFormat] {
+        &[PixelFormat::I420]
+    }
+
+    fn mime_type(&self) -> &'static str {
+        "video/h264"
+    }
+
+    fn fourcc(&self) -> [u8; 4] {
+        *b"H264"
+    }
+
+    fn encode(&mut self, frame: &FrameData<'_>) -> io::Result<Vec<u8>> {
+        let invalid = |msg: &str| io::Error::new(io::ErrorKind::InvalidInput, msg.to_string());
+        if frame.format() != PixelFormat::I420 {
+            return Err(invalid("H.264 takes I420"));
+        }
+        let (w, h) = frame.size_u32();
+        if w % 2 == 1 || h % 2 == 1 {
+            return Err(invalid("H.264 takes even widths and heights"));
+        }
+        let planes = PixelFormat::I420.planes((w, h), frame.stride());
+        let plane = |index: usize| {
+            let plane = &planes[index];
+            let data = frame.data_u8().get(plane.offset..plane.offset + plane.stride * plane.rows);
+            data.ok_or_else(|| invalid("frame data too short"))
+        };
+        let (y, u, v) = (plane(0)?, plane(1)?, plane(2)?);
+        let strides = (planes[0].stride, planes[1].stride, planes[2].stride);
+        let yuv = openh264::formats::YUVSlices::new((y, u, v), (w as usize, h as usize), strides);
+        Ok(self.encoder.encode(&yuv).map_err(io::Error::other)?.to_vec())
+    }
+}
+
+#[cfg(feature = "openh264")]
+impl std::fmt::Debug for H264Encoder {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("H264Encoder").finish_non_exhaustive()
+    }
+}
+
+impl Frame {
+    /// Encodes the native buffer if the encoder takes its format, otherwise the frame
+    /// converted into one it takes.
+    pub fn encode_with(&self, encoder: &mut dyn FrameEncoder) -> io::Result<Vec<u8>> {
+        let formats = encoder.input_formats().to_vec();
+        if let Some(native) = self.native().filter(|native| formats.contains(&native.format())) {
+            return encoder.encode(&native);
+        }
+        let data = self.data();
+        if formats.contains(&data.format()) {
+            return encoder.encode(&data);
+        }
+        encode_converted(encoder, &self.native().unwrap_or(data))
+    }
+}
+
+impl FrameBuf {
+    /// Encodes the frame, converted if the encoder doesn't take its format.
+    pub fn encode_with(&self, encoder: &mut dyn FrameEncoder) -> io::Result<Vec<u8>> {
+        let data =
+            FrameData::from_bytes(self.data_u8(), self.size_u32(), self.format(), self.stride());
+        if encoder.input_formats().contains(&self.format()) {
+            return encoder.encode(&data);
+        }
+        encode_converted(encoder, &data)
+    }
+}
+
+fn encode_converted(encoder: &mut dyn FrameEncoder, data: &FrameData<'_>) -> io::Result<Vec<u8>> {
+    let (size, format) = (data.size_u32(), data.format());
+    for &target in encoder.input_formats() {
+        let converted = convert::to_format(data.data_u8(), size, format, data.stride(), target);
+        if let Some((pixels, stride)) = converted {
+            return encoder.encode(&FrameData::from_bytes(&pixels, size, target, stride));
+        }
+    }
+    let msg = format!("encoder takes none of the formats {format:?} converts to");
+    Err(io::Error::new(io::ErrorKind::InvalidInput, msg))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    /// Decodes QOI into RGBA, following the reference decoder.
+    fn decode_qoi(qoi: &[u8]) -> ((u32, u32), Vec<[u8; 4]>) {
+        let w = u32::from_be_bytes(qoi[4..8].try_into().unwrap());
+        let h = u32::from_be_bytes(qoi[8..12].try_into().unwrap());
+        let (mut index, mut px, mut pixels) = ([[0u8; 4]; 64], [0, 0, 0, 255u8], Vec::new());
+        let mut pos = 14;
+        while pixels.len() < (w * h) as usize {
+            let op = qoi[pos];
+            pos += 1;
+            match op {
+                0xFE => (px[..3].copy_from_slice(&qoi[pos..pos + 3]), pos += 3).1,
+                0xFF => (px.copy_from_slice(&qoi[pos..pos + 4]), pos += 4).1,
+                _ => match op >> 6 {
+                    0 => px = index[op as usize],
+                    1 => {
+                        px[0] = px[0].wrapping_add((op >> 4 & 3).wrapping_sub(2));
+                        px[1] = px[1].wrapping_add((op >> 2 & 3).wrapping_sub(2));
+                        px[2] = px[2].wrapping_add((op & 3).wrapping_sub(2));
+                    }
+                    2 => {
+                        let dg = (op & 0x3F).wrapping_sub(32);
+                        let next = qoi[pos];
+                        pos += 1;
+                        px[0] = px[0].wrapping_add(dg.wrapping_add((next >> 4).wrapping_sub(8)));
+                        px[1] = px[1].wrapping_add(dg);
+                        px[2] = px[2].wrapping_add(dg.wrapping_add((next & 15).wrapping_sub(8)));
+                    }
+                    _ => {
+                        pixels.extend(std::iter::repeat_n(px, (op & 0x3F) as usize));
+                    }
+                },
+            }
+            let [r, g, b, a] = px.map(|c| c as usize);
+            index[(r * 3 + g * 5 + b * 7 + a * 11) % 64] = px;
+            pixels.push(px);
+        }
+        assert_eq!(&qoi[pos..], [0, 0, 0, 0, 0, 0, 0, 1]);
+        ((w, h), pixels)
+    }
+
+    #[test]
+    fn qoi_round_trip() {
+        // runs, small and large differences, alpha changes and repeated colors
+        let mut rgba = Vec::new();
+        for i in 0..300u32 {
+            let v = (i / 70 * 40) as u8;
+            let alpha = if i % 97 == 0 { 128 } else { 255 };
+            rgba.extend([v.wrapping_add(i as u8 % 3), v, v.wrapping_mul(7), alpha]);
+        }
+        let frame = FrameBuf::new(&rgba, (30, 10), PixelFormat::Rgba, 120, Duration::ZERO);
+        let qoi = frame.encode_with(&mut QoiEncoder).unwrap();
+        assert!(qoi.starts_with(b"qoif\0\0\0\x1e\0\0\0\x0a\x04\0"));
+        let (size, pixels) = decode_qoi(&qoi);
+        assert_eq!(size, (30, 10));
+        assert_eq!(pixels.concat(), rgba);
+    }
+
+    #[test]
+    fn converts_only_when_needed() {
+        let jpeg = b"\xFF\xD8 passed through \xFF\xD9";
+        let mjpeg = FrameBuf::new(jpeg, (8, 8), PixelFormat::Mjpeg, 0, Duration::ZERO);
+        assert_eq!(mjpeg.encode_with(&mut JpegEncoder::default()).unwrap(), jpeg);
+
+        // YUYV becomes RGB, not the gray JPEG also accepts
+        let yuyv = FrameBuf::new(
+            &[90, 50, 90, 200].repeat(8),
+            (4, 4),
+            PixelFormat::Yuyv,
+            8,
+            Duration::ZERO,
+        );
+        let png = yuyv.encode_with(&mut PngEncoder).unwrap();
+        let reader = png::Decoder::new(png.as_slice()).read_info().unwrap();
+        assert_eq!(reader.info().color_type, png::ColorType::Rgb);
+        let gray = FrameBuf::new(&[9; 16], (4, 4), PixelFormat::Gray, 4, Duration::ZERO);
+        let png = gray.encode_with(&mut PngEncoder).unwrap();
+        let reader = png::Decoder::new(png.as_slice()).read_info().unwrap();
+        assert_eq!(reader.info().color_type, png::ColorType::Grayscale);
+
+        struct Mjpeg;
+        impl FrameEncoder for Mjpeg {
+            fn input_formats(&self) -> &[PixelFormat] {
+                &[PixelFormat::Mjpeg]
+            }
+            fn mime_type(&self) -> &'static str {
+                "image/jpeg"
+            }
+            fn fourcc(&self) -> [u8; 4] {
+                *b"MJPG"
+            }
+            fn encode(&mut self, frame: &FrameData<'_>) -> io::Result<Vec<u8>> {
+                Ok(frame.data_u8().to_vec())
+            }
+        }
+        assert!(gray.encode_with(&mut Mjpeg).is_err());
+    }
+
+    #[cfg(feature = "openh264")]
+    #[test]
+    fn h264_decodes_again() {
+        use openh264::formats::YUVSource;
+
+        let gray: Vec<u8> = (0..64 * 48).map(|i| (i % 64 * 4) as u8).collect();
+        let frame = FrameBuf::new(&gray, (64, 48), PixelFormat::Gray, 64, Duration::ZERO);
+        let mut encoder = H264Encoder::new(500_000).unwrap();
+        let mut decoder = openh264::decoder::Decoder::new().unwrap();
+        for _ in 0..3 {
+            let h264 = frame.encode_with(&mut encoder).unwrap();
+            assert!(h264.starts_with(&[0, 0, 0, 1]));
+            let decoded = decoder.decode(&h264).unwrap().unwrap();
+            assert_eq!(decoded.dimensions(), (64, 48));
+        }
+        let odd = FrameBuf::new(&[0; 15], (5, 3), PixelFormat::Gray, 5, Duration::ZERO);
+        let err = odd.encode_with(&mut encoder).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    }
+}
@@ -0,0 +1,287 @@
+use std::io;
+use std::sync::{Arc, Mutex, Weak};
+use std::time::Duration;
+
+use crate::PixelFormat;
+
+/// An owned copy of a frame which can be sent to other threads.
+/// Cloning is cheap, clones share the same pixel data.
+#[derive(Clone)]
+pub struct FrameBuf {
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    // u32 words keep `data_u32` aligned, `len` is the length in bytes
+    words: Vec<u32>,
+    len: usize,
+    size: (u32, u32),
+    format: PixelFormat,
+    stride: usize,
+    timestamp: Duration,
+    pool: Weak<PoolInner>,
+}
+
+impl FrameBuf {
+    /// Panics when `data` doesn't hold a whole frame, see [FrameBuf::try_new].
+    pub fn new(
+        data: &[u8],
+        size: (u32, u32),
+        format: PixelFormat,
+        stride: usize,
+        timestamp: Duration,
+    ) -> Self {
+        Self::try_new(data, size, format, stride, timestamp).expect("frame layout")
+    }
+
+    /// Fails with [InvalidData](io::ErrorKind::InvalidData) when `data` is shorter than
+    /// [PixelFormat::frame_len] or `stride` is shorter than a row, the conversions rely on it.
+    pub fn try_new(
+        data: &[u8],
+        size: (u32, u32),
+        format: PixelFormat,
+        stride: usize,
+        timestamp: Duration,
+    ) -> io::Result<Self> {
+        check_layout(data.len(), size, format, stride)?;
+        Ok(Self::new_in(None, data, size, format, stride, timestamp))
+    }
+
+    pub(crate) fn new_in(
+        pool: Option<&FramePool>,
+        data: &[u8],
+        size: (u32, u32),
+        format: PixelFormat,
+        stride: usize,
+        timestamp: Duration,
+    ) -> Self {
+        let word_len = data.len().div_ceil(4);
+        let mut words = pool.and_then(|p| p.take(word_len)).unwrap_or_default();
+        words.clear();
+        words.resize(word_len, 0);
+        words_as_bytes_mut(&mut words)[..data.len()].copy_from_slice(data);
+
+        let pool = pool.map(|p| Arc::downgrade(&p.inner)).unwrap_or_default();
+        let inner = Inner { words, len: data.len(), size, format, stride, timestamp, pool };
+        Self { inner: Arc::new(inner) }
+    }
+
+    pub fn size_u32(&self) -> (u32, u32) {
+        self.inner.size
+    }
+
+    pub fn format(&self) -> PixelFormat {
+        self.inner.format
+    }
+
+    /// Bytes per row, including padding.
+    pub fn stride(&self) -> usize {
+        self.inner.stride
+    }
+
+    pub fn timestamp(&self) -> Duration {
+        self.inner.timestamp
+    }
+
+    pub fn data_u8(&self) -> &[u8] {
+        &words_as_bytes(&self.inner.words)[..self.inner.len]
+    }
+
+    pub fn data_u32(&self) -> &[u32] {
+        &self.inner.words[..self.inner.len / 4]
+    }
+
+    /// The same frame captured at another time, copied only if clones share the data.
+    pub(crate) fn with_timestamp(mut self, timestamp: Duration) -> Self {
+        if let Some(inner) = Arc::get_mut(&mut self.inner) {
+            inner.timestamp = timestamp;
+            return self;
+        }
+        Self::new(self.data_u8(), self.size_u32(), self.format(), self.stride(), timestamp)
+    }
+}
+
+impl std::fmt::Debug for FrameBuf {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("FrameBuf")
+            .field("size", &self.inner.size)
+            .field("format", &self.inner.format)
+            .field("data", &self.inner.len)
+            .finish()
+    }
+}
+
+impl Drop for Inner {
+    fn drop(&mut self) {
+        if let Some(pool) = self.pool.upgrade() {
+            pool.give(std::mem::take(&mut self.words));
+        }
+    }
+}
+
+/// Recycles the allocations of dropped [FrameBuf]s for new owned frames.
+#[derive(Clone)]
+pub struct FramePool {
+    inner: Arc<PoolInner>,
+}
+
+struct PoolInner {
+    idle: Mutex<Vec<Vec<u32>>>,
+    max_idle: usize,
+}
+
+impl FramePool {
+    /// Keeps at most `max_idle` unused allocations around.
+    pub fn new(max_idle: usize) -> Self {
+        Self { inner: Arc::new(PoolInner { idle: Mutex::new(Vec::new()), max_idle }) }
+    }
+
+    /// Number of allocations waiting to be reused.
+    pub fn idle(&self) -> usize {
+        self.inner.idle.lock().unwrap().len()
+    }
+
+    fn take(&self, word_len: usize) -> Option<Vec<u32>> {
+        let mut idle = self.inner.idle.lock().unwrap();
+        let pos = idle.iter().position(|words| words.capacity() >= word_len).unwrap_or(0);
+        (!idle.is_empty()).then(|| idle.swap_remove(pos))
+    }
+}
+
+impl Default for FramePool {
+    fn default() -> Self {
+        Self::new(4)
+    }
+}
+
+impl std::fmt::Debug for FramePool {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("FramePool").field("idle", &self.idle()).finish()
+    }
+}
+
+impl PoolInner {
+    fn give(&self, words: Vec<u32>) {
+        let mut idle = self.idle.lock().unwrap();
+        if idle.len() < self.max_idle {
+            idle.push(words);
+        }
+    }
+}
+
+fn check_layout(
+    len: usize,
+    size: (u32, u32),
+    format: PixelFormat,
+    stride: usize,
+) -> io::Result<()> {
+    if format.is_compressed() {
+        return Ok(());
+    }
+    let invalid = |msg| Err(io::Error::new(io::ErrorKind::InvalidData, msg));
+    // every format has at least a byte per pixel, which keeps the plane layout from overflowing
+    let (w, h) = (size.0 as u128, size.1 as u128);
+    if w * h > len as u128 || stride as u128 * h > len as u128 {
+        return invalid("data shorter than the frame");
+    }
+    let row_len = format.planes(size, 0).first().map_or(0, |plane| plane.row_len);
+    if stride < row_len && stride != 0 {
+        return invalid("stride shorter than a row");
+    }
+    if len < format.frame_len(size, stride) {
+        return invalid("data shorter than the frame");
+    }
+    Ok(())
+}
+
+fn words_as_bytes(words: &[u32]) -> &[u8] {
+    unsafe { std::slice::from_raw_parts(words.as_ptr().cast(), words.len() * 4) }
+}
+
+fn words_as_bytes_mut(words: &mut [u32]) -> &mut [u8] {
+    unsafe { std::slice::from_raw_parts_mut(words.as_mut_ptr().cast(), words.len() * 4) }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn frame_buf(pool: Option<&FramePool>) -> FrameBuf {
+        let data: Vec<u8> = (0..24).collect();
+        FrameBuf::new_in(pool, &data, (2, 3), PixelFormat::Bgra, 8, Duration::from_millis(40))
+    }
+
+    #[test]
+    fn frame_buf_is_send_and_sync() {
+        fn assert_send_sync<T: Send + Sync>() {}
+        assert_send_sync::<FrameBuf>();
+        assert_send_sync::<FramePool>();
+    }
+
+    #[test]
+    fn frame_buf_keeps_data_and_layout() {
+        let buf = frame_buf(None);
+        assert_eq!(buf.data_u8(), (0..24).collect::<Vec<u8>>());
+        assert_eq!(buf.data_u32().len(), 6);
+        assert_eq!(buf.data_u32()[0], u32::from_ne_bytes([0, 1, 2, 3]));
+        assert_eq!(buf.size_u32(), (2, 3));
+        assert_eq!(buf.stride(), 8);
+        assert_eq!(buf.timestamp(), Duration::from_millis(40));
+    }
+
+    #[test]
+    fn rejects_short_data() {
+        let new = |len, size, stride| {
+            FrameBuf::try_new(&vec![0; len], size, PixelFormat::Yuyv, stride, Duration::ZERO)
+        };
+        assert!(new(64 * 48 * 2, (64, 48), 128).is_ok());
+        assert!(new(64 * 48 * 2, (64, 48), 0).is_ok());
+        let kind = |result: io::Result<FrameBuf>| result.unwrap_err().kind();
+        assert_eq!(kind(new(100, (64, 48), 128)), io::ErrorKind::InvalidData);
+        assert_eq!(kind(new(64 * 48 * 2, (64, 48), 64)), io::ErrorKind::InvalidData);
+        assert_eq!(kind(new(100, (u32::MAX, u32::MAX), usize::MAX)), io::ErrorKind::InvalidData);
+        let mjpeg =
+            FrameBuf::try_new(&[0xff, 0xd8], (640, 480), PixelFormat::Mjpeg, 0, Duration::ZERO);
+        assert!(mjpeg.is_ok());
+    }
+
+    #[test]
+    fn frame_buf_clones_share_data() {
+        let buf = frame_buf(None);
+        let clone = buf.clone();
+        std::thread::spawn(move || assert_eq!(clone.data_u8().len(), 24)).join().unwrap();
+        assert_eq!(buf.data_u8().as_ptr(), buf.clone().data_u8().as_ptr());
+    }
+
+    #[test]
+    fn pool_reuses_allocations() {
+        let pool = FramePool::new(2);
+        let first = frame_buf(Some(&pool));
+        let ptr = first.data_u8().as_ptr();
+        let clone = first.clone();
+        drop(first);
+        assert_eq!(pool.idle(), 0);
+        drop(clone);
+        assert_eq!(pool.idle(), 1);
+
+        let second = frame_buf(Some(&pool));
+        assert_eq!(pool.idle(), 0);
+        assert_eq!(second.data_u8().as_ptr(), ptr);
+    }
+
+    #[test]
+    fn pool_keeps_at_most_max_idle() {
+        let pool = FramePool::new(1);
+        let bufs: Vec<_> = (0..3).map(|_| frame_buf(Some(&pool))).collect();
+        drop(bufs);
+        assert_eq!(pool.idle(), 1);
+    }
+
+    #[test]
+    fn frame_buf_outlives_pool() {
+        let pool = FramePool::new(1);
+        let buf = frame_buf(Some(&pool));
+        drop(pool);
+        assert_eq!(buf.data_u8().len(), 24);
+    }
+}
@@ -0,0 +1,315 @@
+//! A small HTTP server to watch a camera from a browser, enabled with the `http` feature.
+//!
+//! * `/stream` MJPEG as `multipart/x-mixed-replace`, which browsers show in an `<img>`, with
+//!   the frame timestamp in seconds in an `X-Timestamp` header of every part
+//! * `/snapshot` the next frame as JPEG
+//! * `/info` device and mode as JSON
+//!
+//! [Server::set_encoder] streams other formats, for example PNG.
+//!
+//! All clients share one capture, the camera only runs while clients are connected.
+//!
+//! ```no_run
+//! use kamera::{http, Camera};
+//!
+//! let camera = Camera::new_default_device();
+//! let server = http::Server::bind("0.0.0.0:8080").unwrap();
+//! server.run(&camera).unwrap(); // until a StopHandle stops it
+//! ```
+
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::sync::{Arc, Mutex};
+
+use crate::broadcast::{Broadcast, State};
+use crate::{Camera, FrameEncoder, JpegEncoder};
+
+const BOUNDARY: &str = "kameraframe";
+/// Longer request and header lines drop the connection.
+const MAX_LINE: u64 = 8192;
+
+pub struct Server {
+    addr: SocketAddr,
+    broadcast: Arc<Broadcast>,
+    encoder: Mutex<Box<dyn FrameEncoder>>,
+    /// MIME type of the encoder output, read by the connections.
+    content_type: Arc<Mutex<&'static str>>,
+}
+
+/// Stops a running [Server] from another thread.
+#[derive(Clone)]
+pub struct StopHandle {
+    addr: SocketAddr,
+    broadcast: Arc<Broadcast>,
+}
+
+impl Server {
+    /// Accepts connections on a background thread right away, frames are captured while
+    /// [Server::run] runs.
+    pub fn bind(addr: impl ToSocketAddrs) -> io::Result<Self> {
+        let listener = TcpListener::bind(addr)?;
+        let addr = listener.local_addr()?;
+        let broadcast = Arc::new(Broadcast::new());
+        let encoder = JpegEncoder::new(80);
+        let content_type = Arc::new(Mutex::new(encoder.mime_type()));
+        let accepting = broadcast.clone();
+        let accepting_type = content_type.clone();
+        std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                if accepting.state().stopped {
+                    break;
+                }
+                let Ok(stream) = stream else { continue };
+                let broadcast = accepting.clone();
+                let content_type = *accepting_type.lock().unwrap();
+                std::thread::spawn(move || {
+                    // errors only end this connection
+                    let _ = handle_connection(stream, &broadcast, content_type);
+                });
+            }
+        });
+        let encoder = Mutex::new(Box::new(encoder) as Box<dyn FrameEncoder>);
+        Ok(Self { addr, broadcast, encoder, content_type })
+    }
+
+    pub fn local_addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// JPEG quality from 1 to 100 for cameras which don't deliver MJPEG, 80 by default.
+    /// Replaces an encoder given to [Server::set_encoder].
+    pub fn set_quality(&mut self, quality: u8) {
+        self.set_encoder(JpegEncoder::new(quality));
+    }
+
+    /// Encodes the frames with `encoder`, served with its [FrameEncoder::mime_type].
+    pub fn set_encoder(&mut self, encoder: impl FrameEncoder + 'static) {
+        *self.content_type.lock().unwrap() = encoder.mime_type();
+        *self.encoder.get_mut().unwrap() = Box::new(encoder);
+    }
+
+    pub fn stop_handle(&self) -> StopHandle {
+        StopHandle { addr: self.addr, broadcast: self.broadcast.clone() }
+    }
+
+    /// Captures frames for the connected clients until stopped. Starts the camera when the
+    /// first client connects and stops it when the last one leaves. The server stops as well
+    /// when the camera fails.
+    pub fn run(&self, camera: &Camera) -> io::Result<()> {
+        let mut encoder = self.encoder.lock().unwrap();
+        let result = self.broadcast.run(camera, |frame| frame.encode_with(&mut **encoder));
+        self.stop_handle().stop();
+        result
+    }
+}
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        self.stop_handle().stop();
+    }
+}
+
+impl std::fmt::Debug for Server {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Server").field("addr", &self.addr).finish()
+    }
+}
+
+impl StopHandle {
+    /// Ends [Server::run], the streams and the accepting of connections.
+    pub fn stop(&self) {
+        self.broadcast.stop();
+        // wakes up the accepting thread
+        let _ = TcpStream::connect(self.addr);
+    }
+}
+
+impl std::fmt::Debug for StopHandle {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("StopHandle").field("addr", &self.addr).finish()
+    }
+}
+
+/// Like [BufRead::read_line], failing after [MAX_LINE] bytes without a newline.
+fn read_line(reader: &mut impl BufRead, line: &mut String) -> io::Result<usize> {
+    let len = io::Read::take(reader, MAX_LINE).read_line(line)?;
+    if len as u64 == MAX_LINE && !line.ends_with('\n') {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "line too long"));
+    }
+    Ok(len)
+}
+
+fn handle_connection(
+    stream: TcpStream,
+    broadcast: &Broadcast,
+    content_type: &str,
+) -> io::Result<()> {
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut request = String::new();
+    read_line(&mut reader, &mut request)?;
+    // the headers don't matter, but a client expects them to be read
+    let mut line = String::new();
+    while read_line(&mut reader, &mut line)? > 2 {
+        line.clear();
+    }
+
+    let mut out = io::BufWriter::new(stream);
+    let mut parts = request.split_whitespace();
+    let (method, path) = (parts.next().unwrap_or(""), parts.next().unwrap_or(""));
+    if method != "GET" {
+        return respond(&mut out, "405 Method Not Allowed", "text/plain", b"GET only\n");
+    }
+    match path.split('?').next().unwrap_or("") {
+        "/stream" => {
+            let mut client = broadcast.client();
+            write!(
+                out,
+                "HTTP/1.1 200 OK\r\nContent-Type: multipart/x-mixed-replace; boundary={BOUNDARY}\r\n\
+                 Cache-Control: no-cache\r\nConnection: close\r\n\r\n"
+            )?;
+            while let Some(frame) = client.next_frame() {
+                write!(
+                    out,
+                    "--{BOUNDARY}\r\nContent-Type: {content_type}\r\nContent-Length: {}\r\n\
+                     X-Timestamp: {:.6}\r\n\r\n",
+                    frame.data.len(),
+                    frame.timestamp.as_secs_f64(),
+                )?;
+                out.write_all(&frame.data)?;
+                out.write_all(b"\r\n")?;
+                out.flush()?;
+            }
+            Ok(())
+        }
+        "/snapshot" => match broadcast.client().next_frame() {
+            Some(frame) => respond(&mut out, "200 OK", content_type, &frame.data),
+            None => respond(&mut out, "503 Service Unavailable", "text/plain", b"stopped\n"),
+        },
+        "/info" => {
+            let json = info_json(&broadcast.state());
+            respond(&mut out, "200 OK", "application/json", json.as_bytes())
+        }
+        _ => respond(&mut out, "404 Not Found", "text/plain", b"try /stream, /snapshot or /info\n"),
+    }
+}
+
+fn respond(out: &mut impl Write, status: &str, content_type: &str, body: &[u8]) -> io::Result<()> {
+    write!(
+        out,
+        "HTTP/1.1 {status}\r\nContent-Type: {content_type}\r\nContent-Length: {}\r\n\
+         Cache-Control: no-cache\r\nConnection: close\r\n\r\n",
+        body.len()
+    )?;
+    out.write_all(body)?;
+    out.flush()
+}
+
+fn info_json(state: &State) -> String {
+    let format = state.format.map_or("null".to_string(), |f| json_string(&format!("{f:?}")));
+    format!(
+        "{{\"device\":{},\"width\":{},\"height\":{},\"format\":{format},\"fps\":{:.2},\"clients\":{}}}",
+        json_string(&state.device),
+        state.size.0,
+        state.size.1,
+        state.fps(),
+        state.clients,
+    )
+}
+
+fn json_string(text: &str) -> String {
+    let mut json = String::from('"');
+    for c in text.chars() {
+        match c {
+            '"' => json.push_str("\\\""),
+            '\\' => json.push_str("\\\\"),
+            c if (c as u32) < 0x20 => json.push_str(&format!("\\u{:04x}", c as u32)),
+            c => json.push(c),
+        }
+    }
+    json.push('"');
+    json
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{y4m, FrameBuf, Pacing, PixelFormat, ReplayOptions};
+    use std::io::Read;
+    use std::time::Duration;
+
+    fn get(addr: SocketAddr, path: &str) -> TcpStream {
+        let mut stream = TcpStream::connect(addr).unwrap();
+        write!(stream, "GET {path} HTTP/1.1\r\nHost: localhost\r\n\r\n").unwrap();
+        stream
+    }
+
+    fn response(addr: SocketAddr, path: &str) -> Vec<u8> {
+        let mut body = Vec::new();
+        get(addr, path).read_to_end(&mut body).unwrap();
+        body
+    }
+
+    #[test]
+    fn json_escapes() {
+        assert_eq!(json_string("a\"b\\c\n"), "\"a\\\"b\\\\c\\u000a\"");
+    }
+
+    #[test]
+    fn drops_clients_with_endless_lines() {
+        let server = Server::bind("127.0.0.1:0").unwrap();
+        for start in ["", "GET /info HTTP/1.1\r\nX-Long: "] {
+            let mut stream = TcpStream::connect(server.local_addr()).unwrap();
+            let _ = stream.write_all(start.as_bytes());
+            let _ = stream.write_all(&[b'a'; 3 * MAX_LINE as usize]);
+            let mut response = Vec::new();
+            let closed = stream.read_to_end(&mut response);
+            assert!(closed.is_err() || response.is_empty(), "{start:?}");
+        }
+    }
+
+    #[test]
+    fn serves_stream_snapshot_and_info() {
+        let path = std::env::temp_dir().join(format!("kamera_http_{}.y4m", std::process::id()));
+        let header = y4m::Header::new((16, 8), y4m::Chroma::Mono, (30, 1));
+        let mut writer = y4m::Writer::create(&path, header).unwrap();
+        for value in [0, 128, 255] {
+            let frame =
+                FrameBuf::new(&[value; 128], (16, 8), PixelFormat::Gray, 16, Duration::ZERO);
+            writer.write_frame_buf(&frame).unwrap();
+        }
+        writer.into_inner().unwrap();
+        let options = ReplayOptions { pacing: Pacing::RealTime, looping: true };
+        let camera = Camera::open_file_with(&path, options).unwrap();
+
+        let server = Server::bind("127.0.0.1:0").unwrap();
+        let (addr, stop) = (server.local_addr(), server.stop_handle());
+        let client = std::thread::spawn(move || {
+            let snapshot = response(addr, "/snapshot");
+            assert!(snapshot.starts_with(b"HTTP/1.1 200 OK\r\nContent-Type: image/jpeg"));
+            let body = snapshot.windows(4).position(|w| w == b"\r\n\r\n").unwrap() + 4;
+            assert!(snapshot[body..].starts_with(&[0xFF, 0xD8]));
+
+            // two clients share the capture
+            let mut streams = [get(addr, "/stream"), get(addr, "/stream")];
+            for stream in &mut streams {
+                let mut start = vec![0; 4096];
+                let mut len = 0;
+                while !start[..len].windows(2).any(|w| w == b"\xFF\xD8") {
+                    len += stream.read(&mut start[len..]).unwrap();
+                }
+                let head = String::from_utf8_lossy(&start[..len]).to_string();
+                assert!(head.contains("multipart/x-mixed-replace; boundary=kameraframe"));
+                assert!(head.contains("--kameraframe\r\nContent-Type: image/jpeg"));
+            }
+
+            let info = String::from_utf8(response(addr, "/info")).unwrap();
+            assert!(info.contains("\"width\":16,\"height\":8,\"format\":\"Gray\""), "{info}");
+            assert!(info.contains("\"clients\":2"), "{info}");
+            assert!(response(addr, "/nothing").starts_with(b"HTTP/1.1 404"));
+            stop.stop();
+        });
+        server.run(&camera).unwrap();
+        client.join().unwrap();
+        std::fs::remove_file(&path).unwrap();
+    }
+}
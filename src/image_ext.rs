@@ -0,0 +1,130 @@
+//! Interoperability with the `image` crate, enabled with the `image` feature.
+
+use image::{DynamicImage, GrayImage, ImageBuffer, Luma, Pixel, Rgb, RgbImage, Rgba, RgbaImage};
+
+use crate::{convert, Frame, FrameBuf, PixelFormat};
+
+impl Frame {
+    pub fn to_rgba_image(&self) -> RgbaImage {
+        let data = self.data();
+        let rgba = convert::to_rgba(data.data_u8(), data.size_u32(), data.format(), data.stride());
+        buffer(data.size_u32(), rgba)
+    }
+
+    pub fn to_rgb_image(&self) -> RgbImage {
+        let data = self.data();
+        let rgb = convert::to_rgb(data.data_u8(), data.size_u32(), data.format(), data.stride());
+        buffer(data.size_u32(), rgb)
+    }
+
+    pub fn to_luma_image(&self) -> GrayImage {
+        let data = self.data();
+        let luma = convert::to_luma(data.data_u8(), data.size_u32(), data.format(), data.stride());
+        buffer(data.size_u32(), luma)
+    }
+}
+
+impl FrameBuf {
+    pub fn to_rgba_image(&self) -> RgbaImage {
+        let rgba = convert::to_rgba(self.data_u8(), self.size_u32(), self.format(), self.stride());
+        buffer(self.size_u32(), rgba)
+    }
+
+    pub fn to_rgb_image(&self) -> RgbImage {
+        let rgb = convert::to_rgb(self.data_u8(), self.size_u32(), self.format(), self.stride());
+        buffer(self.size_u32(), rgb)
+    }
+
+    pub fn to_luma_image(&self) -> GrayImage {
+        let luma = convert::to_luma(self.data_u8(), self.size_u32(), self.format(), self.stride());
+        buffer(self.size_u32(), luma)
+    }
+
+    /// Zero-copy view, if the frame is [PixelFormat::Rgba] without row padding.
+    pub fn as_rgba_image(&self) -> Option<ImageBuffer<Rgba<u8>, &[u8]>> {
+        self.view(PixelFormat::Rgba)
+    }
+
+    /// Zero-copy view, if the frame is [PixelFormat::Rgb] without row padding.
+    pub fn as_rgb_image(&self) -> Option<ImageBuffer<Rgb<u8>, &[u8]>> {
+        self.view(PixelFormat::Rgb)
+    }
+
+    /// Zero-copy view, if the frame is [PixelFormat::Gray] without row padding.
+    pub fn as_luma_image(&self) -> Option<ImageBuffer<Luma<u8>, &[u8]>> {
+        self.view(PixelFormat::Gray)
+    }
+
+    fn view<P: Pixel<Subpixel = u8>>(&self, format: PixelFormat) -> Option<ImageBuffer<P, &[u8]>> {
+        let (w, h) = self.size_u32();
+        let len = self.stride() * h as usize;
+        let packed = self.stride() == w as usize * format.bytes_per_pixel();
+        (self.format() == format && packed && self.data_u8().len() >= len)
+            .then(|| ImageBuffer::from_raw(w, h, &self.data_u8()[..len]))
+            .flatten()
+    }
+
+    /// Copies an image into a frame, for example to feed test images into frame processing.
+    /// 8 bit gray, RGB and RGBA images keep their format, everything else becomes RGBA.
+    pub fn from_image(image: &DynamicImage) -> Self {
+        let size = (image.width(), image.height());
+        let timestamp = std::time::Duration::ZERO;
+        match image {
+            DynamicImage::ImageLuma8(gray) => {
+                FrameBuf::new(gray.as_raw(), size, PixelFormat::Gray, size.0 as usize, timestamp)
+            }
+            DynamicImage::ImageRgb8(rgb) => {
+                FrameBuf::new(rgb.as_raw(), size, PixelFormat::Rgb, size.0 as usize * 3, timestamp)
+            }
+            image => {
+                let rgba = image.to_rgba8();
+                FrameBuf::new(
+                    rgba.as_raw(),
+                    size,
+                    PixelFormat::Rgba,
+                    size.0 as usize * 4,
+                    timestamp,
+                )
+            }
+        }
+    }
+}
+
+impl From<&DynamicImage> for FrameBuf {
+    fn from(image: &DynamicImage) -> Self {
+        FrameBuf::from_image(image)
+    }
+}
+
+fn buffer<P: Pixel<Subpixel = u8>>((w, h): (u32, u32), data: Vec<u8>) -> ImageBuffer<P, Vec<u8>> {
+    ImageBuffer::from_raw(w, h, data).expect("converted buffer matches the frame size")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn dynamic_image_round_trip() {
+        let rgb = RgbImage::from_fn(3, 2, |x, y| Rgb([x as u8, y as u8, 7]));
+        let frame = FrameBuf::from_image(&DynamicImage::ImageRgb8(rgb.clone()));
+        assert_eq!(frame.format(), PixelFormat::Rgb);
+        assert_eq!(frame.to_rgb_image(), rgb);
+        assert_eq!(frame.as_rgb_image().unwrap().as_raw(), &rgb.as_raw().as_slice());
+        assert!(frame.as_rgba_image().is_none());
+        assert_eq!(frame.to_rgba_image().get_pixel(2, 1), &Rgba([2, 1, 7, 255]));
+    }
+
+    #[test]
+    fn bgra_frame_to_rgba_image() {
+        let frame = FrameBuf::new(
+            &[1, 2, 3, 4, 0, 0, 0, 0],
+            (1, 1),
+            PixelFormat::Bgra,
+            8,
+            Default::default(),
+        );
+        assert_eq!(frame.to_rgba_image().into_raw(), [3, 2, 1, 4]);
+        assert_eq!(frame.to_luma_image().into_raw(), [2]);
+    }
+}
@@ -1,5 +1,39 @@
+mod avi;
+#[cfg(any(feature = "http", feature = "rtsp"))]
+mod broadcast;
 mod camera;
+mod convert;
+mod encoder;
+mod frame_buf;
+#[cfg(feature = "http")]
+pub mod http;
+#[cfg(feature = "image")]
+mod image_ext;
+#[cfg(feature = "ndarray")]
+mod ndarray_ext;
+mod pixel_format;
+pub mod raw;
+mod recorder;
+mod replay;
+#[cfg(feature = "rtsp")]
+pub mod rtsp;
+#[cfg(all(feature = "shm", target_os = "linux"))]
+pub mod shm;
+mod snapshot;
+mod subscribe;
+mod timelapse;
+mod virtual_camera;
+pub mod y4m;
 pub use camera::*;
+pub use encoder::*;
+pub use frame_buf::*;
+pub use pixel_format::*;
+pub use recorder::*;
+pub use replay::*;
+pub use snapshot::*;
+pub use subscribe::*;
+pub use timelapse::*;
+pub use virtual_camera::*;
 
 #[cfg(target_os = "macos")]
 pub(crate) mod mac_avf;
@@ -9,3 +43,7 @@ pub(crate) mod win_mf;
 
 #[cfg(target_os = "linux")]
 pub(crate) mod linux_v4l2;
+#[cfg(target_os = "linux")]
+pub use linux_v4l2::{
+    DeviceInfo, FrameSizes, IoMode, Mode, ModeConfig, ReconnectEvent, ReconnectPolicy, UvcMetadata,
+};
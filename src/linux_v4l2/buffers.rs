@@ -0,0 +1,510 @@
+//! Mapped driver buffers lent to frames, queued back when the frame is dropped.
+
+use std::io;
+use std::os::fd::{AsFd, BorrowedFd, FromRawFd, OwnedFd};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use v4l::buffer::Type;
+use v4l::device::Handle;
+use v4l::v4l2;
+use v4l::v4l_sys::*;
+
+use super::ioctl;
+
+/// The buffer ioctls of a capture device, faked in tests.
+pub(crate) trait Driver: Send + Sync {
+    /// Allocates `count` buffers and returns how many the driver allocated, 0 frees them.
+    fn request(&self, count: u32) -> io::Result<u32>;
+    /// Maps each plane of the buffer, one unless the format is multi-planar.
+    fn map(&self, index: u32) -> io::Result<Vec<Box<dyn Memory>>>;
+    /// A dma-buf of a plane of the buffer for other devices.
+    fn export(&self, index: u32, plane: usize) -> io::Result<OwnedFd>;
+    fn queue(&self, index: u32) -> io::Result<()>;
+    /// Waits until a filled buffer can be dequeued.
+    fn wait(&self) -> io::Result<()>;
+    /// The next filled buffer, [io::ErrorKind::WouldBlock] when there is none yet.
+    fn dequeue(&self) -> io::Result<Dequeued>;
+    fn stream_on(&self) -> io::Result<()>;
+    fn stream_off(&self) -> io::Result<()>;
+}
+
+pub(crate) trait Memory: Send + Sync {
+    fn bytes(&self) -> &[u8];
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct Dequeued {
+    pub index: u32,
+    /// Where the data of each plane starts and ends, the end is 0 for the whole plane.
+    pub planes: Vec<(u32, u32)>,
+    pub timestamp: Duration,
+    /// The timestamp is from `CLOCK_MONOTONIC`.
+    pub monotonic: bool,
+    pub sequence: u32,
+}
+
+/// The buffers of one stream, shared with the frames holding them.
+pub(crate) struct Buffers {
+    driver: Box<dyn Driver>,
+    buffers: Vec<Vec<Plane>>,
+    state: Mutex<State>,
+}
+
+struct Plane {
+    memory: Box<dyn Memory>,
+    dmabuf: Option<OwnedFd>,
+}
+
+#[derive(Default)]
+struct State {
+    streaming: bool,
+    /// The driver freed the buffers, they stay mapped for the frames still holding them.
+    released: bool,
+    queued: usize,
+}
+
+/// A dequeued buffer, queued back on drop.
+pub(crate) struct Lease {
+    buffers: Arc<Buffers>,
+    dequeued: Dequeued,
+}
+
+impl Buffers {
+    pub fn new(driver: Box<dyn Driver>, count: u32) -> io::Result<Arc<Self>> {
+        let count = driver.request(count)?;
+        let buffers = (0..count)
+            .map(|index| {
+                let planes = driver.map(index)?.into_iter().enumerate();
+                // not every driver exports, frames then only have the mapping
+                let planes = planes.map(|(plane, memory)| Plane {
+                    memory,
+                    dmabuf: driver.export(index, plane).ok(),
+                });
+                Ok(planes.collect())
+            })
+            .collect::<io::Result<_>>();
+        let buffers = match buffers {
+            Ok(buffers) => buffers,
+            Err(err) => {
+                let _ = driver.request(0);
+                return Err(err);
+            }
+        };
+        Ok(Arc::new(Self { driver, buffers, state: Mutex::default() }))
+    }
+
+    pub fn start(&self) -> io::Result<()> {
+        let mut state = self.state.lock().unwrap();
+        for index in 0..self.buffers.len() {
+            self.driver.queue(index as u32)?;
+            state.queued += 1;
+        }
+        self.driver.stream_on()?;
+        state.streaming = true;
+        Ok(())
+    }
+
+    /// Stops streaming and frees the buffers in the driver. Buffers still held by frames
+    /// stay mapped and exported until those are dropped, which needs Linux 5.0 or newer to
+    /// allocate buffers again meanwhile.
+    pub fn stop(&self) {
+        let mut state = self.state.lock().unwrap();
+        if !state.released {
+            let _ = self.driver.stream_off();
+            let _ = self.driver.request(0);
+            *state = State { streaming: false, released: true, queued: 0 };
+        }
+    }
+
+    pub fn next(self: &Arc<Self>) -> io::Result<Lease> {
+        self.driver.wait()?;
+        self.dequeue()
+    }
+
+    /// Waits for a buffer like [Buffers::next], then takes the newest of the filled ones and
+    /// gives the older ones back to the driver.
+    pub fn newest(self: &Arc<Self>) -> io::Result<Lease> {
+        let mut lease = self.next()?;
+        // bounded in case the driver fills buffers as fast as they come back
+        for _ in 1..self.buffers.len() {
+            match self.dequeue() {
+                Ok(newer) => lease = newer,
+                Err(_) => break,
+            }
+        }
+        Ok(lease)
+    }
+
+    /// The next filled buffer without waiting, [io::ErrorKind::WouldBlock] when there is none.
+    pub fn try_next(self: &Arc<Self>) -> io::Result<Lease> {
+        self.dequeue()
+    }
+
+    fn dequeue(self: &Arc<Self>) -> io::Result<Lease> {
+        let dequeued = self.driver.dequeue()?;
+        self.state.lock().unwrap().queued -= 1;
+        Ok(Lease { buffers: self.clone(), dequeued })
+    }
+
+    /// Buffers the driver can fill, the others are held by frames.
+    pub fn queued(&self) -> usize {
+        self.state.lock().unwrap().queued
+    }
+}
+
+impl Drop for Buffers {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+impl Lease {
+    pub fn planes(&self) -> usize {
+        self.buffer().len()
+    }
+
+    pub fn data(&self, plane: usize) -> &[u8] {
+        let bytes = self.buffer()[plane].memory.bytes();
+        // compressed frames are usually shorter than the buffer
+        let (start, end) = self.dequeued.planes.get(plane).copied().unwrap_or_default();
+        let end = if end == 0 { bytes.len() } else { (end as usize).min(bytes.len()) };
+        &bytes[(start as usize).min(end)..end]
+    }
+
+    /// The dma-buf of a plane and where the data starts in it.
+    pub fn dmabuf(&self, plane: usize) -> Option<(BorrowedFd<'_>, usize)> {
+        let start = self.dequeued.planes.get(plane).map_or(0, |plane| plane.0 as usize);
+        self.buffer()[plane].dmabuf.as_ref().map(|fd| (fd.as_fd(), start))
+    }
+
+    pub fn timestamp(&self) -> Duration {
+        self.dequeued.timestamp
+    }
+
+    pub fn sequence(&self) -> u32 {
+        self.dequeued.sequence
+    }
+
+    pub fn monotonic(&self) -> bool {
+        self.dequeued.monotonic
+    }
+
+    fn buffer(&self) -> &[Plane] {
+        &self.buffers.buffers[self.dequeued.index as usize]
+    }
+}
+
+impl Drop for Lease {
+    fn drop(&mut self) {
+        let mut state = self.buffers.state.lock().unwrap();
+        if state.streaming && self.buffers.driver.queue(self.dequeued.index).is_ok() {
+            state.queued += 1;
+        }
+    }
+}
+
+impl std::fmt::Debug for Lease {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Lease").field("index", &self.dequeued.index).finish()
+    }
+}
+
+/// [Driver] of a V4L2 device with mmap buffers.
+pub(crate) struct DeviceDriver {
+    handle: Arc<Handle>,
+    buf_type: Type,
+    /// Memory planes of multi-planar formats, 0 for the single-planar API.
+    planes: usize,
+}
+
+impl DeviceDriver {
+    pub fn new(handle: Arc<Handle>, buf_type: Type, planes: usize) -> Self {
+        let planes = if matches!(buf_type, Type::VideoCaptureMplane) { planes } else { 0 };
+        Self { handle, buf_type, planes }
+    }
+
+    fn ioctl<T>(&self, request: v4l2::vidioc::_IOC_TYPE, arg: &mut T) -> io::Result<()> {
+        ioctl(self.handle.fd(), request, arg)
+    }
+
+    /// A buffer for the ioctls, multi-planar ones point to `planes`.
+    fn buffer(&self, index: u32, planes: &mut [v4l2_plane; 8]) -> v4l2_buffer {
+        let mut buffer = v4l2_buffer {
+            index,
+            type_: self.buf_type as u32,
+            memory: v4l::memory::Memory::Mmap as u32,
+            ..unsafe { std::mem::zeroed() }
+        };
+        if self.planes > 0 {
+            buffer.m.planes = planes.as_mut_ptr();
+            buffer.length = self.planes as u32;
+        }
+        buffer
+    }
+}
+
+impl Driver for DeviceDriver {
+    fn request(&self, count: u32) -> io::Result<u32> {
+        let mut request = v4l2_requestbuffers {
+            count,
+            type_: self.buf_type as u32,
+            memory: v4l::memory::Memory::Mmap as u32,
+            ..unsafe { std::mem::zeroed() }
+        };
+        self.ioctl(v4l2::vidioc::VIDIOC_REQBUFS, &mut request)?;
+        Ok(request.count)
+    }
+
+    fn map(&self, index: u32) -> io::Result<Vec<Box<dyn Memory>>> {
+        let mut planes = unsafe { std::mem::zeroed() };
+        let mut buffer = self.buffer(index, &mut planes);
+        self.ioctl(v4l2::vidioc::VIDIOC_QUERYBUF, &mut buffer)?;
+        let ranges: Vec<_> = if self.planes > 0 {
+            let planes = &planes[..self.planes];
+            planes.iter().map(|plane| (unsafe { plane.m.mem_offset }, plane.length)).collect()
+        } else {
+            vec![(unsafe { buffer.m.offset }, buffer.length)]
+        };
+        ranges
+            .into_iter()
+            .map(|(offset, len)| {
+                let len = len as usize;
+                let ptr = unsafe {
+                    v4l2::mmap(
+                        std::ptr::null_mut(),
+                        len,
+                        libc::PROT_READ | libc::PROT_WRITE,
+                        libc::MAP_SHARED,
+                        self.handle.fd(),
+                        offset as libc::off_t,
+                    )?
+                };
+                Ok(Box::new(Mapping { ptr: ptr as *mut u8, len }) as Box<dyn Memory>)
+            })
+            .collect()
+    }
+
+    fn export(&self, index: u32, plane: usize) -> io::Result<OwnedFd> {
+        let mut export = v4l2_exportbuffer {
+            type_: self.buf_type as u32,
+            index,
+            plane: plane as u32,
+            flags: libc::O_CLOEXEC as u32,
+            ..unsafe { std::mem::zeroed() }
+        };
+        self.ioctl(v4l2::vidioc::VIDIOC_EXPBUF, &mut export)?;
+        Ok(unsafe { OwnedFd::from_raw_fd(export.fd) })
+    }
+
+    fn queue(&self, index: u32) -> io::Result<()> {
+        let mut planes = unsafe { std::mem::zeroed() };
+        self.ioctl(v4l2::vidioc::VIDIOC_QBUF, &mut self.buffer(index, &mut planes))
+    }
+
+    fn wait(&self) -> io::Result<()> {
+        self.handle.poll(libc::POLLIN, -1).map(|_| ())
+    }
+
+    fn dequeue(&self) -> io::Result<Dequeued> {
+        // the device is nonblocking, EAGAIN is WouldBlock
+        let mut planes = unsafe { std::mem::zeroed() };
+        let mut buffer = self.buffer(0, &mut planes);
+        self.ioctl(v4l2::vidioc::VIDIOC_DQBUF, &mut buffer)?;
+        // bytesused of a multi-planar buffer includes the data offset
+        let planes = if self.planes > 0 {
+            let planes = &planes[..self.planes];
+            planes.iter().map(|plane| (plane.data_offset, plane.bytesused)).collect()
+        } else {
+            vec![(0, buffer.bytesused)]
+        };
+        Ok(Dequeued {
+            index: buffer.index,
+            planes,
+            timestamp: Duration::new(
+                buffer.timestamp.tv_sec as u64,
+                buffer.timestamp.tv_usec as u32 * 1000,
+            ),
+            monotonic: buffer.flags & TIMESTAMP_MASK == TIMESTAMP_MONOTONIC,
+            sequence: buffer.sequence,
+        })
+    }
+
+    fn stream_on(&self) -> io::Result<()> {
+        self.ioctl(v4l2::vidioc::VIDIOC_STREAMON, &mut (self.buf_type as i32))
+    }
+
+    fn stream_off(&self) -> io::Result<()> {
+        self.ioctl(v4l2::vidioc::VIDIOC_STREAMOFF, &mut (self.buf_type as i32))
+    }
+}
+
+const TIMESTAMP_MASK: u32 = 0xe000;
+const TIMESTAMP_MONOTONIC: u32 = 0x2000;
+
+struct Mapping {
+    ptr: *mut u8,
+    len: usize,
+}
+
+// the mapping is only read while the driver doesn't own the buffer
+unsafe impl Send for Mapping {}
+unsafe impl Sync for Mapping {}
+
+impl Memory for Mapping {
+    fn bytes(&self) -> &[u8] {
+        unsafe { std::slice::from_raw_parts(self.ptr, self.len) }
+    }
+}
+
+impl Drop for Mapping {
+    fn drop(&mut self) {
+        let _ = unsafe { v4l2::munmap(self.ptr as *mut std::ffi::c_void, self.len) };
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use std::collections::VecDeque;
+    use std::os::fd::AsRawFd;
+
+    /// A driver filling every buffer with its index, in `planes` planes.
+    #[derive(Default)]
+    pub(crate) struct FakeDriver {
+        pub state: Arc<Mutex<FakeState>>,
+        pub planes: usize,
+    }
+
+    #[derive(Debug, Default)]
+    pub(crate) struct FakeState {
+        pub allocated: u32,
+        pub queued: VecDeque<u32>,
+        pub streaming: bool,
+        pub sequence: u32,
+        /// Frames the fake fills before it stops, endless with `None`.
+        pub frames: Option<u32>,
+    }
+
+    impl Driver for FakeDriver {
+        fn request(&self, count: u32) -> io::Result<u32> {
+            self.state.lock().unwrap().allocated = count;
+            Ok(count)
+        }
+
+        fn map(&self, index: u32) -> io::Result<Vec<Box<dyn Memory>>> {
+            let plane = |plane| Box::new(vec![index as u8 * 10 + plane as u8; 16]) as _;
+            Ok((0..self.planes.max(1)).map(plane).collect())
+        }
+
+        fn export(&self, _index: u32, _plane: usize) -> io::Result<OwnedFd> {
+            Ok(std::fs::File::open("/dev/null")?.into())
+        }
+
+        fn queue(&self, index: u32) -> io::Result<()> {
+            self.state.lock().unwrap().queued.push_back(index);
+            Ok(())
+        }
+
+        fn wait(&self) -> io::Result<()> {
+            Ok(())
+        }
+
+        fn dequeue(&self) -> io::Result<Dequeued> {
+            let mut state = self.state.lock().unwrap();
+            if state.frames.is_some_and(|frames| state.sequence >= frames) {
+                return Err(io::ErrorKind::WouldBlock.into());
+            }
+            let index = state.queued.pop_front().ok_or(io::ErrorKind::WouldBlock)?;
+            state.sequence += 1;
+            let timestamp = Duration::from_millis(state.sequence as u64 * 33);
+            let planes = vec![(0, 8); self.planes.max(1)];
+            let sequence = state.sequence - 1;
+            Ok(Dequeued { index, planes, timestamp, monotonic: false, sequence })
+        }
+
+        fn stream_on(&self) -> io::Result<()> {
+            self.state.lock().unwrap().streaming = true;
+            Ok(())
+        }
+
+        fn stream_off(&self) -> io::Result<()> {
+            let mut state = self.state.lock().unwrap();
+            state.streaming = false;
+            state.queued.clear();
+            Ok(())
+        }
+    }
+
+    impl Memory for Vec<u8> {
+        fn bytes(&self) -> &[u8] {
+            self
+        }
+    }
+
+    #[test]
+    fn leases_until_dropped() {
+        let driver = FakeDriver::default();
+        let fake = driver.state.clone();
+        let buffers = Buffers::new(Box::new(driver), 3).unwrap();
+        buffers.start().unwrap();
+
+        let first = buffers.next().unwrap();
+        let second = buffers.next().unwrap();
+        assert_eq!((first.data(0), second.data(0)), (&[0; 8][..], &[10; 8][..]));
+        assert_eq!((first.sequence(), second.sequence()), (0, 1));
+        assert!(first.dmabuf(0).unwrap().0.as_raw_fd() >= 0);
+        assert_eq!(buffers.queued(), 1);
+        assert_eq!(fake.lock().unwrap().queued, [2]);
+
+        drop(first);
+        assert_eq!(buffers.queued(), 2);
+        assert_eq!(fake.lock().unwrap().queued, [2, 0]);
+        drop(second);
+    }
+
+    #[test]
+    fn stop_keeps_held_buffers() {
+        let driver = FakeDriver::default();
+        let fake = driver.state.clone();
+        let buffers = Buffers::new(Box::new(driver), 2).unwrap();
+        buffers.start().unwrap();
+        let held = buffers.next().unwrap();
+
+        buffers.stop();
+        drop(buffers);
+        assert_eq!(fake.lock().unwrap().allocated, 0);
+        // still mapped and exported, but not queued into the freed buffers
+        assert_eq!(held.data(0), [0; 8]);
+        assert!(held.dmabuf(0).unwrap().0.try_clone_to_owned().is_ok());
+        drop(held);
+        assert!(fake.lock().unwrap().queued.is_empty());
+    }
+
+    #[test]
+    fn drains_to_newest() {
+        let driver = FakeDriver::default();
+        let fake = driver.state.clone();
+        fake.lock().unwrap().frames = Some(4);
+        let buffers = Buffers::new(Box::new(driver), 4).unwrap();
+        buffers.start().unwrap();
+        let held = buffers.next().unwrap();
+
+        // the fake filled the other three, the two older ones go back to the driver
+        let newest = buffers.newest().unwrap();
+        assert_eq!((held.sequence(), newest.sequence()), (0, 3));
+        assert_eq!(fake.lock().unwrap().queued, [1, 2]);
+        assert_eq!(buffers.queued(), 2);
+    }
+
+    #[test]
+    fn multi_planar() {
+        let driver = FakeDriver { planes: 2, ..Default::default() };
+        let buffers = Buffers::new(Box::new(driver), 2).unwrap();
+        buffers.start().unwrap();
+        let lease = buffers.next().unwrap();
+        assert_eq!(lease.planes(), 2);
+        assert_eq!((lease.data(0), lease.data(1)), (&[0; 8][..], &[1; 8][..]));
+        assert!(lease.dmabuf(1).is_some());
+    }
+}
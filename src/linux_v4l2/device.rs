@@ -0,0 +1,437 @@
+//! The device operations of the backend, on V4L2 nodes or on a scripted fake in tests.
+
+use std::io;
+use std::path::{Path, PathBuf};
+
+use v4l::buffer::Type;
+use v4l::capability::Flags;
+use v4l::frameinterval::FrameIntervalEnum;
+use v4l::v4l_sys::*;
+use v4l::video::Capture;
+use v4l::{v4l2, Device, FourCC};
+
+use super::buffers::{DeviceDriver, Driver};
+use super::format::{self, DeviceFormat, FrameSizes};
+use super::{ioctl, DeviceInfo};
+
+/// Lists and opens the device nodes, faked in tests.
+pub(crate) trait Devices: Send + Sync {
+    /// Every video4linux node, capture or not.
+    fn paths(&self) -> Vec<PathBuf>;
+    fn open(&self, path: &Path) -> io::Result<Box<dyn Node>>;
+}
+
+/// The ioctls of an open device node.
+pub(crate) trait Node: Send + Sync {
+    fn info(&self) -> DeviceInfo;
+    /// What this node of the device can do, without the other nodes of the same device.
+    fn caps(&self) -> io::Result<Flags>;
+    fn format(&self, buf_type: Type) -> io::Result<DeviceFormat>;
+    /// Asks for `fourcc` and `size`, the driver picks what comes closest.
+    fn set_format(
+        &self,
+        buf_type: Type,
+        fourcc: [u8; 4],
+        size: (u32, u32),
+    ) -> io::Result<DeviceFormat>;
+    /// Asks for a data format of a metadata node, returns the one the driver picked.
+    fn set_meta_format(&self, fourcc: [u8; 4]) -> io::Result<[u8; 4]>;
+    fn formats(&self, buf_type: Type) -> Vec<[u8; 4]>;
+    fn frame_sizes(&self, fourcc: [u8; 4]) -> FrameSizes;
+    fn frame_intervals(&self, fourcc: [u8; 4], size: (u32, u32)) -> Vec<FrameIntervalEnum>;
+    /// Seconds per frame, `None` when the driver can't change them.
+    fn frame_interval(&self, buf_type: Type) -> io::Result<Option<(u32, u32)>>;
+    /// Asks for seconds per frame, returns what the driver applied.
+    fn set_frame_interval(&self, buf_type: Type, interval: (u32, u32)) -> io::Result<(u32, u32)>;
+    /// The buffer ioctls for streaming through mapped buffers.
+    fn driver(&self, buf_type: Type, planes: usize) -> Box<dyn Driver>;
+    fn user_ptr(&self, buf_type: Type, count: u32) -> io::Result<v4l::io::userptr::Stream>;
+    /// Waits for a frame and reads it into `buf`.
+    fn read(&self, buf: &mut [u8]) -> io::Result<usize>;
+}
+
+/// The nodes in `/dev`.
+pub(crate) struct V4l2Devices;
+
+impl Devices for V4l2Devices {
+    fn paths(&self) -> Vec<PathBuf> {
+        let nodes = v4l::context::enum_devices().into_iter();
+        nodes.map(|node| node.path().to_path_buf()).collect()
+    }
+
+    fn open(&self, path: &Path) -> io::Result<Box<dyn Node>> {
+        let device = Device::with_path(path)?;
+        Ok(Box::new(V4l2Node { device, path: path.to_path_buf() }))
+    }
+}
+
+struct V4l2Node {
+    device: Device,
+    path: PathBuf,
+}
+
+impl Node for V4l2Node {
+    fn info(&self) -> DeviceInfo {
+        DeviceInfo::new(&v4l::context::Node::new(&self.path), &self.device)
+    }
+
+    fn caps(&self) -> io::Result<Flags> {
+        format::device_caps(&self.device)
+    }
+
+    fn format(&self, buf_type: Type) -> io::Result<DeviceFormat> {
+        DeviceFormat::get(&self.device, buf_type)
+    }
+
+    fn set_format(
+        &self,
+        buf_type: Type,
+        fourcc: [u8; 4],
+        size: (u32, u32),
+    ) -> io::Result<DeviceFormat> {
+        DeviceFormat::set(&self.device, buf_type, fourcc, size)
+    }
+
+    fn set_meta_format(&self, fourcc: [u8; 4]) -> io::Result<[u8; 4]> {
+        let mut format =
+            v4l2_format { type_: Type::MetaCapture as u32, ..unsafe { std::mem::zeroed() } };
+        // v4l2_meta_format is the data format followed by the buffer size
+        unsafe { format.fmt.raw_data[..4].copy_from_slice(&fourcc) };
+        ioctl(self.device.handle().fd(), v4l2::vidioc::VIDIOC_S_FMT, &mut format)?;
+        Ok(unsafe { format.fmt.raw_data[..4].try_into().unwrap() })
+    }
+
+    fn formats(&self, buf_type: Type) -> Vec<[u8; 4]> {
+        format::formats(&self.device, buf_type)
+    }
+
+    fn frame_sizes(&self, fourcc: [u8; 4]) -> FrameSizes {
+        format::frame_sizes(&self.device, fourcc)
+    }
+
+    fn frame_intervals(&self, fourcc: [u8; 4], (w, h): (u32, u32)) -> Vec<FrameIntervalEnum> {
+        let intervals = self.device.enum_frameintervals(FourCC::new(&fourcc), w, h);
+        intervals.unwrap_or_default().into_iter().map(|interval| interval.interval).collect()
+    }
+
+    fn frame_interval(&self, buf_type: Type) -> io::Result<Option<(u32, u32)>> {
+        format::frame_interval(&self.device, buf_type)
+    }
+
+    fn set_frame_interval(&self, buf_type: Type, interval: (u32, u32)) -> io::Result<(u32, u32)> {
+        format::set_frame_interval(&self.device, buf_type, interval)
+    }
+
+    fn driver(&self, buf_type: Type, planes: usize) -> Box<dyn Driver> {
+        Box::new(DeviceDriver::new(self.device.handle(), buf_type, planes))
+    }
+
+    fn user_ptr(&self, buf_type: Type, count: u32) -> io::Result<v4l::io::userptr::Stream> {
+        v4l::io::userptr::Stream::with_buffers(&self.device, buf_type, count)
+    }
+
+    fn read(&self, buf: &mut [u8]) -> io::Result<usize> {
+        // the device is nonblocking, then every read() returns one whole frame
+        let handle = self.device.handle();
+        handle.poll(libc::POLLIN, -1)?;
+        let len = unsafe { libc::read(handle.fd(), buf.as_mut_ptr().cast(), buf.len()) };
+        if len < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(len as usize)
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod fake {
+    //! Cameras on any machine for the tests of the backend: formats and sizes to offer,
+    //! generated frames and a script of errors.
+
+    use std::collections::VecDeque;
+    use std::sync::{Arc, Mutex};
+
+    use super::*;
+    use crate::linux_v4l2::buffers::tests::{FakeDriver, FakeState};
+    use crate::linux_v4l2::buffers::{Dequeued, Memory};
+    use crate::PixelFormat;
+
+    /// What a [FakeCamera] can be scripted to fail.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub(crate) enum Op {
+        Open,
+        SetFormat,
+        SetInterval,
+        Request,
+        StreamOn,
+        Dequeue,
+        Read,
+    }
+
+    /// The nodes of the fake, in the order they are listed.
+    #[derive(Clone, Default)]
+    pub(crate) struct FakeDevices {
+        pub cameras: Arc<Mutex<Vec<FakeCamera>>>,
+    }
+
+    /// A device node of the fake, shared with the nodes opened from it.
+    #[derive(Clone)]
+    pub(crate) struct FakeCamera {
+        pub info: DeviceInfo,
+        pub caps: Flags,
+        pub modes: Vec<([u8; 4], FrameSizes)>,
+        /// Seconds per frame for every mode, the driver can't change them without any.
+        pub intervals: Vec<(u32, u32)>,
+        pub state: Arc<Mutex<FakeCameraState>>,
+    }
+
+    #[derive(Debug, Default)]
+    pub(crate) struct FakeCameraState {
+        pub format: ([u8; 4], (u32, u32)),
+        pub interval: (u32, u32),
+        /// Errors the next calls of an operation return as errno, in order.
+        pub errors: Vec<(Op, i32)>,
+        /// Bytes of the next frames, cut off like after an USB error.
+        pub short: VecDeque<usize>,
+        /// Every operation fails with `ENODEV`.
+        pub unplugged: bool,
+        pub buffers: Arc<Mutex<FakeState>>,
+    }
+
+    impl FakeDevices {
+        pub fn new(cameras: Vec<FakeCamera>) -> Arc<Self> {
+            Arc::new(Self { cameras: Arc::new(Mutex::new(cameras)) })
+        }
+
+        /// Removes the node, the nodes opened from it fail with `ENODEV`.
+        pub fn unplug(&self, path: &str) {
+            let mut cameras = self.cameras.lock().unwrap();
+            for camera in cameras.iter().filter(|camera| camera.info.path == Path::new(path)) {
+                camera.state.lock().unwrap().unplugged = true;
+            }
+            cameras.retain(|camera| camera.info.path != Path::new(path));
+        }
+    }
+
+    impl FakeCamera {
+        /// A video node at `/dev/video{number}` with streaming and the first of `modes`.
+        pub fn new(number: u32, name: &str, modes: Vec<([u8; 4], FrameSizes)>) -> Self {
+            let fourcc = modes.first().map_or(*b"YUYV", |mode| mode.0);
+            let format = (fourcc, (1280, 720));
+            let state = FakeCameraState { format, interval: (1, 30), ..Default::default() };
+            Self {
+                info: DeviceInfo {
+                    name: name.into(),
+                    path: format!("/dev/video{number}").into(),
+                    bus_info: format!("usb-0000:00:14.0-{number}"),
+                    serial: None,
+                    by_id: None,
+                    by_path: None,
+                    metadata: None,
+                },
+                caps: Flags::VIDEO_CAPTURE | Flags::STREAMING,
+                modes,
+                intervals: vec![(1, 30), (1, 15)],
+                state: Arc::new(Mutex::new(state)),
+            }
+        }
+
+        /// The metadata node uvcvideo creates after the video node.
+        pub fn metadata(&self) -> Self {
+            let mut metadata = Self::new(0, &self.info.name, Vec::new());
+            let number = self.info.path.to_string_lossy().trim_start_matches("/dev/video").parse();
+            metadata.info.path = format!("/dev/video{}", number.unwrap_or(0) + 1).into();
+            metadata.info.bus_info = self.info.bus_info.clone();
+            metadata.caps = Flags::META_CAPTURE | Flags::STREAMING;
+            metadata
+        }
+
+        pub fn fail(&self, op: Op, errno: i32) {
+            self.state.lock().unwrap().errors.push((op, errno));
+        }
+
+        fn check(&self, op: Op) -> io::Result<()> {
+            let mut state = self.state.lock().unwrap();
+            if state.unplugged {
+                return Err(io::Error::from_raw_os_error(libc::ENODEV));
+            }
+            match state.errors.iter().position(|&(failing, _)| failing == op) {
+                Some(pos) => Err(io::Error::from_raw_os_error(state.errors.remove(pos).1)),
+                None => Ok(()),
+            }
+        }
+
+        fn device_format(&self) -> DeviceFormat {
+            let (fourcc, size) = self.state.lock().unwrap().format;
+            let format = PixelFormat::from_fourcc(fourcc);
+            let stride = format.map_or(0, |format| size.0 as usize * format.bytes_per_pixel());
+            let len = format
+                .map_or(size.0 as usize * size.1 as usize, |format| format.frame_len(size, stride));
+            DeviceFormat {
+                buf_type: Type::VideoCapture,
+                fourcc,
+                size,
+                planes: vec![(stride, len)],
+                colorimetry: Default::default(),
+            }
+        }
+
+        /// A frame of the current format, mid gray for the uncompressed ones.
+        fn frame(&self) -> Vec<u8> {
+            vec![0x80; self.device_format().planes[0].1]
+        }
+
+        /// Bytes of the next frame.
+        fn next_len(&self) -> usize {
+            let short = self.state.lock().unwrap().short.pop_front();
+            short.unwrap_or(self.device_format().planes[0].1)
+        }
+    }
+
+    impl Devices for FakeDevices {
+        fn paths(&self) -> Vec<PathBuf> {
+            let cameras = self.cameras.lock().unwrap();
+            cameras.iter().map(|camera| camera.info.path.clone()).collect()
+        }
+
+        fn open(&self, path: &Path) -> io::Result<Box<dyn Node>> {
+            let cameras = self.cameras.lock().unwrap();
+            let camera = cameras.iter().find(|camera| camera.info.path == path);
+            let camera = camera.ok_or(io::Error::from_raw_os_error(libc::ENOENT))?;
+            camera.check(Op::Open)?;
+            Ok(Box::new(camera.clone()))
+        }
+    }
+
+    impl Node for FakeCamera {
+        fn info(&self) -> DeviceInfo {
+            self.info.clone()
+        }
+
+        fn caps(&self) -> io::Result<Flags> {
+            Ok(self.caps)
+        }
+
+        fn format(&self, _buf_type: Type) -> io::Result<DeviceFormat> {
+            Ok(self.device_format())
+        }
+
+        fn set_format(
+            &self,
+            _buf_type: Type,
+            fourcc: [u8; 4],
+            size: (u32, u32),
+        ) -> io::Result<DeviceFormat> {
+            self.check(Op::SetFormat)?;
+            let mode = self.modes.iter().find(|mode| mode.0 == fourcc).or(self.modes.first());
+            if let Some((fourcc, sizes)) = mode {
+                let size = sizes.closest(size).unwrap_or(size);
+                self.state.lock().unwrap().format = (*fourcc, size);
+            }
+            Ok(self.device_format())
+        }
+
+        fn set_meta_format(&self, fourcc: [u8; 4]) -> io::Result<[u8; 4]> {
+            match self.caps.contains(Flags::META_CAPTURE) {
+                true => Ok(fourcc),
+                false => Err(io::Error::from_raw_os_error(libc::EINVAL)),
+            }
+        }
+
+        fn formats(&self, _buf_type: Type) -> Vec<[u8; 4]> {
+            self.modes.iter().map(|mode| mode.0).collect()
+        }
+
+        fn frame_sizes(&self, fourcc: [u8; 4]) -> FrameSizes {
+            let mode = self.modes.iter().find(|mode| mode.0 == fourcc);
+            mode.map_or(FrameSizes::Discrete(Vec::new()), |mode| mode.1.clone())
+        }
+
+        fn frame_intervals(&self, _fourcc: [u8; 4], _size: (u32, u32)) -> Vec<FrameIntervalEnum> {
+            let intervals = self.intervals.iter();
+            intervals
+                .map(|&(num, den)| FrameIntervalEnum::Discrete(v4l::Fraction::new(num, den)))
+                .collect()
+        }
+
+        fn frame_interval(&self, _buf_type: Type) -> io::Result<Option<(u32, u32)>> {
+            let interval = self.state.lock().unwrap().interval;
+            Ok((!self.intervals.is_empty()).then_some(interval))
+        }
+
+        fn set_frame_interval(
+            &self,
+            _buf_type: Type,
+            interval: (u32, u32),
+        ) -> io::Result<(u32, u32)> {
+            self.check(Op::SetInterval)?;
+            self.state.lock().unwrap().interval = interval;
+            Ok(interval)
+        }
+
+        fn driver(&self, _buf_type: Type, _planes: usize) -> Box<dyn Driver> {
+            let state = self.state.lock().unwrap().buffers.clone();
+            Box::new(FakeCameraDriver {
+                camera: self.clone(),
+                buffers: FakeDriver { state, planes: 0 },
+            })
+        }
+
+        fn user_ptr(&self, _buf_type: Type, _count: u32) -> io::Result<v4l::io::userptr::Stream> {
+            Err(io::ErrorKind::Unsupported.into())
+        }
+
+        fn read(&self, buf: &mut [u8]) -> io::Result<usize> {
+            self.check(Op::Read)?;
+            let frame = self.frame();
+            let len = self.next_len().min(frame.len()).min(buf.len());
+            buf[..len].copy_from_slice(&frame[..len]);
+            Ok(len)
+        }
+    }
+
+    /// The buffers of a [FakeCamera], filled with generated frames.
+    struct FakeCameraDriver {
+        camera: FakeCamera,
+        buffers: FakeDriver,
+    }
+
+    impl Driver for FakeCameraDriver {
+        fn request(&self, count: u32) -> io::Result<u32> {
+            if count > 0 {
+                self.camera.check(Op::Request)?;
+            }
+            self.buffers.request(count)
+        }
+
+        fn map(&self, _index: u32) -> io::Result<Vec<Box<dyn Memory>>> {
+            Ok(vec![Box::new(self.camera.frame())])
+        }
+
+        fn export(&self, index: u32, plane: usize) -> io::Result<std::os::fd::OwnedFd> {
+            self.buffers.export(index, plane)
+        }
+
+        fn queue(&self, index: u32) -> io::Result<()> {
+            self.buffers.queue(index)
+        }
+
+        fn wait(&self) -> io::Result<()> {
+            self.buffers.wait()
+        }
+
+        fn dequeue(&self) -> io::Result<Dequeued> {
+            self.camera.check(Op::Dequeue)?;
+            let dequeued = self.buffers.dequeue()?;
+            Ok(Dequeued { planes: vec![(0, self.camera.next_len() as u32)], ..dequeued })
+        }
+
+        fn stream_on(&self) -> io::Result<()> {
+            self.camera.check(Op::StreamOn)?;
+            self.buffers.stream_on()
+        }
+
+        fn stream_off(&self) -> io::Result<()> {
+            self.buffers.stream_off()
+        }
+    }
+}
@@ -0,0 +1,204 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use v4l::context::Node;
+use v4l::Device;
+
+const BY_ID: &str = "/dev/v4l/by-id";
+const BY_PATH: &str = "/dev/v4l/by-path";
+
+/// What identifies a capture device, see [Camera::devices](crate::Camera::devices).
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct DeviceInfo {
+    /// Name the driver reports, or the path without one.
+    pub name: String,
+    /// The device node like `/dev/video0`, numbered in the order devices show up.
+    pub path: PathBuf,
+    /// Where the device is attached as the driver reports it, like `usb-0000:00:14.0-2`.
+    pub bus_info: String,
+    /// Serial number of an USB device that has one.
+    pub serial: Option<String>,
+    /// The udev link in `/dev/v4l/by-id`, named after vendor, model and serial.
+    pub by_id: Option<PathBuf>,
+    /// The udev link in `/dev/v4l/by-path`, named after the port the device is plugged into.
+    pub by_path: Option<PathBuf>,
+    /// The metadata node of the same camera, with the UVC payload headers of the frames, see
+    /// [Camera::set_uvc_metadata](crate::Camera::set_uvc_metadata).
+    pub metadata: Option<PathBuf>,
+}
+
+impl DeviceInfo {
+    pub(crate) fn new(node: &Node, device: &Device) -> Self {
+        let path = node.path().to_path_buf();
+        let sysfs = Path::new("/sys/class/video4linux").join(path.file_name().unwrap_or_default());
+        // the video node hangs off an interface of the USB device that has the serial
+        let serial = fs::read_to_string(sysfs.join("device/../serial"))
+            .ok()
+            .map(|serial| serial.trim().to_string())
+            .filter(|serial| !serial.is_empty());
+        Self {
+            name: node.name().unwrap_or_else(|| path.to_string_lossy().to_string()),
+            bus_info: device.query_caps().map(|caps| caps.bus).unwrap_or_default(),
+            serial,
+            by_id: link_to(Path::new(BY_ID), &path),
+            by_path: link_to(Path::new(BY_PATH), &path),
+            metadata: None,
+            path,
+        }
+    }
+
+    /// An identifier that stays the same across reboots and replugging, to keep in
+    /// configuration and open with [Camera::open_by_id](crate::Camera::open_by_id).
+    ///
+    /// It is the by-id name for a device with a serial number, which follows the device to
+    /// any port. Identical devices without one are told apart by the port in the by-path
+    /// name, then by the bus info, and the device node is the last resort.
+    pub fn stable_id(&self) -> String {
+        let by_id = self.by_id.as_deref().and_then(file_name);
+        let by_path = self.by_path.as_deref().and_then(file_name);
+        match (by_id, by_path) {
+            (Some(by_id), _) if self.serial.is_some() => by_id,
+            (_, Some(by_path)) => by_path,
+            (Some(by_id), None) => by_id,
+            (None, None) if !self.bus_info.is_empty() => self.bus_info.clone(),
+            (None, None) => self.path.to_string_lossy().to_string(),
+        }
+    }
+
+    /// Whether `id` names this device, as its stable id, a by-id or by-path name or link,
+    /// or the device node.
+    pub fn matches(&self, id: &str) -> bool {
+        let id = Path::new(id);
+        id == Path::new(&self.stable_id())
+            || id == self.path
+            || [&self.by_id, &self.by_path]
+                .into_iter()
+                .flatten()
+                .any(|link| id == link.as_path() || Some(id.as_os_str()) == link.file_name())
+    }
+}
+
+/// Pairs each video node with the metadata node of the same camera, uvcvideo creates it
+/// right after the video node. Cameras with a color and an infrared sensor have two of each.
+pub(crate) fn pair_metadata(video: &mut [DeviceInfo], metadata: &[DeviceInfo]) {
+    let mut unpaired: Vec<_> = metadata.iter().collect();
+    unpaired.sort_by_key(|meta| node_number(&meta.path));
+    let mut video: Vec<_> = video.iter_mut().collect();
+    video.sort_by_key(|info| node_number(&info.path));
+    for info in video {
+        let pos = unpaired.iter().position(|meta| {
+            meta.bus_info == info.bus_info
+                && meta.name == info.name
+                && node_number(&meta.path) > node_number(&info.path)
+        });
+        info.metadata = pos.map(|pos| unpaired.remove(pos).path.clone());
+    }
+}
+
+/// The N of `/dev/videoN`.
+fn node_number(path: &Path) -> Option<u32> {
+    path.file_name()?.to_str()?.strip_prefix("video")?.parse().ok()
+}
+
+fn file_name(path: &Path) -> Option<String> {
+    Some(path.file_name()?.to_string_lossy().to_string())
+}
+
+/// The link in `dir` pointing at the device node `path`.
+fn link_to(dir: &Path, path: &Path) -> Option<PathBuf> {
+    let target = fs::canonicalize(path).ok()?;
+    let mut links: Vec<_> = fs::read_dir(dir)
+        .ok()?
+        .flatten()
+        .map(|entry| entry.path())
+        .filter(|link| fs::canonicalize(link).is_ok_and(|resolved| resolved == target))
+        .collect();
+    // newer udev adds a second by-path name, the shorter one is there on older versions too
+    links.sort_by_key(|link| link.as_os_str().len());
+    links.into_iter().next()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn info() -> DeviceInfo {
+        DeviceInfo {
+            name: "HD Webcam".into(),
+            path: "/dev/video2".into(),
+            bus_info: "usb-0000:00:14.0-2".into(),
+            serial: None,
+            by_id: Some("/dev/v4l/by-id/usb-Vendor_HD_Webcam-video-index0".into()),
+            by_path: Some("/dev/v4l/by-path/pci-0000:00:14.0-usb-0:2:1.0-video-index0".into()),
+            metadata: Some("/dev/video3".into()),
+        }
+    }
+
+    #[test]
+    fn stable_id_prefers_serial_then_port() {
+        let mut info = info();
+        assert_eq!(info.stable_id(), "pci-0000:00:14.0-usb-0:2:1.0-video-index0");
+        info.serial = Some("A1B2".into());
+        info.by_id = Some("/dev/v4l/by-id/usb-Vendor_HD_Webcam_A1B2-video-index0".into());
+        assert_eq!(info.stable_id(), "usb-Vendor_HD_Webcam_A1B2-video-index0");
+        info.by_id = None;
+        info.by_path = None;
+        assert_eq!(info.stable_id(), "usb-0000:00:14.0-2");
+        info.bus_info.clear();
+        assert_eq!(info.stable_id(), "/dev/video2");
+    }
+
+    #[test]
+    fn matches_links_and_node() {
+        let info = info();
+        assert!(info.matches(&info.stable_id()));
+        assert!(info.matches("usb-Vendor_HD_Webcam-video-index0"));
+        assert!(info.matches("/dev/v4l/by-id/usb-Vendor_HD_Webcam-video-index0"));
+        assert!(info.matches("/dev/video2"));
+        assert!(!info.matches("/dev/video0"));
+        assert!(!info.matches("usb-Vendor_Other-video-index0"));
+    }
+
+    #[test]
+    fn pairs_metadata_nodes() {
+        let node = |path: &str, name: &str| DeviceInfo {
+            name: name.into(),
+            path: path.into(),
+            by_id: None,
+            by_path: None,
+            metadata: None,
+            ..info()
+        };
+        let mut video =
+            [node("/dev/video2", "Color"), node("/dev/video0", "Color"), node("/dev/video4", "IR")];
+        video[0].bus_info = "usb-0000:00:14.0-3".into();
+        let mut other = node("/dev/video3", "Color");
+        other.bus_info = video[0].bus_info.clone();
+        let metadata = [node("/dev/video5", "IR"), other, node("/dev/video1", "Color")];
+        pair_metadata(&mut video, &metadata);
+        let paired: Vec<_> = video.iter().map(|info| info.metadata.clone()).collect();
+        assert_eq!(
+            paired,
+            [Some("/dev/video3".into()), Some("/dev/video1".into()), Some("/dev/video5".into())]
+        );
+        let mut alone = [node("/dev/video6", "Other")];
+        pair_metadata(&mut alone, &metadata);
+        assert_eq!(alone[0].metadata, None);
+    }
+
+    #[test]
+    fn finds_link_to_node() {
+        let dir = std::env::temp_dir().join(format!("kamera_by_id_{}", std::process::id()));
+        fs::create_dir_all(dir.join("links")).unwrap();
+        let node = dir.join("video0");
+        fs::write(&node, b"").unwrap();
+        fs::write(dir.join("video1"), b"").unwrap();
+        std::os::unix::fs::symlink(&node, dir.join("links/usb-Cam-video-index0")).unwrap();
+        std::os::unix::fs::symlink(dir.join("video1"), dir.join("links/usb-Other")).unwrap();
+        let link = link_to(&dir.join("links"), &node);
+        assert_eq!(link, Some(dir.join("links/usb-Cam-video-index0")));
+        assert_eq!(link_to(&dir.join("missing"), &node), None);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
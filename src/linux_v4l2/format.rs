@@ -0,0 +1,376 @@
+//! Formats of single- and multi-planar capture queues.
+
+use std::io;
+
+use v4l::buffer::Type;
+use v4l::capability::Flags;
+use v4l::frameinterval::FrameIntervalEnum;
+use v4l::framesize::FrameSizeEnum;
+use v4l::v4l_sys::*;
+use v4l::video::Capture;
+use v4l::{v4l2, Device, FourCC};
+
+use super::device::Node;
+use super::ioctl;
+use crate::{Colorimetry, PixelFormat};
+
+/// The frame sizes a device offers for a format, see [Mode].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FrameSizes {
+    Discrete(Vec<(u32, u32)>),
+    /// Every size from `min` to `max` in steps of `step`, continuous ranges step by 1.
+    Stepwise {
+        min: (u32, u32),
+        max: (u32, u32),
+        step: (u32, u32),
+    },
+}
+
+/// A format of a device with its frame sizes, see [Camera::modes](crate::Camera::modes).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Mode {
+    pub fourcc: [u8; 4],
+    /// `None` for formats kamera can't convert.
+    pub format: Option<PixelFormat>,
+    pub sizes: FrameSizes,
+}
+
+/// What to ask the device for with [Camera::set_mode](crate::Camera::set_mode). `None` keeps
+/// the current value.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct ModeConfig {
+    pub format: Option<PixelFormat>,
+    pub size: Option<(u32, u32)>,
+    /// Frames per second as numerator and denominator, like `(30000, 1001)`.
+    pub frame_rate: Option<(u32, u32)>,
+}
+
+impl FrameSizes {
+    /// The offered size closest to `size`, on the step grid of a stepwise range. `None` when
+    /// the device lists no sizes.
+    pub fn closest(&self, (w, h): (u32, u32)) -> Option<(u32, u32)> {
+        match self {
+            FrameSizes::Discrete(sizes) => {
+                sizes.iter().copied().min_by_key(|&(sw, sh)| sw.abs_diff(w) + sh.abs_diff(h))
+            }
+            FrameSizes::Stepwise { min, max, step } => {
+                Some((snap(w, min.0, max.0, step.0), snap(h, min.1, max.1, step.1)))
+            }
+        }
+    }
+}
+
+fn snap(value: u32, min: u32, max: u32, step: u32) -> u32 {
+    let step = step.max(1);
+    let last = min + (max.max(min) - min) / step * step;
+    let steps = (value.clamp(min, last) - min + step / 2) / step;
+    (min + steps * step).min(last)
+}
+
+/// The negotiated format of a capture queue.
+#[derive(Debug, Clone)]
+pub(crate) struct DeviceFormat {
+    pub buf_type: Type,
+    pub fourcc: [u8; 4],
+    pub size: (u32, u32),
+    /// Bytes per row and length of each memory plane, one unless the format is multi-planar.
+    pub planes: Vec<(usize, usize)>,
+    pub colorimetry: Colorimetry,
+}
+
+impl DeviceFormat {
+    pub fn get(device: &Device, buf_type: Type) -> io::Result<Self> {
+        let mut format = v4l2_format { type_: buf_type as u32, ..unsafe { std::mem::zeroed() } };
+        ioctl(device.handle().fd(), v4l2::vidioc::VIDIOC_G_FMT, &mut format)?;
+        Ok(Self::from_raw(buf_type, &format))
+    }
+
+    /// Asks for `fourcc` and `size`, the driver picks what comes closest.
+    pub fn set(
+        device: &Device,
+        buf_type: Type,
+        fourcc: [u8; 4],
+        size: (u32, u32),
+    ) -> io::Result<Self> {
+        let mut format = v4l2_format { type_: buf_type as u32, ..unsafe { std::mem::zeroed() } };
+        let fd = device.handle().fd();
+        ioctl(fd, v4l2::vidioc::VIDIOC_G_FMT, &mut format)?;
+        let pixelformat = u32::from_le_bytes(fourcc);
+        if is_multi_planar(buf_type) {
+            let pix = unsafe { &mut format.fmt.pix_mp };
+            (pix.width, pix.height, pix.pixelformat) = (size.0, size.1, pixelformat);
+            // the driver fills in the planes of the new format
+            pix.num_planes = 0;
+        } else {
+            let pix = unsafe { &mut format.fmt.pix };
+            (pix.width, pix.height, pix.pixelformat) = (size.0, size.1, pixelformat);
+            pix.bytesperline = 0;
+        }
+        ioctl(fd, v4l2::vidioc::VIDIOC_S_FMT, &mut format)?;
+        Ok(Self::from_raw(buf_type, &format))
+    }
+
+    fn from_raw(buf_type: Type, format: &v4l2_format) -> Self {
+        if is_multi_planar(buf_type) {
+            let pix = unsafe { format.fmt.pix_mp };
+            let plane_fmt = pix.plane_fmt;
+            let planes = plane_fmt[..(pix.num_planes as usize).min(plane_fmt.len())]
+                .iter()
+                .map(|plane| (plane.bytesperline as usize, plane.sizeimage as usize))
+                .collect();
+            Self {
+                buf_type,
+                fourcc: pix.pixelformat.to_le_bytes(),
+                size: (pix.width, pix.height),
+                planes,
+                colorimetry: Colorimetry {
+                    colorspace: pix.colorspace,
+                    transfer: pix.xfer_func as u32,
+                    quantization: pix.quantization as u32,
+                },
+            }
+        } else {
+            let pix = unsafe { format.fmt.pix };
+            Self {
+                buf_type,
+                fourcc: pix.pixelformat.to_le_bytes(),
+                size: (pix.width, pix.height),
+                planes: vec![(pix.bytesperline as usize, pix.sizeimage as usize)],
+                colorimetry: Colorimetry {
+                    colorspace: pix.colorspace,
+                    transfer: pix.xfer_func,
+                    quantization: pix.quantization,
+                },
+            }
+        }
+    }
+
+    pub fn is_multi_planar(&self) -> bool {
+        is_multi_planar(self.buf_type)
+    }
+
+    pub fn pixel_format(&self) -> Option<PixelFormat> {
+        pixel_format(self.fourcc)
+    }
+}
+
+/// Multi-planar fourccs like `NM12` map to their single-planar equivalent.
+fn pixel_format(fourcc: [u8; 4]) -> Option<PixelFormat> {
+    match &fourcc {
+        b"NM12" => Some(PixelFormat::Nv12),
+        b"YM12" => Some(PixelFormat::I420),
+        b"YM16" => Some(PixelFormat::I422),
+        _ => PixelFormat::from_fourcc(fourcc),
+    }
+}
+
+/// The formats and frame sizes of the capture queue.
+pub(crate) fn modes(node: &dyn Node, buf_type: Type) -> Vec<Mode> {
+    let modes = node.formats(buf_type).into_iter().map(|fourcc| Mode {
+        fourcc,
+        format: pixel_format(fourcc),
+        sizes: node.frame_sizes(fourcc),
+    });
+    modes.collect()
+}
+
+/// The formats `VIDIOC_ENUM_FMT` lists for the capture queue.
+pub(crate) fn formats(device: &Device, buf_type: Type) -> Vec<[u8; 4]> {
+    let fd = device.handle().fd();
+    let fourccs = (0..).map_while(|index| {
+        let mut desc =
+            v4l2_fmtdesc { index, type_: buf_type as u32, ..unsafe { std::mem::zeroed() } };
+        ioctl(fd, v4l2::vidioc::VIDIOC_ENUM_FMT, &mut desc).ok()?;
+        Some(desc.pixelformat.to_le_bytes())
+    });
+    fourccs.collect()
+}
+
+/// Drivers without a list of sizes take any size and pick the closest.
+pub(crate) fn frame_sizes(device: &Device, fourcc: [u8; 4]) -> FrameSizes {
+    let sizes = device.enum_framesizes(FourCC::new(&fourcc)).unwrap_or_default();
+    let mut discrete = Vec::new();
+    for size in sizes {
+        match size.size {
+            FrameSizeEnum::Discrete(size) => discrete.push((size.width, size.height)),
+            FrameSizeEnum::Stepwise(range) => {
+                return FrameSizes::Stepwise {
+                    min: (range.min_width, range.min_height),
+                    max: (range.max_width, range.max_height),
+                    step: (range.step_width, range.step_height),
+                };
+            }
+        }
+    }
+    FrameSizes::Discrete(discrete)
+}
+
+/// Frames per second of the capture queue, `None` when the driver doesn't say.
+pub(crate) fn frame_rate(node: &dyn Node, buf_type: Type) -> Option<(u32, u32)> {
+    let (num, den) = node.frame_interval(buf_type).ok()??;
+    (num > 0).then_some((den, num))
+}
+
+/// Asks for the frame rate closest to `rate` of those the device offers for `format`,
+/// returns the one the driver applied.
+pub(crate) fn set_frame_rate(
+    node: &dyn Node,
+    format: &DeviceFormat,
+    (num, den): (u32, u32),
+) -> io::Result<(u32, u32)> {
+    if num == 0 || den == 0 {
+        return Err(io::ErrorKind::InvalidInput.into());
+    }
+    let intervals = node.frame_intervals(format.fourcc, format.size);
+    let interval = closest_interval(intervals, (den, num)).unwrap_or((den, num));
+    if node.frame_interval(format.buf_type)?.is_none() {
+        return Err(io::ErrorKind::Unsupported.into());
+    }
+    let (num, den) = node.set_frame_interval(format.buf_type, interval)?;
+    Ok((den, num))
+}
+
+/// Seconds per frame from `VIDIOC_G_PARM`, `None` when the driver can't change them.
+pub(crate) fn frame_interval(device: &Device, buf_type: Type) -> io::Result<Option<(u32, u32)>> {
+    let mut parm = v4l2_streamparm { type_: buf_type as u32, ..unsafe { std::mem::zeroed() } };
+    ioctl(device.handle().fd(), v4l2::vidioc::VIDIOC_G_PARM, &mut parm)?;
+    let capture = unsafe { parm.parm.capture };
+    let interval = capture.timeperframe;
+    let settable = capture.capability & TIME_PER_FRAME != 0;
+    Ok(settable.then_some((interval.numerator, interval.denominator)))
+}
+
+/// Sets the seconds per frame with `VIDIOC_S_PARM`, returns what the driver applied.
+pub(crate) fn set_frame_interval(
+    device: &Device,
+    buf_type: Type,
+    (num, den): (u32, u32),
+) -> io::Result<(u32, u32)> {
+    let fd = device.handle().fd();
+    let mut parm = v4l2_streamparm { type_: buf_type as u32, ..unsafe { std::mem::zeroed() } };
+    ioctl(fd, v4l2::vidioc::VIDIOC_G_PARM, &mut parm)?;
+    parm.parm.capture.timeperframe = v4l2_fract { numerator: num, denominator: den };
+    ioctl(fd, v4l2::vidioc::VIDIOC_S_PARM, &mut parm)?;
+    let applied = unsafe { parm.parm.capture.timeperframe };
+    Ok((applied.numerator, applied.denominator))
+}
+
+const TIME_PER_FRAME: u32 = 0x1000;
+
+/// The offered frame interval closest to `interval`, on the step grid of a stepwise range.
+fn closest_interval(
+    intervals: impl IntoIterator<Item = FrameIntervalEnum>,
+    interval: (u32, u32),
+) -> Option<(u32, u32)> {
+    let seconds = |(num, den): (u64, u64)| num as f64 / den.max(1) as f64;
+    let exact = (interval.0 as u64, interval.1 as u64);
+    let wanted = seconds(exact);
+    let candidates = intervals.into_iter().map(|offered| match offered {
+        FrameIntervalEnum::Discrete(f) => (f.numerator as u64, f.denominator as u64),
+        FrameIntervalEnum::Stepwise(range) => {
+            let fraction = |f: v4l::Fraction| (f.numerator as u64, f.denominator as u64);
+            let (min, max, step) = (fraction(range.min), fraction(range.max), fraction(range.step));
+            let (min_s, max_s, step_s) = (seconds(min), seconds(max), seconds(step));
+            let wanted = wanted.clamp(min_s, max_s.max(min_s));
+            if step_s <= 0.0 {
+                // a continuous range without step
+                return if wanted <= min_s {
+                    min
+                } else if wanted >= max_s {
+                    max
+                } else {
+                    exact
+                };
+            }
+            let last = ((max_s - min_s) / step_s + 1e-9).floor().max(0.0);
+            let steps = ((wanted - min_s) / step_s).round().min(last) as u64;
+            // min + steps * step
+            (min.0 * step.1 + steps * step.0 * min.1, min.1 * step.1)
+        }
+    });
+    let closest = candidates
+        .min_by(|a, b| (seconds(*a) - wanted).abs().total_cmp(&(seconds(*b) - wanted).abs()))?;
+    let gcd = gcd(closest.0, closest.1).max(1);
+    Some(((closest.0 / gcd) as u32, (closest.1 / gcd) as u32))
+}
+
+fn gcd(a: u64, b: u64) -> u64 {
+    if b == 0 {
+        a
+    } else {
+        gcd(b, a % b)
+    }
+}
+
+fn is_multi_planar(buf_type: Type) -> bool {
+    matches!(buf_type, Type::VideoCaptureMplane)
+}
+
+/// What this node of the device can do, without the other nodes of the same device.
+pub(crate) fn device_caps(device: &Device) -> io::Result<Flags> {
+    let mut caps: v4l2_capability = unsafe { std::mem::zeroed() };
+    ioctl(device.handle().fd(), v4l2::vidioc::VIDIOC_QUERYCAP, &mut caps)?;
+    let all = Flags::from(caps.capabilities);
+    Ok(if all.contains(Flags::DEVICE_CAPS) { Flags::from(caps.device_caps) } else { all })
+}
+
+/// The capture queue of the device, multi-planar only for devices without the other one.
+pub(crate) fn buffer_type(node: &dyn Node) -> io::Result<Type> {
+    let caps = node.caps()?;
+    if caps.contains(Flags::VIDEO_CAPTURE) {
+        Ok(Type::VideoCapture)
+    } else if caps.contains(Flags::VIDEO_CAPTURE_MPLANE) {
+        Ok(Type::VideoCaptureMplane)
+    } else {
+        Err(io::ErrorKind::Unsupported.into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn snaps_to_step_grid() {
+        let range = FrameSizes::Stepwise { min: (16, 16), max: (1920, 1080), step: (16, 8) };
+        assert_eq!(range.closest((1280, 722)), Some((1280, 720)));
+        assert_eq!(range.closest((1287, 725)), Some((1280, 728)));
+        assert_eq!(range.closest((4000, 1)), Some((1920, 16)));
+        // 1079 - 16 is no multiple of 8, the largest height on the grid is 1072
+        let range = FrameSizes::Stepwise { min: (16, 16), max: (1920, 1079), step: (16, 8) };
+        assert_eq!(range.closest((640, 2000)), Some((640, 1072)));
+        let continuous = FrameSizes::Stepwise { min: (1, 1), max: (4096, 4096), step: (1, 1) };
+        assert_eq!(continuous.closest((333, 777)), Some((333, 777)));
+    }
+
+    #[test]
+    fn closest_frame_interval() {
+        let discrete = [(1, 30), (1, 15), (1, 5)]
+            .map(|(num, den)| FrameIntervalEnum::Discrete(v4l::Fraction::new(num, den)));
+        assert_eq!(closest_interval(discrete, (1, 25)), Some((1, 30)));
+        assert_eq!(closest_interval([], (1, 25)), None);
+
+        let range = |min: (u32, u32), max: (u32, u32), step: (u32, u32)| {
+            FrameIntervalEnum::Stepwise(v4l::frameinterval::Stepwise {
+                min: v4l::Fraction::new(min.0, min.1),
+                max: v4l::Fraction::new(max.0, max.1),
+                step: v4l::Fraction::new(step.0, step.1),
+            })
+        };
+        // 1/60 to 1 second in steps of 1/60
+        assert_eq!(closest_interval([range((1, 60), (1, 1), (1, 60))], (1, 25)), Some((1, 30)));
+        assert_eq!(closest_interval([range((1, 60), (1, 1), (1, 60))], (1, 120)), Some((1, 60)));
+        assert_eq!(closest_interval([range((1, 60), (1, 1), (1, 60))], (2, 1)), Some((1, 1)));
+        assert_eq!(
+            closest_interval([range((1, 60), (1, 1), (0, 1))], (1001, 30000)),
+            Some((1001, 30000))
+        );
+    }
+
+    #[test]
+    fn closest_discrete_size() {
+        let sizes = FrameSizes::Discrete(vec![(640, 480), (1280, 720), (1920, 1080)]);
+        assert_eq!(sizes.closest((1300, 700)), Some((1280, 720)));
+        assert_eq!(FrameSizes::Discrete(Vec::new()).closest((640, 480)), None);
+    }
+}
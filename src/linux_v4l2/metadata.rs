@@ -0,0 +1,178 @@
+//! UVC payload headers from the metadata node that uvcvideo creates next to the video node.
+
+use std::collections::VecDeque;
+use std::io;
+use std::sync::Arc;
+use std::time::Duration;
+
+use v4l::buffer::Type;
+
+use super::buffers::Buffers;
+use super::device::Node;
+
+const UVC_META: [u8; 4] = *b"UVCH";
+const HAS_PTS: u8 = 0x04;
+const HAS_SCR: u8 = 0x08;
+
+/// The UVC payload header of a frame with when the host received it, see
+/// [Frame::uvc_metadata](crate::Frame::uvc_metadata). Relating PTS and SCR to the host time
+/// gives when the sensor exposed the frame.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct UvcMetadata {
+    /// `CLOCK_MONOTONIC` when the payload arrived, like [Frame::timestamp](crate::Frame::timestamp).
+    pub host_time: Duration,
+    /// The USB frame number when the payload arrived.
+    pub host_sof: u16,
+    /// The header info bits: frame id, end of frame, still image and error.
+    pub flags: u8,
+    /// Presentation time stamp in ticks of the device clock.
+    pub pts: Option<u32>,
+    /// Source clock reference, the device clock and its 11 bit USB frame number when the
+    /// payload was sent.
+    pub scr: Option<(u32, u16)>,
+}
+
+/// Parses a metadata buffer, blocks of `struct uvc_meta_buf` for each payload with a new
+/// header.
+pub(crate) fn parse(mut data: &[u8]) -> Vec<UvcMetadata> {
+    let mut headers = Vec::new();
+    // u64 ns, u16 sof, then the header starting with its length and the info bits
+    while data.len() >= 12 {
+        let length = data[10] as usize;
+        if length < 2 || data.len() < length + 10 {
+            break;
+        }
+        let flags = data[11];
+        let fields = &data[12..length + 10];
+        let pts_len = if flags & HAS_PTS != 0 { 4 } else { 0 };
+        let u32_at = |at: usize| u32::from_le_bytes(fields[at..at + 4].try_into().unwrap());
+        headers.push(UvcMetadata {
+            host_time: Duration::from_nanos(u64::from_le_bytes(data[..8].try_into().unwrap())),
+            host_sof: u16::from_le_bytes([data[8], data[9]]),
+            flags,
+            pts: (pts_len == 4 && fields.len() >= 4).then(|| u32_at(0)),
+            scr: (flags & HAS_SCR != 0 && fields.len() >= pts_len + 6).then(|| {
+                let sof = u16::from_le_bytes([fields[pts_len + 4], fields[pts_len + 5]]);
+                (u32_at(pts_len), sof & 0x7ff)
+            }),
+        });
+        data = &data[length + 10..];
+    }
+    headers
+}
+
+/// The metadata of a video stream, matched to its frames by sequence number.
+pub(crate) struct MetadataStream {
+    buffers: Arc<Buffers>,
+    pending: VecDeque<(u32, Vec<UvcMetadata>)>,
+    _node: Box<dyn Node>,
+}
+
+impl MetadataStream {
+    pub fn open(node: Box<dyn Node>, count: u32) -> io::Result<Self> {
+        if node.set_meta_format(UVC_META)? != UVC_META {
+            return Err(io::ErrorKind::Unsupported.into());
+        }
+        let buffers = Buffers::new(node.driver(Type::MetaCapture, 1), count)?;
+        buffers.start()?;
+        Ok(Self { buffers, pending: VecDeque::new(), _node: node })
+    }
+
+    /// The payload headers of the frame with `sequence`, empty when they are lost.
+    pub fn take(&mut self, sequence: u32) -> Vec<UvcMetadata> {
+        // uvcvideo completes the metadata buffer together with the one of the frame
+        while let Ok(lease) = self.buffers.try_next() {
+            self.pending.push_back((lease.sequence(), parse(lease.data(0))));
+        }
+        take_matching(&mut self.pending, sequence)
+    }
+}
+
+/// Drops the entries before `sequence` and takes the one of it, later ones stay for later
+/// frames.
+fn take_matching(
+    pending: &mut VecDeque<(u32, Vec<UvcMetadata>)>,
+    sequence: u32,
+) -> Vec<UvcMetadata> {
+    while let Some(&(next, _)) = pending.front() {
+        // wrapping, the counter runs for the whole stream
+        if (next.wrapping_sub(sequence) as i32) < 0 {
+            pending.pop_front();
+        } else {
+            break;
+        }
+    }
+    match pending.front() {
+        Some((next, _)) if *next == sequence => pending.pop_front().unwrap().1,
+        _ => Vec::new(),
+    }
+}
+
+impl std::fmt::Debug for MetadataStream {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("MetadataStream").field("pending", &self.pending.len()).finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn block(ns: u64, sof: u16, flags: u8, fields: &[u8]) -> Vec<u8> {
+        let mut block = ns.to_le_bytes().to_vec();
+        block.extend(sof.to_le_bytes());
+        block.extend([fields.len() as u8 + 2, flags]);
+        block.extend(fields);
+        block
+    }
+
+    #[test]
+    fn parses_payload_headers() {
+        let mut data = block(
+            1_000,
+            7,
+            0x80 | HAS_PTS | HAS_SCR,
+            &[
+                1, 0, 0, 0, // pts
+                2, 0, 0, 0, 0x01, 0xf8, // scr, the top bits of the sof are reserved
+            ],
+        );
+        data.extend(block(2_000, 8, 0x81, &[]));
+        data.extend(block(3_000, 9, HAS_SCR, &[3, 0, 0, 0, 4, 0]));
+        // a cut off block
+        data.extend(&block(4_000, 10, 0, &[])[..6]);
+        let headers = parse(&data);
+        assert_eq!(headers.len(), 3);
+        assert_eq!(
+            headers[0],
+            UvcMetadata {
+                host_time: Duration::from_nanos(1_000),
+                host_sof: 7,
+                flags: 0x8c,
+                pts: Some(1),
+                scr: Some((2, 1)),
+            }
+        );
+        assert_eq!((headers[1].pts, headers[1].scr, headers[1].flags), (None, None, 0x81));
+        assert_eq!((headers[2].pts, headers[2].scr), (None, Some((3, 4))));
+    }
+
+    #[test]
+    fn matches_frames_by_sequence() {
+        let header = |sof| UvcMetadata {
+            host_time: Duration::ZERO,
+            host_sof: sof,
+            flags: 0,
+            pts: None,
+            scr: None,
+        };
+        let mut pending: VecDeque<_> =
+            [(u32::MAX, vec![header(0)]), (0, vec![header(1)]), (2, vec![header(2)])].into();
+        assert_eq!(take_matching(&mut pending, 0), vec![header(1)]);
+        // the metadata of a frame got lost
+        assert_eq!(take_matching(&mut pending, 1), vec![]);
+        assert_eq!(pending.len(), 1);
+        assert_eq!(take_matching(&mut pending, 3), vec![]);
+        assert!(pending.is_empty());
+    }
+}
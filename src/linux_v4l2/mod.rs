@@ -1,44 +1,118 @@
 use ffimage::color::Bgra;
 
+use v4l::capability::Flags;
 use v4l::context::Node;
 use v4l::io::traits::CaptureStream;
 
 use v4l::video::Capture;
 use v4l::*;
 
-use std::marker::PhantomData;
+use std::os::fd::BorrowedFd;
+use std::sync::mpsc::Receiver;
+use std::sync::{Arc, Condvar, Mutex, OnceLock, RwLock};
+use std::time::{Duration, Instant};
 
-use std::sync::RwLock;
+use crate::{convert, Colorimetry, InnerCamera, NativePlane, PixelFormat};
 
-use crate::InnerCamera;
+mod buffers;
+mod device;
+mod device_info;
+mod format;
+mod metadata;
+mod reconnect;
+
+use buffers::{Buffers, Lease};
+#[cfg(test)]
+pub(crate) use device::fake;
+use device::{Devices, V4l2Devices};
+pub use device_info::DeviceInfo;
+use format::DeviceFormat;
+pub use format::{FrameSizes, Mode, ModeConfig};
+use metadata::MetadataStream;
+pub use metadata::UvcMetadata;
+use reconnect::Reconnect;
+pub use reconnect::{ReconnectEvent, ReconnectPolicy};
 
 pub struct Camera {
-    device: RwLock<v4l::Device>,
-    device_name: String,
-    stream: RwLock<Option<v4l::io::mmap::Stream<'static>>>,
-}
-
-fn name_or_path(device_node: &v4l::context::Node) -> String {
-    device_node.name().unwrap_or_else(|| device_node.path().to_string_lossy().to_string())
-}
-
-fn get_next_best_format(device: &Device) -> Format {
-    let _rgb = FourCC::new(b"RGB3");
-    let mut fmt = device.format().expect("device.format()");
-    let size = device
-        .enum_framesizes(fmt.fourcc)
-        .unwrap()
-        .into_iter()
-        .next()
-        .unwrap()
-        .size
-        .to_discrete()
-        .into_iter()
-        .last()
-        .unwrap();
-    fmt.width = size.width;
-    fmt.height = size.height;
-    fmt
+    devices: Arc<dyn Devices>,
+    node: RwLock<Arc<dyn device::Node>>,
+    info: RwLock<DeviceInfo>,
+    format: RwLock<DeviceFormat>,
+    stream: RwLock<Option<Stream>>,
+    metadata: RwLock<Option<MetadataStream>>,
+    options: RwLock<StreamOptions>,
+    reconnect: Mutex<Reconnect>,
+    /// Wakes a reconnect waiting for its next attempt when the camera stops.
+    stopping: Condvar,
+    /// Why the last start failed, for the next [Camera::try_wait_for_frame].
+    start_error: Mutex<Option<std::io::Error>>,
+}
+
+/// Settings for the next start, kept when changing the device.
+#[derive(Debug, Clone, Copy)]
+struct StreamOptions {
+    io_mode: Option<IoMode>,
+    buffer_count: u32,
+    drain_to_newest: bool,
+    uvc_metadata: bool,
+}
+
+impl Default for StreamOptions {
+    fn default() -> Self {
+        Self { io_mode: None, buffer_count: 4, drain_to_newest: false, uvc_metadata: false }
+    }
+}
+
+/// How frames get from a V4L2 driver to the process, see
+/// [Camera::set_io_mode](crate::Camera::set_io_mode).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IoMode {
+    /// Streaming into buffers of the driver mapped into the process, what most drivers do.
+    /// Frames hold their buffer until they are dropped and can pass it on as dma-buf, see
+    /// [Frame::dmabuf_fd](crate::Frame::dmabuf_fd).
+    Mmap,
+    /// Streaming into buffers allocated by the process.
+    UserPtr,
+    /// `read()` calls on the device, for drivers without streaming like some older capture
+    /// cards and virtual devices. Frames are timestamped when they arrive.
+    Read,
+}
+
+enum Stream {
+    Mmap(Arc<Buffers>),
+    UserPtr(v4l::io::userptr::Stream),
+    Read { buf: Vec<u8>, sequence: u32, started: Instant },
+}
+
+/// Damaged frames in a row [Camera::wait_for_frame](InnerCamera::wait_for_frame) skips
+/// before it gives up.
+const SKIPPED_FRAMES: usize = 8;
+
+fn get_next_best_format(node: &dyn device::Node) -> std::io::Result<DeviceFormat> {
+    let buf_type = format::buffer_type(node)?;
+    let fmt = node.format(buf_type)?;
+    let modes = format::modes(node, buf_type);
+    // keep the current fourcc unless frames in it can't be converted
+    let mode = match modes.iter().find(|m| m.fourcc == fmt.fourcc) {
+        Some(mode) if mode.format.is_some() => Some(mode),
+        current => modes.iter().find(|m| m.format.is_some()).or(current),
+    };
+    let fourcc = mode.map_or(fmt.fourcc, |mode| mode.fourcc);
+    // the first listed size, a stepwise range keeps the size the driver picked
+    let size = match mode {
+        Some(Mode { sizes: FrameSizes::Discrete(sizes), .. }) => sizes.first().copied(),
+        Some(Mode { sizes, .. }) => sizes.closest(fmt.size),
+        None => None,
+    };
+    node.set_format(buf_type, fourcc, size.unwrap_or(fmt.size))
+}
+
+fn ioctl<T>(
+    fd: std::os::raw::c_int,
+    request: v4l2::vidioc::_IOC_TYPE,
+    arg: &mut T,
+) -> std::io::Result<()> {
+    unsafe { v4l2::ioctl(fd, request, arg as *mut T as *mut std::ffi::c_void) }
 }
 
 #[allow(unused)]
@@ -63,119 +137,659 @@ fn display_device_formats(device: &Device) {
     }
 }
 
-fn enum_devices() -> Vec<Node> {
-    v4l::context::enum_devices()
-        .into_iter()
-        .filter_map(|node| Device::with_path(node.path()).ok().map(|device| (node, device)))
-        .filter(|(_, device)| device.format().is_ok())
-        .map(|(node, _)| node)
-        .collect()
+/// The video capture nodes, paired with their metadata nodes. Nodes are told apart by their
+/// capabilities, UVC cameras have a metadata node next to each video node.
+pub fn devices() -> Vec<DeviceInfo> {
+    list(&V4l2Devices)
+}
+
+fn list(devices: &dyn Devices) -> Vec<DeviceInfo> {
+    let (mut video, mut metadata) = (Vec::new(), Vec::new());
+    for path in devices.paths() {
+        let Ok(node) = devices.open(&path) else { continue };
+        let Ok(caps) = node.caps() else { continue };
+        if caps.intersects(Flags::VIDEO_CAPTURE | Flags::VIDEO_CAPTURE_MPLANE) {
+            video.push(node.info());
+        } else if caps.contains(Flags::META_CAPTURE) {
+            metadata.push(node.info());
+        }
+    }
+    device_info::pair_metadata(&mut video, &metadata);
+    video
 }
 
 impl Camera {
-    fn from_node(node: &v4l::context::Node) -> Self {
-        let device = v4l::Device::with_path(node.path()).unwrap();
-        device.set_format(&get_next_best_format(&device)).unwrap();
-        Self {
-            device: RwLock::new(device),
-            device_name: name_or_path(node),
+    fn open(devices: Arc<dyn Devices>, info: DeviceInfo) -> std::io::Result<Self> {
+        let node: Arc<dyn device::Node> = devices.open(&info.path)?.into();
+        let format = get_next_best_format(&*node)?;
+        Ok(Self {
+            devices,
+            node: RwLock::new(node),
+            info: RwLock::new(info),
+            format: RwLock::new(format),
             stream: RwLock::new(None),
+            metadata: RwLock::new(None),
+            options: RwLock::default(),
+            reconnect: Mutex::default(),
+            stopping: Condvar::new(),
+            start_error: Mutex::default(),
+        })
+    }
+
+    pub fn open_by_id(id: &str) -> std::io::Result<Self> {
+        Self::open_by_id_in(Arc::new(V4l2Devices), id)
+    }
+
+    fn open_by_id_in(devices: Arc<dyn Devices>, id: &str) -> std::io::Result<Self> {
+        let info = list(&*devices)
+            .into_iter()
+            .find(|info| info.matches(id))
+            .ok_or(std::io::ErrorKind::NotFound)?;
+        Self::open(devices, info)
+    }
+
+    pub(crate) fn first_in(devices: Arc<dyn Devices>) -> std::io::Result<Self> {
+        let info = list(&*devices).into_iter().next().ok_or(std::io::ErrorKind::NotFound)?;
+        Self::open(devices, info)
+    }
+
+    pub fn info(&self) -> DeviceInfo {
+        self.info.read().unwrap().clone()
+    }
+
+    /// The node of the device, another one after reconnecting.
+    fn node(&self) -> Arc<dyn device::Node> {
+        self.node.read().unwrap().clone()
+    }
+
+    pub fn set_io_mode(&self, mode: Option<IoMode>) {
+        self.options.write().unwrap().io_mode = mode;
+    }
+
+    pub fn set_buffer_count(&self, count: u32) {
+        self.options.write().unwrap().buffer_count = count.max(1);
+    }
+
+    pub fn set_drain_to_newest(&self, drain: bool) {
+        self.options.write().unwrap().drain_to_newest = drain;
+    }
+
+    pub fn set_uvc_metadata(&self, enabled: bool) {
+        self.options.write().unwrap().uvc_metadata = enabled;
+    }
+
+    pub fn set_reconnect_policy(&self, policy: Option<ReconnectPolicy>) {
+        let mut reconnect = self.reconnect.lock().unwrap();
+        reconnect.policy = policy;
+        if policy.is_none() {
+            reconnect.lost = None;
         }
     }
+
+    pub fn reconnect_events(&self) -> Receiver<ReconnectEvent> {
+        self.reconnect.lock().unwrap().subscribe()
+    }
+
+    pub fn io_mode(&self) -> Option<IoMode> {
+        self.stream.read().unwrap().as_ref().map(|stream| match stream {
+            Stream::Mmap(_) => IoMode::Mmap,
+            Stream::UserPtr(_) => IoMode::UserPtr,
+            Stream::Read { .. } => IoMode::Read,
+        })
+    }
+
+    pub fn modes(&self) -> Vec<Mode> {
+        format::modes(&*self.node(), self.format.read().unwrap().buf_type)
+    }
+
+    pub fn set_mode(&self, config: ModeConfig) -> std::io::Result<ModeConfig> {
+        self.stopped(|| self.apply_mode(config))
+    }
+
+    pub fn frame_rate(&self) -> Option<(u32, u32)> {
+        format::frame_rate(&*self.node(), self.format.read().unwrap().buf_type)
+    }
+
+    pub fn set_frame_rate(&self, rate: (u32, u32)) -> std::io::Result<(u32, u32)> {
+        self.stopped(|| format::set_frame_rate(&*self.node(), &self.format.read().unwrap(), rate))
+    }
+
+    /// Runs `f` with the stream stopped, drivers refuse to change the format while streaming.
+    fn stopped<R>(&self, f: impl FnOnce() -> R) -> R {
+        let started = self.stream.read().unwrap().is_some();
+        self.stop();
+        let result = f();
+        if started {
+            self.start();
+        }
+        result
+    }
+
+    fn apply_mode(&self, config: ModeConfig) -> std::io::Result<ModeConfig> {
+        let node = &*self.node();
+        let current = self.format.read().unwrap().clone();
+        let modes = format::modes(node, current.buf_type);
+        let mode = match config.format {
+            Some(format) => Some(
+                modes
+                    .iter()
+                    .find(|mode| mode.format == Some(format))
+                    .ok_or(std::io::ErrorKind::Unsupported)?,
+            ),
+            None => modes.iter().find(|mode| mode.fourcc == current.fourcc),
+        };
+        let fourcc = mode.map_or(current.fourcc, |mode| mode.fourcc);
+        let size = config.size.unwrap_or(current.size);
+        let size = mode.and_then(|mode| mode.sizes.closest(size)).unwrap_or(size);
+        let format = node.set_format(current.buf_type, fourcc, size)?;
+        // the device has the new format even when the rate fails
+        *self.format.write().unwrap() = format.clone();
+        if let Some(rate) = config.frame_rate {
+            format::set_frame_rate(node, &format, rate)?;
+        }
+        Ok(ModeConfig {
+            format: format.pixel_format(),
+            size: Some(format.size),
+            frame_rate: format::frame_rate(node, format.buf_type),
+        })
+    }
+
+    fn open_stream(&self, mode: IoMode) -> std::io::Result<Stream> {
+        let format = self.format.read().unwrap();
+        let (typ, planes) = (format.buf_type, format.planes.len());
+        let count = self.options.read().unwrap().buffer_count;
+        Ok(match mode {
+            IoMode::Mmap => {
+                let buffers = Buffers::new(self.node().driver(typ, planes), count)?;
+                buffers.start()?;
+                Stream::Mmap(buffers)
+            }
+            // only mapped buffers know about planes
+            _ if format.is_multi_planar() => {
+                return Err(std::io::ErrorKind::Unsupported.into());
+            }
+            IoMode::UserPtr => Stream::UserPtr(self.node().user_ptr(typ, count)?),
+            IoMode::Read => {
+                let len = format.planes[0].1;
+                Stream::Read { buf: vec![0; len], sequence: 0, started: Instant::now() }
+            }
+        })
+    }
+
+    fn start_stream(&self) -> std::io::Result<()> {
+        if self.stream.read().unwrap().is_some() {
+            return Ok(());
+        }
+        let forced = self.options.read().unwrap().io_mode;
+        let stream = match forced {
+            Some(mode) => self.open_stream(mode)?,
+            None => {
+                let caps = self.node().caps();
+                let caps = caps.unwrap_or(Flags::STREAMING);
+                // some drivers announce streaming but refuse to allocate buffers
+                let stream = caps
+                    .contains(Flags::STREAMING)
+                    .then(|| self.open_stream(IoMode::Mmap).ok())
+                    .flatten();
+                match stream {
+                    Some(stream) => stream,
+                    None if caps.contains(Flags::READ_WRITE) => self.open_stream(IoMode::Read)?,
+                    None => self.open_stream(IoMode::Mmap)?,
+                }
+            }
+        };
+        let _ = self.stream.write().unwrap().insert(stream);
+        let options = *self.options.read().unwrap();
+        // frames go without metadata when the node is busy or has another format
+        *self.metadata.write().unwrap() = match &self.info.read().unwrap().metadata {
+            Some(path) if options.uvc_metadata => self
+                .devices
+                .open(path)
+                .and_then(|node| MetadataStream::open(node, options.buffer_count))
+                .ok(),
+            _ => None,
+        };
+        Ok(())
+    }
+
+    fn drop_stream(&self) {
+        self.metadata.write().unwrap().take();
+        if let Some(Stream::Mmap(buffers)) = self.stream.write().unwrap().take() {
+            // frames may still hold buffers
+            buffers.stop();
+        }
+    }
+
+    /// Like [Camera::wait_for_frame](InnerCamera::wait_for_frame), with a reconnect policy
+    /// it fails with [Interrupted](std::io::ErrorKind::Interrupted) while the device is
+    /// gone and with [NotConnected](std::io::ErrorKind::NotConnected) after giving up.
+    pub fn try_wait_for_frame(&self) -> std::io::Result<Frame> {
+        if let Some(err) = self.start_error.lock().unwrap().take() {
+            let kind = match err.kind() {
+                std::io::ErrorKind::Unsupported => std::io::ErrorKind::Unsupported,
+                _ => std::io::ErrorKind::NotConnected,
+            };
+            return Err(std::io::Error::new(kind, format!("failed to start streaming: {err}")));
+        }
+        loop {
+            if self.reconnect.lock().unwrap().lost.is_some() {
+                self.reconnect()?;
+            }
+            match self.next_frame() {
+                Err(err) if is_lost(&err) && self.lose() => continue,
+                result => return result,
+            }
+        }
+    }
+
+    /// Starts reconnecting, `false` without a policy.
+    fn lose(&self) -> bool {
+        let id = self.info.read().unwrap().stable_id();
+        let lost = self.reconnect.lock().unwrap().lose(id);
+        if lost {
+            self.drop_stream();
+        }
+        lost
+    }
+
+    /// Waits for the next attempt and makes it, unless the camera stops meanwhile.
+    fn reconnect(&self) -> std::io::Result<()> {
+        let reconnect = self.reconnect.lock().unwrap();
+        let (id, due, gave_up) = match &reconnect.lost {
+            Some(lost) => (lost.id.clone(), lost.due, lost.gave_up),
+            None => return Ok(()),
+        };
+        if gave_up {
+            let msg = format!("gave up reconnecting to {id}");
+            return Err(std::io::Error::new(std::io::ErrorKind::NotConnected, msg));
+        }
+        let lost = |reconnect: &mut Reconnect| reconnect.lost.as_ref().is_some_and(|l| l.id == id);
+        let timeout = due.saturating_duration_since(Instant::now());
+        let (mut reconnect, _) =
+            self.stopping.wait_timeout_while(reconnect, timeout, lost).unwrap();
+        if !lost(&mut reconnect) {
+            let msg = "camera stopped while reconnecting";
+            return Err(std::io::Error::new(std::io::ErrorKind::NotConnected, msg));
+        }
+        // locked, so that stop() waits for the stream of a successful attempt to drop it
+        let err = match self.reopen(&id, reconnect.frame_rate) {
+            Ok(path) => {
+                reconnect.reconnected(path);
+                return Ok(());
+            }
+            Err(err) => err,
+        };
+        reconnect.failed();
+        let kind = match reconnect.lost.as_ref().is_some_and(|lost| lost.gave_up) {
+            true => std::io::ErrorKind::NotConnected,
+            false => std::io::ErrorKind::Interrupted,
+        };
+        Err(std::io::Error::new(kind, format!("reconnecting to {id}: {err}")))
+    }
+
+    /// Opens the device with `id` again, in the mode and with the frame rate it had, and
+    /// starts streaming.
+    fn reopen(
+        &self,
+        id: &str,
+        frame_rate: Option<(u32, u32)>,
+    ) -> std::io::Result<std::path::PathBuf> {
+        let info = list(&*self.devices)
+            .into_iter()
+            .find(|info| info.matches(id))
+            .ok_or(std::io::ErrorKind::NotFound)?;
+        let node: Arc<dyn device::Node> = self.devices.open(&info.path)?.into();
+        let current = self.format.read().unwrap().clone();
+        let format = node.set_format(current.buf_type, current.fourcc, current.size)?;
+        if let Some(rate) = frame_rate {
+            format::set_frame_rate(&*node, &format, rate)?;
+        }
+        let path = info.path.clone();
+        *self.node.write().unwrap() = node;
+        *self.info.write().unwrap() = info;
+        *self.format.write().unwrap() = format;
+        self.start_stream()?;
+        Ok(path)
+    }
+
+    fn next_frame(&self) -> std::io::Result<Frame> {
+        let format = self.format.read().unwrap().clone();
+        let native_format = format.pixel_format().ok_or_else(|| {
+            std::io::Error::new(std::io::ErrorKind::Unsupported, "unsupported pixel format")
+        })?;
+        let plane_strides: Vec<_> = format.planes.iter().map(|plane| plane.0).collect();
+        let size = format.size;
+        // shorter uncompressed frames were cut off, like after an USB error
+        let frame_len = match format.is_multi_planar() {
+            true => 0,
+            false => native_format.frame_len(size, plane_strides[0]),
+        };
+        let drain = self.options.read().unwrap().drain_to_newest;
+        let node = self.node();
+        let mut stream = self.stream.write().unwrap();
+        let stream = stream.as_mut().ok_or_else(|| {
+            std::io::Error::new(std::io::ErrorKind::NotConnected, "camera not started")
+        })?;
+        let mut skipped = 0;
+        let (native, timestamp, sequence, monotonic) = loop {
+            let next = match &mut *stream {
+                Stream::Mmap(buffers) => {
+                    let lease = if drain { buffers.newest() } else { buffers.next() };
+                    lease.map(|lease| {
+                        let (timestamp, sequence) = (lease.timestamp(), lease.sequence());
+                        let monotonic = lease.monotonic();
+                        // the application holds all other buffers, copy so the driver can go on
+                        let native = match buffers.queued() {
+                            0 => Native::Copied(pack(&lease, native_format, size, &plane_strides)),
+                            _ => Native::Leased(Box::new(lease)),
+                        };
+                        (native, timestamp, sequence, monotonic)
+                    })
+                }
+                Stream::UserPtr(stream) => stream.next().map(buffer_frame),
+                Stream::Read { buf, sequence, started } => node.read(buf).map(|len| {
+                    *sequence += 1;
+                    let native = Native::Copied(buf[..len].to_vec());
+                    (native, started.elapsed(), *sequence - 1, false)
+                }),
+            };
+            let damaged = match &next {
+                Ok((native, ..)) => native.len() < frame_len,
+                Err(err) => err.raw_os_error() == Some(libc::EIO),
+            };
+            if !damaged {
+                break next?;
+            }
+            // the next frame is usually fine again, a damaged one goes back to the driver
+            skipped += 1;
+            if skipped > SKIPPED_FRAMES {
+                let msg = "only damaged frames";
+                return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, msg));
+            }
+        };
+        let uvc_metadata = match self.metadata.write().unwrap().as_mut() {
+            Some(metadata) => metadata.take(sequence),
+            None => Vec::new(),
+        };
+
+        Ok(Frame {
+            native,
+            native_format,
+            plane_strides,
+            packed: OnceLock::new(),
+            bgra: OnceLock::new(),
+            size,
+            timestamp,
+            sequence,
+            colorimetry: format.colorimetry,
+            latency: monotonic.then(|| monotonic_now().saturating_sub(timestamp)),
+            uvc_metadata,
+        })
+    }
 }
 
 impl InnerCamera for Camera {
     type Frame = Frame;
 
     fn new_default_device() -> Self {
-        let node = enum_devices().into_iter().next().unwrap();
-        Self::from_node(&node)
+        Self::first_in(Arc::new(V4l2Devices)).expect("camera device")
     }
 
     fn start(&self) {
-        if self.stream.read().unwrap().is_none() {
-            let device = self.device.write().unwrap();
-            let stream =
-                v4l::io::mmap::Stream::with_buffers(&device, v4l::buffer::Type::VideoCapture, 4)
-                    .expect("Failed to create buffer stream");
-            let _ = self.stream.write().unwrap().insert(stream);
-        }
+        let result = self.start_stream();
+        *self.start_error.lock().unwrap() = match result {
+            Ok(()) => {
+                self.reconnect.lock().unwrap().frame_rate = self.frame_rate();
+                None
+            }
+            // streams again once the device is back
+            Err(err) if is_lost(&err) && self.lose() => None,
+            Err(err) => Some(err),
+        };
     }
 
     fn stop(&self) {
-        let _ = self.stream.write().unwrap().take();
+        self.reconnect.lock().unwrap().lost = None;
+        self.stopping.notify_all();
+        self.start_error.lock().unwrap().take();
+        self.drop_stream();
     }
 
     fn wait_for_frame(&self) -> Option<Frame> {
-        let format = self.device.read().unwrap().format().unwrap();
-        let size = (format.width, format.height);
-        if let Ok((buf, _meta)) = self.stream.write().unwrap().as_mut().unwrap().next() {
-            let data = match &format.fourcc.repr {
-                b"RGB3" => buf.to_vec(),
-                b"YUYV" => yuyv_to_rgb32(buf, size.0, size.1),
-                b"MJPG" => todo!("NJPG not implemented"),
-                _ => panic!("invalid buffer pixelformat"),
-            };
-
-            Some(Frame { data, size })
-        } else {
-            None
+        // keeps waiting while reconnecting, `None` once given up or stopped
+        loop {
+            match self.try_wait_for_frame() {
+                Err(err) if err.kind() == std::io::ErrorKind::Interrupted => continue,
+                result => return result.ok(),
+            }
         }
     }
 
     fn change_device(&mut self) {
-        let devices = enum_devices();
-        if let Some(pos) = devices.iter().position(|n| name_or_path(n) == self.device_name) {
-            let new_pos = (pos + 1) % devices.len();
-            if new_pos != pos {
-                *self = Self::from_node(&devices[new_pos]);
-                self.start();
+        let devices = list(&*self.devices);
+        let path = self.info.read().unwrap().path.clone();
+        let next = match devices.iter().position(|info| info.path == path) {
+            Some(pos) => devices.get((pos + 1) % devices.len()).filter(|_| devices.len() > 1),
+            None => devices.first(),
+        };
+        let Some(next) = next else {
+            if devices.is_empty() {
+                self.stop();
             }
-        } else if !devices.is_empty() {
-            *self = Self::from_node(&devices[0]);
+            return;
+        };
+        // a busy device is left out, the camera stays with the current one
+        if let Ok(camera) = Self::open(self.devices.clone(), next.clone()) {
+            *camera.options.write().unwrap() = *self.options.read().unwrap();
+            let mut reconnect = std::mem::take(&mut *self.reconnect.lock().unwrap());
+            reconnect.lost = None;
+            *camera.reconnect.lock().unwrap() = reconnect;
+            *self = camera;
             self.start();
-        } else {
-            self.stop();
         }
     }
+
+    fn device_name(&self) -> String {
+        self.info.read().unwrap().name.clone()
+    }
+}
+
+/// Copies the planes of a buffer into one laid out like [PixelFormat::planes].
+fn pack(lease: &Lease, format: PixelFormat, size: (u32, u32), strides: &[usize]) -> Vec<u8> {
+    if lease.planes() == 1 {
+        return lease.data(0).to_vec();
+    }
+    let first_stride = strides.first().copied().unwrap_or(0);
+    let mut packed = vec![0; format.frame_len(size, first_stride)];
+    let layout = format.planes(size, first_stride).into_iter().zip(strides);
+    for (index, (plane, &stride)) in layout.enumerate().take(lease.planes()) {
+        let data = lease.data(index);
+        for row in 0..plane.rows {
+            let Some(src) = data.get(row * stride..row * stride + plane.row_len) else { break };
+            let dst = plane.offset + row * plane.stride;
+            packed[dst..dst + plane.row_len].copy_from_slice(src);
+        }
+    }
+    packed
+}
+
+fn buffer_frame((buf, meta): (&[u8], &v4l::buffer::Metadata)) -> (Native, Duration, u32, bool) {
+    // the buffer has the allocated size, compressed frames are usually shorter
+    let used = if meta.bytesused > 0 { meta.bytesused as usize } else { buf.len() };
+    let timestamp = Duration::new(meta.timestamp.sec as _, meta.timestamp.usec as u32 * 1000);
+    let clock = meta.flags & v4l::buffer::Flags::TIMESTAMP_MASK;
+    let monotonic = clock == v4l::buffer::Flags::TIMESTAMP_MONOTONIC;
+    (Native::Copied(buf[..used.min(buf.len())].to_vec()), timestamp, meta.sequence, monotonic)
+}
+
+/// What drivers return once their device is unplugged.
+fn is_lost(err: &std::io::Error) -> bool {
+    err.raw_os_error() == Some(libc::ENODEV)
+}
+
+/// The clock of V4L2 timestamps.
+fn monotonic_now() -> Duration {
+    let mut now = libc::timespec { tv_sec: 0, tv_nsec: 0 };
+    unsafe { libc::clock_gettime(libc::CLOCK_MONOTONIC, &mut now) };
+    Duration::new(now.tv_sec as u64, now.tv_nsec as u32)
 }
 
 impl std::fmt::Debug for Camera {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        f.debug_struct("Camera").field("device", &self.device_name).finish()
+        f.debug_struct("Camera").field("device", &self.info.read().unwrap().name).finish()
     }
 }
 
 pub struct Frame {
-    data: Vec<u8>,
+    native: Native,
+    native_format: PixelFormat,
+    /// Bytes per row of each memory plane.
+    plane_strides: Vec<usize>,
+    /// The planes of a multi-planar buffer in one for [Frame::native].
+    packed: OnceLock<Vec<u8>>,
+    bgra: OnceLock<Vec<u8>>,
     size: (u32, u32),
+    timestamp: Duration,
+    sequence: u32,
+    colorimetry: Colorimetry,
+    latency: Option<Duration>,
+    uvc_metadata: Vec<UvcMetadata>,
+}
+
+enum Native {
+    Copied(Vec<u8>),
+    /// Still the buffer of the driver, queued back on drop.
+    Leased(Box<Lease>),
+}
+
+impl Native {
+    /// Bytes of a single-planar buffer, the planes of others are checked when packed.
+    fn len(&self) -> usize {
+        match self {
+            Native::Copied(bytes) => bytes.len(),
+            Native::Leased(lease) if lease.planes() == 1 => lease.data(0).len(),
+            Native::Leased(_) => usize::MAX,
+        }
+    }
 }
 
 impl Frame {
-    pub fn data(&self) -> FrameData {
-        FrameData { data: self.data.clone(), _phantom: PhantomData }
+    fn native_bytes(&self) -> &[u8] {
+        match &self.native {
+            Native::Copied(bytes) => bytes,
+            Native::Leased(lease) if lease.planes() == 1 => lease.data(0),
+            Native::Leased(lease) => self
+                .packed
+                .get_or_init(|| pack(lease, self.native_format, self.size, &self.plane_strides)),
+        }
+    }
+
+    fn native_stride(&self) -> usize {
+        self.plane_strides.first().copied().unwrap_or(0)
+    }
+
+    pub fn data(&self) -> FrameData<'_> {
+        let native = self.native_bytes();
+        let bgra = self.bgra.get_or_init(|| match self.native_format {
+            PixelFormat::Yuyv => yuyv_to_rgb32(native, self.size.0, self.size.1),
+            format => convert::to_bgra(native, self.size, format, self.native_stride()),
+        });
+        FrameData { data: bgra }
+    }
+
+    pub fn native(&self) -> Option<(FrameData<'_>, PixelFormat, usize)> {
+        Some((FrameData { data: self.native_bytes() }, self.native_format, self.native_stride()))
+    }
+
+    pub fn native_planes(&self) -> Vec<NativePlane<'_>> {
+        let Native::Leased(lease) = &self.native else {
+            return NativePlane::split(
+                self.native_bytes(),
+                self.native_format,
+                self.size,
+                self.native_stride(),
+            );
+        };
+        if lease.planes() > 1 {
+            let planes = self.plane_strides.iter().enumerate();
+            return planes
+                .map(|(index, &stride)| {
+                    NativePlane::new(lease.data(index), stride, lease.dmabuf(index))
+                })
+                .collect();
+        }
+        // the planes of a single-planar format follow each other in the buffer
+        let layout = self.native_format.planes(self.size, self.native_stride());
+        let planes =
+            NativePlane::split(lease.data(0), self.native_format, self.size, self.native_stride());
+        planes
+            .into_iter()
+            .enumerate()
+            .map(|(index, plane)| {
+                let offset = layout.get(index).map_or(0, |plane| plane.offset);
+                let dmabuf = lease.dmabuf(0).map(|(fd, start)| (fd, start + offset));
+                NativePlane::new(plane.data(), plane.stride(), dmabuf)
+            })
+            .collect()
+    }
+
+    pub fn dmabuf_fd(&self) -> Option<BorrowedFd<'_>> {
+        match &self.native {
+            Native::Leased(lease) => lease.dmabuf(0).map(|(fd, _)| fd),
+            Native::Copied(_) => None,
+        }
     }
 
     pub fn size_u32(&self) -> (u32, u32) {
         self.size
     }
+
+    pub fn format(&self) -> PixelFormat {
+        PixelFormat::Bgra
+    }
+
+    pub fn stride(&self) -> usize {
+        self.size.0 as usize * 4
+    }
+
+    pub fn timestamp(&self) -> Duration {
+        self.timestamp
+    }
+
+    pub fn sequence(&self) -> Option<u32> {
+        Some(self.sequence)
+    }
+
+    pub fn colorimetry(&self) -> Colorimetry {
+        self.colorimetry
+    }
+
+    pub fn latency(&self) -> Option<Duration> {
+        self.latency
+    }
+
+    pub fn uvc_metadata(&self) -> &[UvcMetadata] {
+        &self.uvc_metadata
+    }
 }
 
 impl std::fmt::Debug for Frame {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        f.debug_struct("Frame").field("data", &self.data.len()).finish()
+        f.debug_struct("Frame")
+            .field("format", &self.native_format)
+            .field("data", &self.native_bytes().len())
+            .finish()
     }
 }
 
 #[derive(Debug)]
 pub struct FrameData<'a> {
-    data: Vec<u8>,
-    _phantom: PhantomData<&'a ()>,
+    data: &'a [u8],
 }
 
 impl<'a> FrameData<'a> {
     pub fn data_u8(&self) -> &[u8] {
-        &self.data
+        self.data
     }
 
     pub fn data_u32(&self) -> &[u32] {
@@ -199,3 +813,317 @@ fn yuyv_to_rgb32(buf: &[u8], w: u32, h: u32) -> Vec<u8> {
 
     rgba.into_buf()
 }
+
+#[cfg(test)]
+mod tests {
+    use super::buffers::tests::FakeDriver;
+    use super::device::fake::{FakeCamera, FakeDevices, Op};
+    use super::*;
+    use std::io;
+    use std::path::Path;
+
+    fn fake_camera(number: u32) -> FakeCamera {
+        let modes = vec![
+            (*b"YUYV", FrameSizes::Discrete(vec![(640, 480), (320, 240)])),
+            (*b"GREY", FrameSizes::Stepwise { min: (16, 16), max: (1920, 1080), step: (16, 8) }),
+        ];
+        FakeCamera::new(number, "Fake Camera", modes)
+    }
+
+    fn open(fake: &FakeCamera) -> Camera {
+        Camera::first_in(FakeDevices::new(vec![fake.clone()])).unwrap()
+    }
+
+    #[test]
+    fn packs_multi_planar_buffers() {
+        let driver = FakeDriver { planes: 2, ..Default::default() };
+        let buffers = Buffers::new(Box::new(driver), 2).unwrap();
+        buffers.start().unwrap();
+        // the fake fills 8 bytes of each plane with its number
+        let lease = buffers.next().unwrap();
+        let packed = pack(&lease, PixelFormat::Nv12, (4, 2), &[4, 4]);
+        assert_eq!(packed, [[0; 8].as_slice(), &[1; 4]].concat());
+
+        let frame = Frame {
+            native: Native::Leased(Box::new(lease)),
+            native_format: PixelFormat::Nv12,
+            plane_strides: vec![4, 4],
+            packed: OnceLock::new(),
+            bgra: OnceLock::new(),
+            size: (4, 2),
+            timestamp: Duration::ZERO,
+            sequence: 0,
+            colorimetry: Colorimetry::default(),
+            latency: None,
+            uvc_metadata: Vec::new(),
+        };
+        let planes = frame.native_planes();
+        assert_eq!((planes[0].data(), planes[0].stride()), (&[0; 8][..], 4));
+        assert_eq!((planes[1].data(), planes[1].stride()), (&[1; 8][..], 4));
+        assert!(planes[1].dmabuf_fd().is_some());
+        assert_eq!(frame.native().unwrap().0.data_u8(), packed);
+    }
+
+    #[test]
+    fn opens_with_first_listed_size() {
+        let camera = open(&fake_camera(0));
+        let format = camera.format.read().unwrap().clone();
+        assert_eq!((format.fourcc, format.size), (*b"YUYV", (640, 480)));
+        assert_eq!(camera.modes().len(), 2);
+        assert_eq!(camera.frame_rate(), Some((30, 1)));
+    }
+
+    #[test]
+    fn opens_with_convertible_format() {
+        let modes = vec![
+            (*b"BA81", FrameSizes::Discrete(vec![(640, 480)])),
+            (*b"YUYV", FrameSizes::Discrete(vec![(320, 240)])),
+        ];
+        let camera = open(&FakeCamera::new(0, "Fake Camera", modes));
+        let format = camera.format.read().unwrap().clone();
+        assert_eq!((format.fourcc, format.size), (*b"YUYV", (320, 240)));
+
+        let modes = vec![(*b"BA81", FrameSizes::Discrete(vec![(640, 480)]))];
+        let camera = open(&FakeCamera::new(0, "Fake Camera", modes));
+        camera.start();
+        let err = camera.try_wait_for_frame().unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::Unsupported);
+    }
+
+    #[test]
+    fn sets_modes() {
+        let fake = fake_camera(0);
+        let camera = open(&fake);
+        let gray = ModeConfig {
+            format: Some(PixelFormat::Gray),
+            size: Some((330, 250)),
+            frame_rate: Some((15, 1)),
+        };
+        let applied = camera.set_mode(gray).unwrap();
+        assert_eq!(applied, ModeConfig { size: Some((336, 248)), ..gray });
+
+        let nv12 = ModeConfig { format: Some(PixelFormat::Nv12), ..Default::default() };
+        assert_eq!(camera.set_mode(nv12).unwrap_err().kind(), io::ErrorKind::Unsupported);
+        fake.fail(Op::SetFormat, libc::EBUSY);
+        let larger = ModeConfig { size: Some((1920, 1080)), ..Default::default() };
+        assert_eq!(camera.set_mode(larger).unwrap_err().raw_os_error(), Some(libc::EBUSY));
+        assert_eq!(camera.format.read().unwrap().size, (336, 248));
+        assert_eq!(camera.set_frame_rate((24, 1)).unwrap(), (30, 1));
+
+        fake.fail(Op::SetInterval, libc::EBUSY);
+        let small = ModeConfig {
+            format: Some(PixelFormat::Yuyv),
+            size: Some((320, 240)),
+            frame_rate: Some((15, 1)),
+        };
+        assert!(camera.set_mode(small).is_err());
+        assert_eq!(camera.format.read().unwrap().size, (320, 240));
+        assert_eq!(&camera.format.read().unwrap().fourcc, b"YUYV");
+    }
+
+    #[test]
+    fn converts_generated_frames() {
+        let camera = open(&fake_camera(0));
+        camera.start();
+        let frame = camera.wait_for_frame().unwrap();
+        assert_eq!(frame.native().unwrap().0.data_u8().len(), 640 * 480 * 2);
+        let data = frame.data();
+        assert_eq!(data.data_u8().len(), 640 * 480 * 4);
+        let [b, g, r, _] = data.data_u8()[..4] else { unreachable!() };
+        assert!(b == g && g == r, "gray {:?}", [b, g, r]);
+
+        camera
+            .set_mode(ModeConfig { format: Some(PixelFormat::Gray), ..Default::default() })
+            .unwrap();
+        let frame = camera.wait_for_frame().unwrap();
+        assert_eq!(frame.size, (640, 480));
+        assert_eq!(frame.data().data_u8()[..4], [0x80, 0x80, 0x80, 0xff]);
+    }
+
+    #[test]
+    fn skips_damaged_frames() {
+        let fake = fake_camera(0);
+        let camera = open(&fake);
+        camera.start();
+        fake.fail(Op::Dequeue, libc::EIO);
+        fake.state.lock().unwrap().short.extend([1000, 2]);
+        // the two cut off frames went back to the driver
+        let frame = camera.wait_for_frame().unwrap();
+        assert_eq!(frame.sequence, 2);
+        assert_eq!(frame.data().data_u8().len(), 640 * 480 * 4);
+
+        fake.state.lock().unwrap().short.extend([2; SKIPPED_FRAMES + 1]);
+        assert!(camera.wait_for_frame().is_none());
+        assert!(camera.wait_for_frame().is_some());
+        fake.fail(Op::Dequeue, libc::ENODEV);
+        assert!(camera.wait_for_frame().is_none());
+    }
+
+    #[test]
+    fn reads_without_streaming() {
+        let mut fake = fake_camera(0);
+        fake.caps = Flags::VIDEO_CAPTURE | Flags::READ_WRITE;
+        let camera = open(&fake);
+        camera.start();
+        assert_eq!(camera.io_mode(), Some(IoMode::Read));
+        fake.state.lock().unwrap().short.push_back(10);
+        assert_eq!(camera.wait_for_frame().unwrap().sequence, 1);
+        fake.fail(Op::Read, libc::ENODEV);
+        assert!(camera.wait_for_frame().is_none());
+    }
+
+    #[test]
+    fn changes_between_video_nodes() {
+        let (first, second) = (fake_camera(0), fake_camera(2));
+        let devices = FakeDevices::new(vec![first.clone(), first.metadata(), second]);
+        let listed = list(&*devices);
+        assert_eq!(listed.len(), 2);
+        assert_eq!(listed[0].metadata.as_deref(), Some(Path::new("/dev/video1")));
+
+        let mut camera = Camera::first_in(devices.clone()).unwrap();
+        camera.set_buffer_count(2);
+        camera.start();
+        camera.change_device();
+        assert_eq!(camera.info().path, Path::new("/dev/video2"));
+        assert_eq!(camera.options.read().unwrap().buffer_count, 2);
+        assert!(camera.wait_for_frame().is_some());
+
+        // a busy device is left out
+        first.fail(Op::Open, libc::EBUSY);
+        camera.change_device();
+        assert_eq!(camera.info().path, Path::new("/dev/video2"));
+        assert!(camera.wait_for_frame().is_some());
+
+        devices.unplug("/dev/video2");
+        assert!(camera.wait_for_frame().is_none());
+        camera.change_device();
+        assert_eq!(camera.info().path, Path::new("/dev/video0"));
+        assert!(camera.wait_for_frame().is_some());
+        devices.unplug("/dev/video0");
+        camera.change_device();
+        assert!(camera.stream.read().unwrap().is_none());
+    }
+
+    #[test]
+    fn opens_by_id() {
+        let fake = fake_camera(0);
+        let devices = FakeDevices::new(vec![fake.clone()]);
+        let camera = Camera::open_by_id_in(devices.clone(), "usb-0000:00:14.0-0").unwrap();
+        assert_eq!(camera.info().path, Path::new("/dev/video0"));
+        let missing = Camera::open_by_id_in(devices.clone(), "usb-0000:00:14.0-1");
+        assert_eq!(missing.unwrap_err().kind(), io::ErrorKind::NotFound);
+        fake.fail(Op::SetFormat, libc::EBUSY);
+        let busy = Camera::open_by_id_in(devices, "/dev/video0");
+        assert_eq!(busy.unwrap_err().raw_os_error(), Some(libc::EBUSY));
+    }
+
+    #[test]
+    fn reconnects_by_stable_id() {
+        let devices = FakeDevices::new(vec![fake_camera(0)]);
+        let camera = Camera::first_in(devices.clone()).unwrap();
+        let gray = ModeConfig { format: Some(PixelFormat::Gray), ..Default::default() };
+        camera.set_mode(ModeConfig { frame_rate: Some((15, 1)), ..gray }).unwrap();
+        camera.set_buffer_count(2);
+        let delay = Duration::from_millis(1);
+        let policy =
+            ReconnectPolicy { initial_delay: delay, max_delay: delay * 2, max_attempts: Some(3) };
+        camera.set_reconnect_policy(Some(policy));
+        let events = camera.reconnect_events();
+        camera.start();
+        assert!(camera.try_wait_for_frame().is_ok());
+
+        devices.unplug("/dev/video0");
+        let err = camera.try_wait_for_frame().unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::Interrupted);
+        // back after the reset under another node
+        let replugged = fake_camera(4);
+        replugged.state.lock().unwrap().interval = (1, 30);
+        devices.cameras.lock().unwrap().push(FakeCamera {
+            info: DeviceInfo { bus_info: "usb-0000:00:14.0-0".into(), ..replugged.info.clone() },
+            ..replugged.clone()
+        });
+        let frame = camera.try_wait_for_frame().unwrap();
+        assert_eq!(frame.data().data_u8()[..4], [0x80, 0x80, 0x80, 0xff]);
+        assert_eq!(camera.info().path, Path::new("/dev/video4"));
+        assert_eq!(replugged.state.lock().unwrap().format.0, *b"GREY");
+        assert_eq!(camera.frame_rate(), Some((15, 1)));
+        assert_eq!(replugged.state.lock().unwrap().buffers.lock().unwrap().allocated, 2);
+        let id = "usb-0000:00:14.0-0".to_string();
+        assert_eq!(
+            events.try_iter().collect::<Vec<_>>(),
+            [
+                ReconnectEvent::Lost { id },
+                ReconnectEvent::Retrying { attempt: 1, retry_in: delay * 2 },
+                ReconnectEvent::Reconnected { path: "/dev/video4".into(), attempts: 2 },
+            ]
+        );
+
+        // waits through failed attempts, slower ones to replug in between
+        let (initial_delay, max_delay) = (Duration::from_millis(50), Duration::from_millis(100));
+        let slow = ReconnectPolicy { initial_delay, max_delay, ..policy };
+        camera.set_reconnect_policy(Some(slow));
+        devices.unplug("/dev/video4");
+        let frame = std::thread::scope(|scope| {
+            let waiting = scope.spawn(|| camera.wait_for_frame());
+            while !matches!(events.recv().unwrap(), ReconnectEvent::Retrying { .. }) {}
+            let replugged = fake_camera(5);
+            devices.cameras.lock().unwrap().push(FakeCamera {
+                info: DeviceInfo { bus_info: "usb-0000:00:14.0-0".into(), ..replugged.info },
+                ..replugged
+            });
+            waiting.join().unwrap()
+        });
+        assert!(frame.is_some());
+        assert_eq!(camera.info().path, Path::new("/dev/video5"));
+
+        camera.set_reconnect_policy(Some(policy));
+        devices.unplug("/dev/video5");
+        let kinds: Vec<_> =
+            (0..4).map(|_| camera.try_wait_for_frame().unwrap_err().kind()).collect();
+        use io::ErrorKind::{Interrupted, NotConnected};
+        assert_eq!(kinds, [Interrupted, Interrupted, NotConnected, NotConnected]);
+        assert_eq!(events.try_iter().last(), Some(ReconnectEvent::GaveUp { attempts: 3 }));
+        assert!(camera.wait_for_frame().is_none());
+    }
+
+    #[test]
+    fn stopping_ends_the_wait_for_a_reconnect() {
+        let devices = FakeDevices::new(vec![fake_camera(0)]);
+        let camera = Arc::new(Camera::first_in(devices.clone()).unwrap());
+        let policy =
+            ReconnectPolicy { initial_delay: Duration::from_secs(60), ..Default::default() };
+        camera.set_reconnect_policy(Some(policy));
+        camera.start();
+        devices.unplug("/dev/video0");
+        let waiting = camera.clone();
+        let wait = std::thread::spawn(move || waiting.try_wait_for_frame());
+        std::thread::sleep(Duration::from_millis(50));
+        let stopped = Instant::now();
+        camera.stop();
+        let err = wait.join().unwrap().unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::NotConnected);
+        assert!(stopped.elapsed() < Duration::from_secs(5));
+        assert!(camera.stream.read().unwrap().is_none());
+    }
+
+    #[test]
+    fn failed_starts_show_up_when_waiting() {
+        let fake = fake_camera(0);
+        let camera = open(&fake);
+        camera.set_io_mode(Some(IoMode::Mmap));
+        fake.fail(Op::Request, libc::EBUSY);
+        camera.start();
+        let err = camera.try_wait_for_frame().unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::NotConnected);
+        assert!(err.to_string().contains("failed to start"), "{err}");
+        camera.start();
+        assert!(camera.try_wait_for_frame().is_ok());
+        camera.stop();
+
+        let devices = FakeDevices::new(vec![fake.clone()]);
+        let camera = Camera::first_in(devices.clone()).unwrap();
+        devices.unplug("/dev/video0");
+        camera.start();
+        assert_eq!(camera.try_wait_for_frame().unwrap_err().kind(), io::ErrorKind::NotConnected);
+    }
+}
@@ -0,0 +1,140 @@
+//! Opening a lost device again, see [Camera::set_reconnect_policy](crate::Camera::set_reconnect_policy).
+
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::time::{Duration, Instant};
+
+/// How a camera gets its device back after it was lost, like after an USB reset. It looks
+/// for the device by its [stable id](crate::DeviceInfo::stable_id), waiting longer after
+/// every failed attempt.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ReconnectPolicy {
+    /// Wait before the first attempt, doubled after every failed one.
+    pub initial_delay: Duration,
+    pub max_delay: Duration,
+    /// Attempts before giving up, `None` tries until the camera is stopped.
+    pub max_attempts: Option<u32>,
+}
+
+impl Default for ReconnectPolicy {
+    fn default() -> Self {
+        Self {
+            initial_delay: Duration::from_millis(100),
+            max_delay: Duration::from_secs(5),
+            max_attempts: None,
+        }
+    }
+}
+
+/// What happened to the device of a camera with a [ReconnectPolicy], see
+/// [Camera::reconnect_events](crate::Camera::reconnect_events).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ReconnectEvent {
+    /// The device went away, with the stable id it is looked for by.
+    Lost { id: String },
+    /// Attempt number `attempt` failed, the next one is in `retry_in`.
+    Retrying { attempt: u32, retry_in: Duration },
+    /// The device is back at `path` with the mode it had and streaming again.
+    Reconnected { path: std::path::PathBuf, attempts: u32 },
+    /// The policy ran out of attempts.
+    GaveUp { attempts: u32 },
+}
+
+/// Where a camera with a policy stands.
+#[derive(Debug, Default)]
+pub(crate) struct Reconnect {
+    pub policy: Option<ReconnectPolicy>,
+    pub lost: Option<Lost>,
+    /// The frame rate at the last start, the node can't tell it anymore once it's gone.
+    pub frame_rate: Option<(u32, u32)>,
+    events: Vec<Sender<ReconnectEvent>>,
+}
+
+#[derive(Debug)]
+pub(crate) struct Lost {
+    pub id: String,
+    pub attempts: u32,
+    pub due: Instant,
+    delay: Duration,
+    pub gave_up: bool,
+}
+
+impl Reconnect {
+    pub fn subscribe(&mut self) -> Receiver<ReconnectEvent> {
+        let (sender, receiver) = channel();
+        self.events.push(sender);
+        receiver
+    }
+
+    pub fn emit(&mut self, event: ReconnectEvent) {
+        self.events.retain(|sender| sender.send(event.clone()).is_ok());
+    }
+
+    /// Starts looking for the device with `id`, `false` without a policy.
+    pub fn lose(&mut self, id: String) -> bool {
+        let Some(policy) = self.policy else { return false };
+        if self.lost.is_none() {
+            let delay = policy.initial_delay;
+            let due = Instant::now() + delay;
+            self.lost = Some(Lost { id: id.clone(), attempts: 0, due, delay, gave_up: false });
+            self.emit(ReconnectEvent::Lost { id });
+        }
+        true
+    }
+
+    /// Counts a failed attempt and schedules the next one, if the policy allows another.
+    pub fn failed(&mut self) {
+        let (Some(policy), Some(lost)) = (self.policy, self.lost.as_mut()) else { return };
+        lost.attempts += 1;
+        let attempts = lost.attempts;
+        if policy.max_attempts.is_some_and(|max| attempts >= max) {
+            lost.gave_up = true;
+            self.emit(ReconnectEvent::GaveUp { attempts });
+            return;
+        }
+        lost.delay = (lost.delay * 2).min(policy.max_delay);
+        lost.due = Instant::now() + lost.delay;
+        let retry_in = lost.delay;
+        self.emit(ReconnectEvent::Retrying { attempt: attempts, retry_in });
+    }
+
+    pub fn reconnected(&mut self, path: std::path::PathBuf) {
+        if let Some(lost) = self.lost.take() {
+            self.emit(ReconnectEvent::Reconnected { path, attempts: lost.attempts + 1 });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn backs_off_until_giving_up() {
+        let mut reconnect = Reconnect::default();
+        assert!(!reconnect.lose("cam".into()), "no policy");
+        let events = reconnect.subscribe();
+        reconnect.policy = Some(ReconnectPolicy {
+            initial_delay: Duration::from_millis(100),
+            max_delay: Duration::from_millis(300),
+            max_attempts: Some(4),
+        });
+        assert!(reconnect.lose("cam".into()));
+        assert!(reconnect.lose("cam".into()), "lost only once");
+        for _ in 0..4 {
+            reconnect.failed();
+        }
+        assert!(reconnect.lost.as_ref().unwrap().gave_up);
+        let retry_in = |ms| Duration::from_millis(ms);
+        let events: Vec<_> = events.try_iter().collect();
+        assert_eq!(
+            events,
+            [
+                ReconnectEvent::Lost { id: "cam".into() },
+                ReconnectEvent::Retrying { attempt: 1, retry_in: retry_in(200) },
+                ReconnectEvent::Retrying { attempt: 2, retry_in: retry_in(300) },
+                ReconnectEvent::Retrying { attempt: 3, retry_in: retry_in(300) },
+                ReconnectEvent::GaveUp { attempts: 4 },
+            ]
+        );
+    }
+}
@@ -1,6 +1,8 @@
 use super::*;
+use crate::{Colorimetry, PixelFormat};
 use objc2::rc::Id;
 use std::sync::Arc;
+use std::time::Duration;
 
 #[derive(Debug)]
 pub struct Camera {
@@ -66,10 +68,14 @@ impl Camera {
         self.input = new_input;
         self.session.add_input(&self.input);
     }
+
+    pub fn device_name(&self) -> String {
+        self.device.localized_name().to_string()
+    }
 }
 
 impl Frame {
-    pub fn data(&self) -> FrameData {
+    pub fn data(&self) -> FrameData<'_> {
         FrameData { pixels: self.sample.pixels() }
     }
 
@@ -77,6 +83,30 @@ impl Frame {
         let (w, h) = self.sample.size_usize();
         (w as _, h as _)
     }
+
+    pub fn format(&self) -> PixelFormat {
+        PixelFormat::Bgra
+    }
+
+    pub fn native(&self) -> Option<(FrameData<'_>, PixelFormat, usize)> {
+        None
+    }
+
+    pub fn stride(&self) -> usize {
+        self.sample.stride()
+    }
+
+    pub fn timestamp(&self) -> Duration {
+        self.sample.timestamp()
+    }
+
+    pub fn sequence(&self) -> Option<u32> {
+        None
+    }
+
+    pub fn colorimetry(&self) -> Colorimetry {
+        Colorimetry::default()
+    }
 }
 
 impl<'a> FrameData<'a> {
@@ -1,4 +1,5 @@
 use std::ffi::c_void;
+use std::time::Duration;
 
 use objc2::{Encode, Encoding, RefEncode};
 
@@ -17,6 +18,22 @@ impl SampleBuffer {
         let height = unsafe { CVPixelBufferGetHeight(ibuf) };
         (width, height)
     }
+
+    pub fn stride(&self) -> usize {
+        let ibuf = unsafe { CMSampleBufferGetImageBuffer(self.inner) };
+        unsafe { CVPixelBufferGetBytesPerRow(ibuf) }
+    }
+
+    pub fn timestamp(&self) -> Duration {
+        let time = unsafe { CMSampleBufferGetPresentationTimeStamp(self.inner) };
+        if time.timescale > 0 && time.value > 0 {
+            Duration::from_nanos(
+                (time.value as i128 * 1_000_000_000 / time.timescale as i128) as u64,
+            )
+        } else {
+            Duration::ZERO
+        }
+    }
 }
 
 impl Drop for SampleBuffer {
@@ -50,6 +67,7 @@ impl std::fmt::Debug for SampleBuffer {
 extern "C" {
     pub fn CMSampleBufferGetFormatDescription(sbuf: CMSampleBufferRef) -> CMFormatDescriptionRef;
     pub fn CMSampleBufferGetImageBuffer(sbuf: CMSampleBufferRef) -> CVImageBufferRef;
+    pub fn CMSampleBufferGetPresentationTimeStamp(sbuf: CMSampleBufferRef) -> CMTime;
     pub fn CMFormatDescriptionGetMediaSubType(desc: CMFormatDescriptionRef) -> u32;
     pub fn CMVideoFormatDescriptionGetDimensions(desc: CMFormatDescriptionRef)
         -> CMVideoDimensions;
@@ -92,6 +110,15 @@ pub struct CMVideoDimensions {
     pub height: i32,
 }
 
+#[repr(C)]
+#[derive(Debug)]
+pub struct CMTime {
+    pub value: i64,
+    pub timescale: i32,
+    pub flags: u32,
+    pub epoch: i64,
+}
+
 #[repr(C)]
 pub struct CMSampleBuffer {
     _priv: [u8; 0],
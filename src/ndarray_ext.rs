@@ -0,0 +1,93 @@
+//! Views of frames as `ndarray` arrays, enabled with the `ndarray` feature.
+
+use ndarray::{ArrayView2, ArrayView3, ShapeBuilder};
+
+use crate::{FrameBuf, FrameData, PixelFormat};
+
+impl FrameData<'_> {
+    /// See [FrameBuf::array_view].
+    pub fn array_view(&self) -> Option<ArrayView3<'_, u8>> {
+        array_view(self.data_u8(), self.size_u32(), self.format(), self.stride())
+    }
+
+    /// See [FrameBuf::plane_view].
+    pub fn plane_view(&self, index: usize) -> Option<ArrayView2<'_, u8>> {
+        plane_view(self.data_u8(), self.size_u32(), self.format(), self.stride(), index)
+    }
+}
+
+impl FrameBuf {
+    /// The pixels as height x width x channels, the channels in the byte order of the format.
+    /// [PixelFormat::Yuyv] has two channels, Y and alternating U and V.
+    /// Planar formats have no such view, use [FrameBuf::plane_view] instead.
+    /// Compressed formats have neither.
+    pub fn array_view(&self) -> Option<ArrayView3<'_, u8>> {
+        array_view(self.data_u8(), self.size_u32(), self.format(), self.stride())
+    }
+
+    /// The bytes of a plane as rows x bytes per row, without the row padding.
+    /// For [PixelFormat::Nv12] plane 1 holds interleaved U and V bytes.
+    pub fn plane_view(&self, index: usize) -> Option<ArrayView2<'_, u8>> {
+        plane_view(self.data_u8(), self.size_u32(), self.format(), self.stride(), index)
+    }
+}
+
+fn array_view(
+    data: &[u8],
+    (w, h): (u32, u32),
+    format: PixelFormat,
+    stride: usize,
+) -> Option<ArrayView3<'_, u8>> {
+    if format.is_planar() || format.is_compressed() {
+        return None;
+    }
+    let channels = format.bytes_per_pixel();
+    let shape = (h as usize, w as usize, channels).strides((stride, channels, 1));
+    ArrayView3::from_shape(shape, data).ok()
+}
+
+fn plane_view(
+    data: &[u8],
+    size: (u32, u32),
+    format: PixelFormat,
+    stride: usize,
+    index: usize,
+) -> Option<ArrayView2<'_, u8>> {
+    let plane = *format.planes(size, stride).get(index)?;
+    let shape = (plane.rows, plane.row_len).strides((plane.stride, 1));
+    ArrayView2::from_shape(shape, data.get(plane.offset..)?).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn bgra_view_skips_padding() {
+        let data: Vec<u8> = (0..20).collect();
+        let frame = FrameBuf::new(&data, (2, 2), PixelFormat::Bgra, 10, Duration::ZERO);
+        let view = frame.array_view().unwrap();
+        assert_eq!(view.shape(), [2, 2, 4]);
+        assert_eq!(view[[1, 0, 0]], 10);
+        assert_eq!(view[[1, 1, 3]], 17);
+        assert_eq!(frame.plane_view(0).unwrap().shape(), [2, 8]);
+        assert!(frame.plane_view(1).is_none());
+    }
+
+    #[test]
+    fn i420_plane_views() {
+        let data: Vec<u8> = (0..24).collect();
+        let frame = FrameBuf::new(&data, (4, 4), PixelFormat::I420, 4, Duration::ZERO);
+        assert!(frame.array_view().is_none());
+        assert_eq!(frame.plane_view(0).unwrap()[[3, 3]], 15);
+        let v = frame.plane_view(2).unwrap();
+        assert_eq!(v.shape(), [2, 2]);
+        assert_eq!(v[[1, 1]], 23);
+    }
+
+    #[test]
+    fn short_buffer_has_no_frame() {
+        assert!(FrameBuf::try_new(&[0; 7], (1, 2), PixelFormat::Bgra, 4, Duration::ZERO).is_err());
+    }
+}
@@ -0,0 +1,179 @@
+/// Memory layout of the pixels in a frame.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub enum PixelFormat {
+    /// Bytes B, G, R, A. Read as native `u32` on little endian machines this is ARGB.
+    Bgra,
+    /// Bytes R, G, B, A.
+    Rgba,
+    /// Bytes R, G, B.
+    Rgb,
+    /// One luma byte per pixel.
+    Gray,
+    /// Packed 4:2:2 YUV, bytes Y0, U, Y1, V for every two pixels.
+    Yuyv,
+    /// Planar 4:2:0 YUV, a Y plane followed by one plane of interleaved U and V.
+    Nv12,
+    /// Planar 4:2:0 YUV, a Y plane followed by a U and a V plane.
+    I420,
+    /// Planar 4:2:2 YUV, a Y plane followed by a U and a V plane of full height.
+    I422,
+    /// Planar 4:4:4 YUV, three planes of full size.
+    I444,
+    /// One compressed JPEG image per frame, as many webcams deliver it. It has no planes.
+    Mjpeg,
+}
+
+/// Where one plane of a frame lies in the frame data, all values in bytes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Plane {
+    pub offset: usize,
+    /// Bytes from one row to the next, including padding.
+    pub stride: usize,
+    /// Bytes of pixel data in a row, without padding.
+    pub row_len: usize,
+    pub rows: usize,
+}
+
+/// How the pixel values of a native buffer map to colors, as the values of the V4L2 enums
+/// `v4l2_colorspace`, `v4l2_xfer_func` and `v4l2_quantization`. Zero is the default of the
+/// driver, which is also what platforms without this information report.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub struct Colorimetry {
+    pub colorspace: u32,
+    pub transfer: u32,
+    pub quantization: u32,
+}
+
+impl PixelFormat {
+    /// Bytes per pixel of the first plane.
+    pub fn bytes_per_pixel(&self) -> usize {
+        match self {
+            PixelFormat::Bgra | PixelFormat::Rgba => 4,
+            PixelFormat::Rgb => 3,
+            PixelFormat::Yuyv => 2,
+            PixelFormat::Gray
+            | PixelFormat::Nv12
+            | PixelFormat::I420
+            | PixelFormat::I422
+            | PixelFormat::I444 => 1,
+            PixelFormat::Mjpeg => 0,
+        }
+    }
+
+    pub fn is_planar(&self) -> bool {
+        matches!(
+            self,
+            PixelFormat::Nv12 | PixelFormat::I420 | PixelFormat::I422 | PixelFormat::I444
+        )
+    }
+
+    pub fn is_compressed(&self) -> bool {
+        matches!(self, PixelFormat::Mjpeg)
+    }
+
+    /// Plane layout for a frame of `size` whose first plane has `stride` bytes per row.
+    /// Chroma planes follow the conventions of libyuv: NV12 uses the luma stride for the
+    /// interleaved chroma plane, I420 and I422 use half of it for each chroma plane.
+    pub fn planes(&self, (w, h): (u32, u32), stride: usize) -> Vec<Plane> {
+        if self.is_compressed() {
+            return Vec::new();
+        }
+        let (w, h) = (w as usize, h as usize);
+        let row_len = match self {
+            PixelFormat::Yuyv => w.div_ceil(2) * 4,
+            _ => w * self.bytes_per_pixel(),
+        };
+        let stride = stride.max(row_len);
+        let first = Plane { offset: 0, stride, row_len, rows: h };
+        let chroma_offset = stride * h;
+        let (cw, ch) = (w.div_ceil(2), h.div_ceil(2));
+
+        match self {
+            PixelFormat::Nv12 => {
+                let uv = Plane { offset: chroma_offset, stride, row_len: cw * 2, rows: ch };
+                vec![first, uv]
+            }
+            PixelFormat::I420 | PixelFormat::I422 | PixelFormat::I444 => {
+                let (stride, row_len, rows) = match self {
+                    PixelFormat::I420 => (stride.div_ceil(2), cw, ch),
+                    PixelFormat::I422 => (stride.div_ceil(2), cw, h),
+                    _ => (stride, w, h),
+                };
+                let u = Plane { offset: chroma_offset, stride, row_len, rows };
+                let v = Plane { offset: u.offset + stride * rows, ..u };
+                vec![first, u, v]
+            }
+            _ => vec![first],
+        }
+    }
+
+    /// The V4L2 fourcc of the format, like `*b"YUYV"`.
+    pub fn fourcc(&self) -> [u8; 4] {
+        match self {
+            PixelFormat::Bgra => *b"AR24",
+            PixelFormat::Rgba => *b"AB24",
+            PixelFormat::Rgb => *b"RGB3",
+            PixelFormat::Gray => *b"GREY",
+            PixelFormat::Yuyv => *b"YUYV",
+            PixelFormat::Nv12 => *b"NV12",
+            PixelFormat::I420 => *b"YU12",
+            PixelFormat::I422 => *b"422P",
+            PixelFormat::I444 => *b"YM24",
+            PixelFormat::Mjpeg => *b"MJPG",
+        }
+    }
+
+    pub fn from_fourcc(fourcc: [u8; 4]) -> Option<Self> {
+        let formats = [
+            PixelFormat::Bgra,
+            PixelFormat::Rgba,
+            PixelFormat::Rgb,
+            PixelFormat::Gray,
+            PixelFormat::Yuyv,
+            PixelFormat::Nv12,
+            PixelFormat::I420,
+            PixelFormat::I422,
+            PixelFormat::I444,
+            PixelFormat::Mjpeg,
+        ];
+        formats.into_iter().find(|format| format.fourcc() == fourcc)
+    }
+
+    /// Bytes needed for a frame of `size` whose first plane has `stride` bytes per row.
+    /// Zero for compressed formats.
+    pub fn frame_len(&self, size: (u32, u32), stride: usize) -> usize {
+        let planes = self.planes(size, stride);
+        planes.last().map_or(0, |last| last.offset + last.stride * last.rows)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn i420_planes() {
+        let planes = PixelFormat::I420.planes((5, 3), 6);
+        assert_eq!(planes[0], Plane { offset: 0, stride: 6, row_len: 5, rows: 3 });
+        assert_eq!(planes[1], Plane { offset: 18, stride: 3, row_len: 3, rows: 2 });
+        assert_eq!(planes[2], Plane { offset: 24, stride: 3, row_len: 3, rows: 2 });
+        assert_eq!(PixelFormat::I420.frame_len((5, 3), 6), 30);
+    }
+
+    #[test]
+    fn packed_planes() {
+        assert_eq!(PixelFormat::Yuyv.planes((3, 2), 0)[0].row_len, 8);
+        assert_eq!(PixelFormat::Bgra.frame_len((3, 2), 16), 32);
+        assert_eq!(PixelFormat::Nv12.frame_len((4, 4), 4), 24);
+        assert_eq!(PixelFormat::I422.frame_len((3, 2), 3), 14);
+        assert_eq!(PixelFormat::I444.frame_len((3, 2), 3), 18);
+    }
+
+    #[test]
+    fn fourcc_round_trip() {
+        assert_eq!(PixelFormat::from_fourcc(*b"YU12"), Some(PixelFormat::I420));
+        assert_eq!(PixelFormat::from_fourcc(PixelFormat::Bgra.fourcc()), Some(PixelFormat::Bgra));
+        assert_eq!(PixelFormat::from_fourcc(*b"BA81"), None);
+    }
+}
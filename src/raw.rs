@@ -0,0 +1,352 @@
+//! The "kamera raw" capture format: native buffers exactly as the device delivered them, for
+//! debugging driver and color problems. [Camera::open_file] replays them through the normal
+//! conversions.
+//!
+//! A file starts with [MAGIC] followed by one record per frame, all numbers little endian:
+//!
+//! ```text
+//! "FRAM"
+//! u32 length of the header fields that follow the length, 48 in this version
+//! [u8; 4] fourcc, u32 width, u32 height, u32 stride
+//! u32 colorspace, u32 transfer, u32 quantization, see Colorimetry
+//! u32 flags, bit 0 set when the sequence number is known
+//! u32 sequence, u64 timestamp in nanoseconds
+//! u32 data length, then the data
+//! ```
+//!
+//! Readers skip header fields they don't know, so fields can be appended.
+
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::Path;
+use std::time::Duration;
+
+use crate::{Camera, Colorimetry, Frame, FrameBuf, PixelFormat};
+
+pub const MAGIC: &[u8; 8] = b"KAMRAW01";
+const HEADER_LEN: u32 = 48;
+/// Room for fields of later versions, which readers skip.
+const MAX_HEADER_LEN: u32 = 4096;
+const HAS_SEQUENCE: u32 = 1;
+
+/// One captured buffer with everything known about it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Record {
+    pub fourcc: [u8; 4],
+    pub size: (u32, u32),
+    pub stride: usize,
+    pub colorimetry: Colorimetry,
+    pub sequence: Option<u32>,
+    pub timestamp: Duration,
+    pub data: Vec<u8>,
+}
+
+impl Record {
+    /// The buffer as a frame. Fails with [InvalidData](io::ErrorKind::InvalidData) if kamera
+    /// doesn't know the fourcc or the data doesn't fit the size and stride.
+    pub fn to_frame_buf(&self) -> io::Result<FrameBuf> {
+        let format = PixelFormat::from_fourcc(self.fourcc).ok_or_else(|| {
+            let msg = format!("unsupported fourcc {}", String::from_utf8_lossy(&self.fourcc));
+            io::Error::new(io::ErrorKind::InvalidData, msg)
+        })?;
+        FrameBuf::try_new(&self.data, self.size, format, self.stride, self.timestamp)
+    }
+}
+
+pub struct Writer<W: Write = BufWriter<File>> {
+    out: W,
+}
+
+impl Writer {
+    pub fn create(path: impl AsRef<Path>) -> io::Result<Self> {
+        Self::new(BufWriter::new(File::create(path)?))
+    }
+}
+
+impl<W: Write> Writer<W> {
+    /// Writes the file header.
+    pub fn new(mut out: W) -> io::Result<Self> {
+        out.write_all(MAGIC)?;
+        Ok(Self { out })
+    }
+
+    /// Writes the [Frame::native] buffer, or the converted one on platforms which only
+    /// deliver converted frames.
+    pub fn write_frame(&mut self, frame: &Frame) -> io::Result<()> {
+        let data = frame.native().unwrap_or_else(|| frame.data());
+        self.write(
+            data.format().fourcc(),
+            data.data_u8(),
+            data.size_u32(),
+            data.stride(),
+            frame.colorimetry(),
+            frame.sequence(),
+            frame.timestamp(),
+        )
+    }
+
+    pub fn write_frame_buf(&mut self, frame: &FrameBuf) -> io::Result<()> {
+        self.write(
+            frame.format().fourcc(),
+            frame.data_u8(),
+            frame.size_u32(),
+            frame.stride(),
+            Colorimetry::default(),
+            None,
+            frame.timestamp(),
+        )
+    }
+
+    pub fn write_record(&mut self, record: &Record) -> io::Result<()> {
+        self.write(
+            record.fourcc,
+            &record.data,
+            record.size,
+            record.stride,
+            record.colorimetry,
+            record.sequence,
+            record.timestamp,
+        )
+    }
+
+    /// Waits for `frames` frames of the started camera and writes them.
+    pub fn record(&mut self, camera: &Camera, frames: usize) -> io::Result<()> {
+        for _ in 0..frames {
+            let frame = camera
+                .wait_for_frame()
+                .ok_or_else(|| io::Error::other("camera delivered no frame"))?;
+            self.write_frame(&frame)?;
+        }
+        Ok(())
+    }
+
+    /// Flushes and returns the underlying writer.
+    pub fn into_inner(mut self) -> io::Result<W> {
+        self.out.flush()?;
+        Ok(self.out)
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn write(
+        &mut self,
+        fourcc: [u8; 4],
+        data: &[u8],
+        (w, h): (u32, u32),
+        stride: usize,
+        colorimetry: Colorimetry,
+        sequence: Option<u32>,
+        timestamp: Duration,
+    ) -> io::Result<()> {
+        let too_large = || io::Error::new(io::ErrorKind::InvalidInput, "too large for raw");
+        let len: u32 = data.len().try_into().map_err(|_| too_large())?;
+        let stride: u32 = stride.try_into().map_err(|_| too_large())?;
+        let flags = if sequence.is_some() { HAS_SEQUENCE } else { 0 };
+
+        let mut header = Vec::with_capacity(8 + HEADER_LEN as usize);
+        header.extend(b"FRAM");
+        header.extend(HEADER_LEN.to_le_bytes());
+        header.extend(fourcc);
+        for value in [
+            w,
+            h,
+            stride,
+            colorimetry.colorspace,
+            colorimetry.transfer,
+            colorimetry.quantization,
+            flags,
+            sequence.unwrap_or(0),
+        ] {
+            header.extend(value.to_le_bytes());
+        }
+        header.extend((timestamp.as_nanos() as u64).to_le_bytes());
+        header.extend(len.to_le_bytes());
+        self.out.write_all(&header)?;
+        self.out.write_all(data)
+    }
+}
+
+pub struct Reader<R: Read = BufReader<File>> {
+    input: R,
+}
+
+impl Reader {
+    pub fn open(path: impl AsRef<Path>) -> io::Result<Self> {
+        Self::new(BufReader::new(File::open(path)?))
+    }
+}
+
+impl<R: Read> Reader<R> {
+    /// Checks the file header.
+    pub fn new(mut input: R) -> io::Result<Self> {
+        let mut magic = [0; 8];
+        input.read_exact(&mut magic)?;
+        if &magic != MAGIC {
+            return Err(invalid_data("no kamera raw file"));
+        }
+        Ok(Self { input })
+    }
+
+    /// The next record, `None` at the end of the file.
+    pub fn read_record(&mut self) -> io::Result<Option<Record>> {
+        let mut tag = [0; 4];
+        match self.input.read_exact(&mut tag) {
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+            result => result?,
+        }
+        if &tag != b"FRAM" {
+            return Err(invalid_data("no FRAM record"));
+        }
+        let header_len = self.read_u32()?;
+        if header_len < HEADER_LEN {
+            return Err(invalid_data("record header too short"));
+        }
+        if header_len > MAX_HEADER_LEN {
+            return Err(invalid_data("record header too long"));
+        }
+        let mut header = vec![0; header_len as usize];
+        self.input.read_exact(&mut header)?;
+        let u32_at = |pos: usize| u32::from_le_bytes(header[pos..pos + 4].try_into().unwrap());
+
+        let flags = u32_at(28);
+        let nanos = u64::from_le_bytes(header[36..44].try_into().unwrap());
+        // grows with what is read, a broken length doesn't allocate more than the file has
+        let len = u32_at(44) as u64;
+        let mut data = Vec::new();
+        (&mut self.input).take(len).read_to_end(&mut data)?;
+        if (data.len() as u64) < len {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        Ok(Some(Record {
+            fourcc: header[..4].try_into().unwrap(),
+            size: (u32_at(4), u32_at(8)),
+            stride: u32_at(12) as usize,
+            colorimetry: Colorimetry {
+                colorspace: u32_at(16),
+                transfer: u32_at(20),
+                quantization: u32_at(24),
+            },
+            sequence: (flags & HAS_SEQUENCE != 0).then(|| u32_at(32)),
+            timestamp: Duration::from_nanos(nanos),
+            data,
+        }))
+    }
+
+    fn read_u32(&mut self) -> io::Result<u32> {
+        let mut bytes = [0; 4];
+        self.input.read_exact(&mut bytes)?;
+        Ok(u32::from_le_bytes(bytes))
+    }
+}
+
+impl<R: Read> Iterator for Reader<R> {
+    type Item = io::Result<Record>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.read_record().transpose()
+    }
+}
+
+fn invalid_data(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(sequence: Option<u32>) -> Record {
+        Record {
+            fourcc: *b"YUYV",
+            size: (2, 2),
+            stride: 6,
+            colorimetry: Colorimetry { colorspace: 1, transfer: 2, quantization: 3 },
+            sequence,
+            timestamp: Duration::new(12, 345),
+            data: (0..12).collect(),
+        }
+    }
+
+    #[test]
+    fn records_round_trip() {
+        let mut writer = Writer::new(Vec::new()).unwrap();
+        writer.write_record(&record(Some(7))).unwrap();
+        writer.write_record(&record(None)).unwrap();
+        let file = writer.into_inner().unwrap();
+        assert!(file.starts_with(MAGIC));
+
+        let records: Vec<_> = Reader::new(file.as_slice()).unwrap().map(Result::unwrap).collect();
+        assert_eq!(records, [record(Some(7)), record(None)]);
+        let frame = records[0].to_frame_buf().unwrap();
+        assert_eq!((frame.format(), frame.stride()), (PixelFormat::Yuyv, 6));
+    }
+
+    #[test]
+    fn skips_unknown_header_fields() {
+        let mut file = MAGIC.to_vec();
+        file.extend(b"FRAM");
+        file.extend((HEADER_LEN + 4).to_le_bytes());
+        file.extend(b"GREY");
+        for value in [1, 1, 1, 0, 0, 0, 0, 0] {
+            file.extend(u32::to_le_bytes(value));
+        }
+        file.extend(0u64.to_le_bytes());
+        file.extend(1u32.to_le_bytes());
+        file.extend(b"new!");
+        file.push(42);
+
+        let mut reader = Reader::new(file.as_slice()).unwrap();
+        assert_eq!(reader.read_record().unwrap().unwrap().data, [42]);
+        assert!(reader.read_record().unwrap().is_none());
+        assert!(Reader::new(&b"KAMRAW02"[..]).is_err());
+    }
+
+    #[test]
+    fn rejects_broken_lengths() {
+        let mut writer = Writer::new(Vec::new()).unwrap();
+        writer.write_record(&record(None)).unwrap();
+        let file = writer.into_inner().unwrap();
+        let read = |at: usize| {
+            let mut file = file.clone();
+            file[at..at + 4].copy_from_slice(&u32::MAX.to_le_bytes());
+            Reader::new(file.as_slice()).unwrap().read_record().unwrap_err().kind()
+        };
+        // the header length, then the data length in the header
+        assert_eq!(read(MAGIC.len() + 4), io::ErrorKind::InvalidData);
+        assert_eq!(read(MAGIC.len() + 8 + 44), io::ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn rejects_short_records() {
+        let short = Record { size: (64, 48), stride: 128, data: vec![0; 100], ..record(None) };
+        assert_eq!(short.to_frame_buf().unwrap_err().kind(), io::ErrorKind::InvalidData);
+        let narrow = Record { stride: 2, ..record(None) };
+        assert_eq!(narrow.to_frame_buf().unwrap_err().kind(), io::ErrorKind::InvalidData);
+
+        let path = std::env::temp_dir().join(format!("kamera_short_{}.raw", std::process::id()));
+        let mut writer = Writer::create(&path).unwrap();
+        writer.write_record(&short).unwrap();
+        writer.into_inner().unwrap();
+        let camera = Camera::open_file(&path).unwrap();
+        camera.start();
+        assert!(camera.wait_for_frame().is_none());
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn replays_through_camera() {
+        let path = std::env::temp_dir().join(format!("kamera_raw_{}.raw", std::process::id()));
+        let mut writer = Writer::create(&path).unwrap();
+        writer.write_record(&record(Some(7))).unwrap();
+        writer.into_inner().unwrap();
+
+        let camera = Camera::open_file(&path).unwrap();
+        camera.start();
+        let frame = camera.wait_for_frame().unwrap();
+        assert_eq!(frame.sequence(), Some(7));
+        assert_eq!(frame.colorimetry().quantization, 3);
+        assert_eq!(frame.native().unwrap().data_u8(), record(None).data);
+        assert_eq!(frame.data().data_u8().len(), 2 * 2 * 4);
+        assert!(camera.wait_for_frame().is_none());
+        std::fs::remove_file(&path).unwrap();
+    }
+}
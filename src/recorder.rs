@@ -0,0 +1,267 @@
+//! Recording frames into MJPEG AVI files, see [Recorder].
+
+use std::fs::File;
+use std::io::{self, BufWriter, Seek, Write};
+use std::path::Path;
+use std::time::Duration;
+
+use crate::avi::AviWriter;
+use crate::{Camera, Frame, FrameBuf, FrameEncoder, JpegEncoder};
+
+/// Writes frames as an MJPEG video into an AVI file which common players can open.
+/// Files over 1 GB use the OpenDML extensions.
+///
+/// Frames the device already delivers as MJPEG are written untouched, others are encoded as
+/// JPEG, or with the encoder given to [Recorder::set_encoder]. The frame rate of the video is
+/// the average rate of the frame timestamps. The file is only complete after
+/// [Recorder::finish].
+pub struct Recorder<W: Write + Seek = BufWriter<File>> {
+    out: Option<W>,
+    avi: Option<AviWriter<W>>,
+    encoder: Box<dyn FrameEncoder>,
+}
+
+impl Recorder {
+    pub fn create(path: impl AsRef<Path>) -> io::Result<Self> {
+        Ok(Self::new(BufWriter::new(File::create(path)?)))
+    }
+}
+
+impl<W: Write + Seek> Recorder<W> {
+    pub fn new(out: W) -> Self {
+        Self { out: Some(out), avi: None, encoder: Box::new(JpegEncoder::default()) }
+    }
+
+    /// JPEG quality from 1 to 100 for frames which are not MJPEG already, 90 by default.
+    /// Replaces an encoder given to [Recorder::set_encoder] before the first frame, and is
+    /// ignored after it when recording with another codec.
+    pub fn set_quality(&mut self, quality: u8) {
+        let encoder = JpegEncoder::new(quality);
+        if self.avi.is_none() || self.encoder.fourcc() == encoder.fourcc() {
+            self.encoder = Box::new(encoder);
+        }
+    }
+
+    /// Encodes the frames with `encoder`, the AVI codec is its [FrameEncoder::fourcc].
+    /// Fails after the first frame, the codec of the file is fixed by then.
+    pub fn set_encoder(&mut self, encoder: impl FrameEncoder + 'static) -> io::Result<()> {
+        if self.avi.is_some() {
+            let msg = "encoder set after the first frame";
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, msg));
+        }
+        self.encoder = Box::new(encoder);
+        Ok(())
+    }
+
+    /// Number of frames written so far.
+    pub fn frames(&self) -> u32 {
+        self.avi.as_ref().map_or(0, |avi| avi.frames())
+    }
+
+    /// All frames must have the size of the first one.
+    pub fn write_frame(&mut self, frame: &Frame) -> io::Result<()> {
+        self.check_size(frame.size_u32())?;
+        let data = frame.encode_with(&mut *self.encoder)?;
+        self.write(&data, frame.size_u32(), frame.timestamp())
+    }
+
+    /// All frames must have the size of the first one.
+    pub fn write_frame_buf(&mut self, frame: &FrameBuf) -> io::Result<()> {
+        self.check_size(frame.size_u32())?;
+        let data = frame.encode_with(&mut *self.encoder)?;
+        self.write(&data, frame.size_u32(), frame.timestamp())
+    }
+
+    /// Waits for `frames` frames of the started camera and writes them.
+    pub fn record(&mut self, camera: &Camera, frames: usize) -> io::Result<()> {
+        for _ in 0..frames {
+            let frame = camera
+                .wait_for_frame()
+                .ok_or_else(|| io::Error::other("camera delivered no frame"))?;
+            self.write_frame(&frame)?;
+        }
+        Ok(())
+    }
+
+    /// Writes the indexes and headers and returns the underlying writer.
+    pub fn finish(mut self) -> io::Result<W> {
+        match self.avi.take() {
+            Some(avi) => avi.finish(),
+            None => {
+                let fourcc = self.encoder.fourcc();
+                AviWriter::new(self.take_out()?, (0, 0), fourcc)?.finish()
+            }
+        }
+    }
+
+    fn check_size(&self, size: (u32, u32)) -> io::Result<()> {
+        match &self.avi {
+            Some(avi) if avi.size() != size => {
+                let msg = format!("frame size {size:?} differs from video size {:?}", avi.size());
+                Err(io::Error::new(io::ErrorKind::InvalidInput, msg))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn write(&mut self, data: &[u8], size: (u32, u32), timestamp: Duration) -> io::Result<()> {
+        if self.avi.is_none() {
+            let avi = AviWriter::new(self.take_out()?, size, self.encoder.fourcc())?;
+            self.avi = Some(avi);
+        }
+        self.avi.as_mut().unwrap().write_frame(data, timestamp)
+    }
+
+    /// The writer for the headers, gone after writing them failed.
+    fn take_out(&mut self) -> io::Result<W> {
+        self.out.take().ok_or_else(|| io::Error::other("recorder failed earlier"))
+    }
+}
+
+impl<W: Write + Seek> std::fmt::Debug for Recorder<W> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Recorder").field("frames", &self.frames()).finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{PixelFormat, QoiEncoder};
+    use std::io::Cursor;
+
+    fn u32_at(data: &[u8], pos: usize) -> u32 {
+        u32::from_le_bytes(data[pos..pos + 4].try_into().unwrap())
+    }
+
+    /// Top level RIFFs as (form type, offset of contents, length of contents).
+    fn riffs(avi: &[u8]) -> Vec<(&[u8], usize, usize)> {
+        let mut pos = 0;
+        let mut riffs = Vec::new();
+        while pos < avi.len() {
+            assert_eq!(&avi[pos..pos + 4], b"RIFF");
+            let len = u32_at(avi, pos + 4) as usize;
+            riffs.push((&avi[pos + 8..pos + 12], pos + 12, len - 4));
+            pos += 8 + len;
+        }
+        riffs
+    }
+
+    fn frame(i: u8) -> FrameBuf {
+        let gray = vec![i * 20; 8 * 6];
+        FrameBuf::new(&gray, (8, 6), PixelFormat::Gray, 8, Duration::from_millis(i as u64 * 40))
+    }
+
+    #[test]
+    fn records_mjpeg_avi() {
+        let mut recorder = Recorder::new(Cursor::new(Vec::new()));
+        for i in 0..5 {
+            recorder.write_frame_buf(&frame(i)).unwrap();
+        }
+        let jpeg = b"\xFF\xD8 not decoded \xFF\xD9";
+        let mjpeg = FrameBuf::new(jpeg, (8, 6), PixelFormat::Mjpeg, 0, Duration::from_millis(200));
+        recorder.write_frame_buf(&mjpeg).unwrap();
+        let avi = recorder.finish().unwrap().into_inner();
+
+        let riffs = riffs(&avi);
+        assert_eq!(riffs.len(), 1);
+        assert_eq!(riffs[0].0, b"AVI ");
+        let pos = |tag: &[u8]| avi.windows(4).position(|w| w == tag).unwrap();
+        assert_eq!(u32_at(&avi, pos(b"avih") + 8), 40_000);
+        assert_eq!(u32_at(&avi, pos(b"avih") + 8 + 16), 6);
+        // dwScale, dwRate and dwLength
+        let strh = pos(b"strh") + 8;
+        assert_eq!(&avi[strh..strh + 8], b"vidsMJPG");
+        assert_eq!(u32_at(&avi, strh + 20), 40_000);
+        assert_eq!(u32_at(&avi, strh + 24), 1_000_000);
+        assert_eq!(u32_at(&avi, strh + 32), 6);
+        assert_eq!(u32_at(&avi, pos(b"dmlh") + 8), 6);
+        assert_eq!(u32_at(&avi, pos(b"idx1") + 4), 6 * 16);
+        assert!(avi.windows(jpeg.len()).any(|w| w == jpeg));
+
+        let movi = pos(b"movi");
+        let first = u32_at(&avi, pos(b"idx1") + 8 + 8) as usize;
+        assert_eq!(&avi[movi + first..movi + first + 4], b"00dc");
+        assert_eq!(&avi[movi + first + 8..movi + first + 10], b"\xFF\xD8");
+    }
+
+    #[test]
+    fn splits_into_avix_riffs() {
+        let mut recorder = Recorder::new(Cursor::new(Vec::new()));
+        recorder.write_frame_buf(&frame(0)).unwrap();
+        recorder.avi.as_mut().unwrap().riff_limit = 6000;
+        for i in 1..40 {
+            recorder.write_frame_buf(&frame(i % 10)).unwrap();
+        }
+        let avi = recorder.finish().unwrap().into_inner();
+
+        let riffs = riffs(&avi);
+        assert!(riffs.len() > 2);
+        assert!(riffs[1..].iter().all(|riff| riff.0 == b"AVIX"));
+        assert!(riffs.iter().all(|riff| riff.2 <= 6000));
+
+        // super index entries point at ix00 chunks whose frame counts add up
+        let indx = avi.windows(4).position(|w| w == b"indx").unwrap() + 8;
+        assert_eq!(u32_at(&avi, indx + 4) as usize, riffs.len());
+        let mut frames = 0;
+        for entry in 0..riffs.len() {
+            let entry = indx + 24 + entry * 16;
+            let offset = u64::from_le_bytes(avi[entry..entry + 8].try_into().unwrap()) as usize;
+            assert_eq!(&avi[offset..offset + 4], b"ix00");
+            assert_eq!(u32_at(&avi, offset + 12), u32_at(&avi, entry + 12));
+            frames += u32_at(&avi, entry + 12);
+        }
+        assert_eq!(frames, 40);
+        assert_eq!(u32_at(&avi, avi.windows(4).position(|w| w == b"dmlh").unwrap() + 8), 40);
+    }
+
+    /// Fails every write like a full disk.
+    struct Full;
+
+    impl Write for Full {
+        fn write(&mut self, _buf: &[u8]) -> io::Result<usize> {
+            Err(io::ErrorKind::StorageFull.into())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Seek for Full {
+        fn seek(&mut self, _pos: io::SeekFrom) -> io::Result<u64> {
+            Ok(0)
+        }
+    }
+
+    #[test]
+    fn fails_again_after_a_write_error() {
+        let mut recorder = Recorder::new(Full);
+        let kind = recorder.write_frame_buf(&frame(0)).unwrap_err().kind();
+        assert_eq!(kind, io::ErrorKind::StorageFull);
+        assert!(recorder.write_frame_buf(&frame(1)).is_err());
+        assert!(recorder.finish().is_err());
+    }
+
+    #[test]
+    fn rejects_other_frame_sizes() {
+        let mut recorder = Recorder::new(Cursor::new(Vec::new()));
+        recorder.write_frame_buf(&frame(0)).unwrap();
+        let small = FrameBuf::new(&[0; 4], (2, 2), PixelFormat::Gray, 2, Duration::ZERO);
+        assert!(recorder.write_frame_buf(&small).is_err());
+    }
+
+    #[test]
+    fn records_with_other_encoders() {
+        let mut recorder = Recorder::new(Cursor::new(Vec::new()));
+        recorder.set_encoder(QoiEncoder).unwrap();
+        recorder.write_frame_buf(&frame(1)).unwrap();
+        assert!(recorder.set_encoder(JpegEncoder::default()).is_err());
+        recorder.set_quality(50);
+        recorder.write_frame_buf(&frame(2)).unwrap();
+        let avi = recorder.finish().unwrap().into_inner();
+        let strh = avi.windows(4).position(|w| w == b"strh").unwrap() + 8;
+        assert_eq!(&avi[strh..strh + 8], b"vidsQOIF");
+        assert_eq!(avi.windows(8).filter(|w| w == b"qoif\0\0\0\x08").count(), 2);
+    }
+}
@@ -0,0 +1,474 @@
+//! Recorded footage played back through the [Camera] API, see [Camera::open_file].
+
+use std::fs::File;
+use std::io::{self, BufReader, Read};
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, OnceLock};
+use std::time::{Duration, Instant};
+
+use crate::avi::AviReader;
+use crate::{convert, raw, y4m, Camera, Colorimetry, Frame, FrameBuf, PixelFormat};
+
+/// How fast a replayed file delivers frames.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Pacing {
+    /// [Camera::wait_for_frame] blocks until the frame is due according to its timestamp,
+    /// like a camera would.
+    #[default]
+    RealTime,
+    /// Every call returns the next frame right away.
+    AsFastAsPossible,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct ReplayOptions {
+    pub pacing: Pacing,
+    /// Starts over at the end of the file instead of returning `None`. Timestamps keep
+    /// increasing.
+    pub looping: bool,
+}
+
+/// Frames at 30 fps for image sequences, which have no timing of their own.
+const IMAGE_FRAME_DURATION: Duration = Duration::from_nanos(1_000_000_000 / 30);
+
+impl Camera {
+    /// Plays a recording instead of a device: a Y4M file, an MJPEG AVI, a [raw] capture or a
+    /// directory of numbered PNG or JPEG images. Frames are delivered in real time without
+    /// looping.
+    pub fn open_file(path: impl AsRef<Path>) -> io::Result<Self> {
+        Self::open_file_with(path, ReplayOptions::default())
+    }
+
+    pub fn open_file_with(path: impl AsRef<Path>, options: ReplayOptions) -> io::Result<Self> {
+        Ok(Self::from_replay(ReplayCamera::new(open_source(path.as_ref())?, options)))
+    }
+}
+
+/// A sequence of frames in their native format which can start over.
+pub(crate) trait FrameSource: Send {
+    fn name(&self) -> String;
+    /// The next frame, `None` at the end.
+    fn next_frame(&mut self) -> io::Result<Option<OwnedFrame>>;
+    fn rewind(&mut self) -> io::Result<()>;
+}
+
+fn open_source(path: &Path) -> io::Result<Box<dyn FrameSource>> {
+    if path.is_dir() {
+        return Ok(Box::new(ImageSequence::new(path)?));
+    }
+    let mut magic = [0; 12];
+    let len = File::open(path)?.read(&mut magic)?;
+    match &magic[..len] {
+        [b'Y', b'U', b'V', b'4', b'M', b'P', b'E', b'G', b'2', ..] => {
+            Ok(Box::new(Y4mSource { path: path.to_owned(), reader: y4m::Reader::open(path)? }))
+        }
+        [b'R', b'I', b'F', b'F', _, _, _, _, b'A', b'V', b'I', b' '] => {
+            let reader = AviReader::new(BufReader::new(File::open(path)?))?;
+            Ok(Box::new(AviSource { path: path.to_owned(), reader, next: 0 }))
+        }
+        magic if magic.starts_with(raw::MAGIC) => {
+            Ok(Box::new(RawSource { path: path.to_owned(), reader: raw::Reader::open(path)? }))
+        }
+        _ => Err(io::Error::new(io::ErrorKind::InvalidData, "no Y4M, AVI or raw capture file")),
+    }
+}
+
+fn file_name(path: &Path) -> String {
+    path.file_name().unwrap_or(path.as_os_str()).to_string_lossy().to_string()
+}
+
+struct Y4mSource {
+    path: PathBuf,
+    reader: y4m::Reader,
+}
+
+impl FrameSource for Y4mSource {
+    fn name(&self) -> String {
+        file_name(&self.path)
+    }
+
+    fn next_frame(&mut self) -> io::Result<Option<OwnedFrame>> {
+        Ok(self.reader.read_frame()?.map(OwnedFrame::new))
+    }
+
+    fn rewind(&mut self) -> io::Result<()> {
+        self.reader = y4m::Reader::open(&self.path)?;
+        Ok(())
+    }
+}
+
+struct RawSource {
+    path: PathBuf,
+    reader: raw::Reader,
+}
+
+impl FrameSource for RawSource {
+    fn name(&self) -> String {
+        file_name(&self.path)
+    }
+
+    fn next_frame(&mut self) -> io::Result<Option<OwnedFrame>> {
+        let Some(record) = self.reader.read_record()? else { return Ok(None) };
+        let native = record.to_frame_buf()?;
+        let frame = OwnedFrame::new(native);
+        Ok(Some(OwnedFrame { sequence: record.sequence, colorimetry: record.colorimetry, ..frame }))
+    }
+
+    fn rewind(&mut self) -> io::Result<()> {
+        self.reader = raw::Reader::open(&self.path)?;
+        Ok(())
+    }
+}
+
+struct AviSource {
+    path: PathBuf,
+    reader: AviReader<BufReader<File>>,
+    next: usize,
+}
+
+impl FrameSource for AviSource {
+    fn name(&self) -> String {
+        file_name(&self.path)
+    }
+
+    fn next_frame(&mut self) -> io::Result<Option<OwnedFrame>> {
+        if self.next == self.reader.frames() {
+            return Ok(None);
+        }
+        let jpeg = self.reader.read_frame(self.next)?;
+        let timestamp = self.reader.timestamp(self.next);
+        self.next += 1;
+        let native = FrameBuf::new(&jpeg, self.reader.size(), PixelFormat::Mjpeg, 0, timestamp);
+        Ok(Some(OwnedFrame::new(native)))
+    }
+
+    fn rewind(&mut self) -> io::Result<()> {
+        self.next = 0;
+        Ok(())
+    }
+}
+
+/// PNG and JPEG files of a directory in the order of the numbers in their names.
+struct ImageSequence {
+    dir: PathBuf,
+    files: Vec<PathBuf>,
+    next: usize,
+}
+
+impl ImageSequence {
+    fn new(dir: &Path) -> io::Result<Self> {
+        let mut files = Vec::new();
+        for entry in std::fs::read_dir(dir)? {
+            let path = entry?.path();
+            let ext = path.extension().and_then(|e| e.to_str()).map(|e| e.to_ascii_lowercase());
+            if matches!(ext.as_deref(), Some("png" | "jpg" | "jpeg")) {
+                files.push(path);
+            }
+        }
+        files.sort_by_cached_key(|path| {
+            let name = file_name(path);
+            let digits: String = name.chars().filter(char::is_ascii_digit).collect();
+            (digits.parse::<u128>().ok(), name)
+        });
+        if files.is_empty() {
+            let msg = format!("no PNG or JPEG images in {dir:?}");
+            return Err(io::Error::new(io::ErrorKind::NotFound, msg));
+        }
+        Ok(Self { dir: dir.to_owned(), files, next: 0 })
+    }
+}
+
+impl FrameSource for ImageSequence {
+    fn name(&self) -> String {
+        file_name(&self.dir)
+    }
+
+    fn next_frame(&mut self) -> io::Result<Option<OwnedFrame>> {
+        let Some(path) = self.files.get(self.next) else { return Ok(None) };
+        let timestamp = IMAGE_FRAME_DURATION * self.next as u32;
+        self.next += 1;
+        let data = std::fs::read(path)?;
+        if data.starts_with(&[0xFF, 0xD8]) {
+            // decoding is left to whoever looks at the pixels, like for MJPEG cameras
+            let mut decoder = jpeg_decoder::Decoder::new(data.as_slice());
+            decoder.read_info().map_err(io::Error::other)?;
+            let info = decoder.info().unwrap();
+            let size = (info.width as u32, info.height as u32);
+            let native = FrameBuf::new(&data, size, PixelFormat::Mjpeg, 0, timestamp);
+            return Ok(Some(OwnedFrame::new(native)));
+        }
+        Ok(Some(OwnedFrame::new(decode_png(&data, timestamp)?)))
+    }
+
+    fn rewind(&mut self) -> io::Result<()> {
+        self.next = 0;
+        Ok(())
+    }
+}
+
+fn decode_png(data: &[u8], timestamp: Duration) -> io::Result<FrameBuf> {
+    let mut decoder = png::Decoder::new(data);
+    decoder.set_transformations(png::Transformations::EXPAND | png::Transformations::STRIP_16);
+    let mut reader = decoder.read_info().map_err(io::Error::other)?;
+    let mut pixels = vec![0; reader.output_buffer_size()];
+    let info = reader.next_frame(&mut pixels).map_err(io::Error::other)?;
+    let size = (info.width, info.height);
+    let format = match info.color_type {
+        png::ColorType::Grayscale => PixelFormat::Gray,
+        png::ColorType::Rgb => PixelFormat::Rgb,
+        png::ColorType::Rgba => PixelFormat::Rgba,
+        _ => {
+            // gray with alpha, the alpha is dropped
+            let gray: Vec<u8> = pixels[..info.buffer_size()].iter().step_by(2).copied().collect();
+            return Ok(FrameBuf::new(&gray, size, PixelFormat::Gray, size.0 as usize, timestamp));
+        }
+    };
+    Ok(FrameBuf::new(&pixels[..info.buffer_size()], size, format, info.line_size, timestamp))
+}
+
+pub(crate) struct ReplayCamera {
+    name: String,
+    options: ReplayOptions,
+    state: Mutex<State>,
+}
+
+struct State {
+    source: Box<dyn FrameSource>,
+    /// Instant of timestamp zero while started.
+    clock: Option<Instant>,
+    /// Timestamp of the last frame delivered.
+    position: Duration,
+    /// Added to the source timestamps, grows with every loop.
+    offset: Duration,
+    /// Timestamp of the first frame of the current pass and of the previous frame.
+    first: Option<Duration>,
+    previous: Option<Duration>,
+    interval: Duration,
+}
+
+impl ReplayCamera {
+    pub(crate) fn new(source: Box<dyn FrameSource>, options: ReplayOptions) -> Self {
+        let state = State {
+            source,
+            clock: None,
+            position: Duration::ZERO,
+            offset: Duration::ZERO,
+            first: None,
+            previous: None,
+            interval: IMAGE_FRAME_DURATION,
+        };
+        Self { name: state.source.name(), options, state: Mutex::new(state) }
+    }
+
+    /// Continues where the last [ReplayCamera::stop] left off.
+    pub(crate) fn start(&self) {
+        let mut state = self.state.lock().unwrap();
+        if state.clock.is_none() {
+            let now = Instant::now();
+            state.clock = Some(now.checked_sub(state.position).unwrap_or(now));
+        }
+    }
+
+    pub(crate) fn stop(&self) {
+        self.state.lock().unwrap().clock = None;
+    }
+
+    /// `None` when stopped, at the end of a file without looping or on read errors.
+    pub(crate) fn wait_for_frame(&self) -> Option<OwnedFrame> {
+        let mut state = self.state.lock().unwrap();
+        let clock = state.clock?;
+        let mut frame = state.source.next_frame().ok()?;
+        if frame.is_none() && self.options.looping && state.previous.is_some() {
+            state.offset = state.position + state.interval;
+            (state.first, state.previous) = (None, None);
+            state.source.rewind().ok()?;
+            frame = state.source.next_frame().ok()?;
+        }
+        let mut frame = frame?;
+
+        let source_time = frame.native.timestamp();
+        if let Some(previous) = state.previous.filter(|&p| p < source_time) {
+            state.interval = source_time - previous;
+        }
+        state.previous = Some(source_time);
+        let first = *state.first.get_or_insert(source_time);
+        let timestamp = state.offset + source_time.saturating_sub(first);
+        state.position = timestamp;
+
+        // unlocked, so that stop() doesn't wait for the frame
+        drop(state);
+        if self.options.pacing == Pacing::RealTime {
+            let due = clock + timestamp;
+            std::thread::sleep(due.saturating_duration_since(Instant::now()));
+        }
+        frame.native = frame.native.with_timestamp(timestamp);
+        Some(frame)
+    }
+
+    pub(crate) fn device_name(&self) -> String {
+        self.name.clone()
+    }
+}
+
+impl std::fmt::Debug for ReplayCamera {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ReplayCamera").field("name", &self.name).finish()
+    }
+}
+
+/// A frame owning its native buffer, replayed from a file or shared between subscribers.
+/// Converted to BGRA on first access like the frames of devices.
+pub(crate) struct OwnedFrame {
+    native: FrameBuf,
+    bgra: OnceLock<Vec<u8>>,
+    sequence: Option<u32>,
+    colorimetry: Colorimetry,
+}
+
+impl OwnedFrame {
+    pub(crate) fn new(native: FrameBuf) -> Self {
+        Self { native, bgra: OnceLock::new(), sequence: None, colorimetry: Colorimetry::default() }
+    }
+
+    pub(crate) fn with_sequence(self, sequence: u32) -> Self {
+        Self { sequence: Some(sequence), ..self }
+    }
+
+    /// Copies the [Frame::native] buffer, or the converted one where there is none.
+    pub(crate) fn copy_of(frame: &Frame) -> Self {
+        let data = frame.native().unwrap_or_else(|| frame.data());
+        let (size, format, stride) = (data.size_u32(), data.format(), data.stride());
+        let native = FrameBuf::new(data.data_u8(), size, format, stride, frame.timestamp());
+        Self { sequence: frame.sequence(), colorimetry: frame.colorimetry(), ..Self::new(native) }
+    }
+
+    /// BGRA pixels and their stride.
+    pub(crate) fn data(&self) -> (&[u8], usize) {
+        let native = &self.native;
+        if native.format() == PixelFormat::Bgra {
+            return (native.data_u8(), native.stride());
+        }
+        let bgra = self.bgra.get_or_init(|| {
+            let (data, size) = (native.data_u8(), native.size_u32());
+            convert::to_bgra(data, size, native.format(), native.stride())
+        });
+        (bgra, native.size_u32().0 as usize * 4)
+    }
+
+    pub(crate) fn native(&self) -> &FrameBuf {
+        &self.native
+    }
+
+    pub(crate) fn sequence(&self) -> Option<u32> {
+        self.sequence
+    }
+
+    pub(crate) fn colorimetry(&self) -> Colorimetry {
+        self.colorimetry
+    }
+}
+
+impl std::fmt::Debug for OwnedFrame {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("OwnedFrame").field("native", &self.native).finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{ImageFormat, Recorder};
+
+    fn temp_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("kamera_replay_{}_{name}", std::process::id()))
+    }
+
+    fn gray(value: u8) -> FrameBuf {
+        FrameBuf::new(&[value; 16], (4, 4), PixelFormat::Gray, 4, Duration::ZERO)
+    }
+
+    fn as_fast_as_possible(looping: bool) -> ReplayOptions {
+        ReplayOptions { pacing: Pacing::AsFastAsPossible, looping }
+    }
+
+    #[test]
+    fn replays_y4m_with_looping() {
+        let path = temp_path("loop.y4m");
+        let header = y4m::Header::new((4, 4), y4m::Chroma::Mono, (10, 1));
+        let mut writer = y4m::Writer::create(&path, header).unwrap();
+        for value in [50, 100] {
+            writer.write_frame_buf(&gray(value)).unwrap();
+        }
+        writer.into_inner().unwrap();
+
+        let camera = Camera::open_file_with(&path, as_fast_as_possible(true)).unwrap();
+        assert!(camera.wait_for_frame().is_none(), "not started");
+        camera.start();
+        let frames: Vec<_> = (0..5).map(|_| camera.wait_for_frame().unwrap()).collect();
+        let timestamps: Vec<_> = frames.iter().map(|f| f.timestamp().as_millis()).collect();
+        assert_eq!(timestamps, [0, 100, 200, 300, 400]);
+        assert_eq!(frames[2].native().unwrap().data_u8(), [50; 16]);
+        assert_eq!(frames[3].data().format(), PixelFormat::Bgra);
+        assert_eq!(camera.device_name(), file_name(&path));
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn replays_avi_in_real_time() {
+        let path = temp_path("real_time.avi");
+        let mut recorder = Recorder::create(&path).unwrap();
+        for i in 0..3 {
+            let jpeg = gray(i * 50).encode(ImageFormat::Jpeg { quality: 90 }, &Default::default());
+            let frame = FrameBuf::new(
+                &jpeg.unwrap(),
+                (4, 4),
+                PixelFormat::Mjpeg,
+                0,
+                Duration::from_millis(i as u64 * 50),
+            );
+            recorder.write_frame_buf(&frame).unwrap();
+        }
+        recorder.finish().unwrap();
+
+        let camera = Camera::open_file(&path).unwrap();
+        camera.start();
+        let start = Instant::now();
+        let frames: Vec<_> = std::iter::from_fn(|| camera.wait_for_frame()).collect();
+        assert!(start.elapsed() >= Duration::from_millis(100));
+        assert_eq!(frames.len(), 3);
+        assert_eq!(frames[2].timestamp(), Duration::from_millis(100));
+        assert_eq!(frames[0].native().unwrap().format(), PixelFormat::Mjpeg);
+        assert_eq!(frames[1].data().size_u32(), (4, 4));
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn rejects_truncated_avi_header() {
+        let path = temp_path("truncated.avi");
+        // the RIFF chunk ends 2 bytes into the header list
+        let mut avi = [b"RIFF".as_slice(), &14u32.to_le_bytes(), b"AVI LIST"].concat();
+        avi.extend([&100u32.to_le_bytes()[..], b"hdrl", &[0; 8]].concat());
+        std::fs::write(&path, avi).unwrap();
+        let err = Camera::open_file(&path).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn replays_numbered_images() {
+        let dir = temp_path("images");
+        std::fs::create_dir_all(&dir).unwrap();
+        for i in [1, 2, 10] {
+            gray(i).save(dir.join(format!("frame{i}.png"))).unwrap();
+        }
+        std::fs::write(dir.join("notes.txt"), "not an image").unwrap();
+
+        let camera = Camera::open_file_with(&dir, as_fast_as_possible(false)).unwrap();
+        camera.start();
+        let frames: Vec<_> = std::iter::from_fn(|| camera.wait_for_frame()).collect();
+        let values: Vec<_> = frames.iter().map(|f| f.native().unwrap().data_u8()[0]).collect();
+        assert_eq!(values, [1, 2, 10]);
+        assert_eq!(frames[1].native().unwrap().format(), PixelFormat::Gray);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,713 @@
+//! A minimal RTSP server streaming RTP/JPEG (RFC 2435), enabled with the `rtsp` feature.
+//! VLC, ffmpeg and NVRs can play `rtsp://host:port/` with RTP over UDP or interleaved in the
+//! RTSP connection.
+//!
+//! ```no_run
+//! use kamera::{rtsp, Camera};
+//!
+//! let camera = Camera::new_default_device();
+//! let server = rtsp::Server::bind("0.0.0.0:8554").unwrap();
+//! server.run(&camera).unwrap(); // until a StopHandle stops it
+//! ```
+//!
+//! RTP/JPEG carries baseline 4:2:0 or 4:2:2 JPEGs up to 2040x2040 pixels with the standard
+//! Huffman tables. MJPEG from the camera is passed through when it fits, everything else is
+//! encoded. Unlike the `http` server there is no `set_encoder`, since the payload
+//! format only carries such JPEGs.
+
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs, UdpSocket};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+
+use crate::broadcast::Broadcast;
+use crate::{convert, Camera, Frame, PixelFormat};
+
+/// RTP packets stay below the usual MTU of 1500 bytes with IP and UDP headers.
+const MAX_PACKET: usize = 1400;
+const PAYLOAD_TYPE: u8 = 26;
+/// Longer request lines or more headers drop the connection.
+const MAX_LINE: u64 = 8192;
+const MAX_HEADERS: usize = 64;
+/// RTP/JPEG uses a 90 kHz clock.
+const CLOCK_RATE: u128 = 90_000;
+
+/// The typical Huffman tables of the JPEG standard, K.3, as a DHT segment. RTP/JPEG can't
+/// carry others, receivers decode every frame with these.
+const STANDARD_HUFFMAN: [u8; 416] = [
+    0x00, 0x00, 0x01, 0x05, 0x01, 0x01, 0x01, 0x01, 0x01, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+    0x00, 0x00, 0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07, 0x08, 0x09, 0x0A, 0x0B, 0x10, 0x00, 0x02,
+    0x01, 0x03, 0x03, 0x02, 0x04, 0x03, 0x05, 0x05, 0x04, 0x04, 0x00, 0x00, 0x01, 0x7D, 0x01, 0x02,
+    0x03, 0x00, 0x04, 0x11, 0x05, 0x12, 0x21, 0x31, 0x41, 0x06, 0x13, 0x51, 0x61, 0x07, 0x22, 0x71,
+    0x14, 0x32, 0x81, 0x91, 0xA1, 0x08, 0x23, 0x42, 0xB1, 0xC1, 0x15, 0x52, 0xD1, 0xF0, 0x24, 0x33,
+    0x62, 0x72, 0x82, 0x09, 0x0A, 0x16, 0x17, 0x18, 0x19, 0x1A, 0x25, 0x26, 0x27, 0x28, 0x29, 0x2A,
+    0x34, 0x35, 0x36, 0x37, 0x38, 0x39, 0x3A, 0x43, 0x44, 0x45, 0x46, 0x47, 0x48, 0x49, 0x4A, 0x53,
+    0x54, 0x55, 0x56, 0x57, 0x58, 0x59, 0x5A, 0x63, 0x64, 0x65, 0x66, 0x67, 0x68, 0x69, 0x6A, 0x73,
+    0x74, 0x75, 0x76, 0x77, 0x78, 0x79, 0x7A, 0x83, 0x84, 0x85, 0x86, 0x87, 0x88, 0x89, 0x8A, 0x92,
+    0x93, 0x94, 0x95, 0x96, 0x97, 0x98, 0x99, 0x9A, 0xA2, 0xA3, 0xA4, 0xA5, 0xA6, 0xA7, 0xA8, 0xA9,
+    0xAA, 0xB2, 0xB3, 0xB4, 0xB5, 0xB6, 0xB7, 0xB8, 0xB9, 0xBA, 0xC2, 0xC3, 0xC4, 0xC5, 0xC6, 0xC7,
+    0xC8, 0xC9, 0xCA, 0xD2, 0xD3, 0xD4, 0xD5, 0xD6, 0xD7, 0xD8, 0xD9, 0xDA, 0xE1, 0xE2, 0xE3, 0xE4,
+    0xE5, 0xE6, 0xE7, 0xE8, 0xE9, 0xEA, 0xF1, 0xF2, 0xF3, 0xF4, 0xF5, 0xF6, 0xF7, 0xF8, 0xF9, 0xFA,
+    0x01, 0x00, 0x03, 0x01, 0x01, 0x01, 0x01, 0x01, 0x01, 0x01, 0x01, 0x01, 0x00, 0x00, 0x00, 0x00,
+    0x00, 0x00, 0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07, 0x08, 0x09, 0x0A, 0x0B, 0x11, 0x00, 0x02,
+    0x01, 0x02, 0x04, 0x04, 0x03, 0x04, 0x07, 0x05, 0x04, 0x04, 0x00, 0x01, 0x02, 0x77, 0x00, 0x01,
+    0x02, 0x03, 0x11, 0x04, 0x05, 0x21, 0x31, 0x06, 0x12, 0x41, 0x51, 0x07, 0x61, 0x71, 0x13, 0x22,
+    0x32, 0x81, 0x08, 0x14, 0x42, 0x91, 0xA1, 0xB1, 0xC1, 0x09, 0x23, 0x33, 0x52, 0xF0, 0x15, 0x62,
+    0x72, 0xD1, 0x0A, 0x16, 0x24, 0x34, 0xE1, 0x25, 0xF1, 0x17, 0x18, 0x19, 0x1A, 0x26, 0x27, 0x28,
+    0x29, 0x2A, 0x35, 0x36, 0x37, 0x38, 0x39, 0x3A, 0x43, 0x44, 0x45, 0x46, 0x47, 0x48, 0x49, 0x4A,
+    0x53, 0x54, 0x55, 0x56, 0x57, 0x58, 0x59, 0x5A, 0x63, 0x64, 0x65, 0x66, 0x67, 0x68, 0x69, 0x6A,
+    0x73, 0x74, 0x75, 0x76, 0x77, 0x78, 0x79, 0x7A, 0x82, 0x83, 0x84, 0x85, 0x86, 0x87, 0x88, 0x89,
+    0x8A, 0x92, 0x93, 0x94, 0x95, 0x96, 0x97, 0x98, 0x99, 0x9A, 0xA2, 0xA3, 0xA4, 0xA5, 0xA6, 0xA7,
+    0xA8, 0xA9, 0xAA, 0xB2, 0xB3, 0xB4, 0xB5, 0xB6, 0xB7, 0xB8, 0xB9, 0xBA, 0xC2, 0xC3, 0xC4, 0xC5,
+    0xC6, 0xC7, 0xC8, 0xC9, 0xCA, 0xD2, 0xD3, 0xD4, 0xD5, 0xD6, 0xD7, 0xD8, 0xD9, 0xDA, 0xE2, 0xE3,
+    0xE4, 0xE5, 0xE6, 0xE7, 0xE8, 0xE9, 0xEA, 0xF2, 0xF3, 0xF4, 0xF5, 0xF6, 0xF7, 0xF8, 0xF9, 0xFA,
+];
+
+pub struct Server {
+    addr: SocketAddr,
+    broadcast: Arc<Broadcast>,
+    quality: u8,
+}
+
+/// Stops a running [Server] from another thread.
+#[derive(Clone)]
+pub struct StopHandle {
+    addr: SocketAddr,
+    broadcast: Arc<Broadcast>,
+}
+
+impl Server {
+    /// Accepts connections on a background thread right away, frames are captured while
+    /// [Server::run] runs.
+    pub fn bind(addr: impl ToSocketAddrs) -> io::Result<Self> {
+        let listener = TcpListener::bind(addr)?;
+        let addr = listener.local_addr()?;
+        let broadcast = Arc::new(Broadcast::new());
+        let accepting = broadcast.clone();
+        std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                if accepting.state().stopped {
+                    break;
+                }
+                let Ok(stream) = stream else { continue };
+                let broadcast = accepting.clone();
+                std::thread::spawn(move || {
+                    // errors only end this connection
+                    let _ = handle_connection(stream, broadcast);
+                });
+            }
+        });
+        Ok(Self { addr, broadcast, quality: 80 })
+    }
+
+    pub fn local_addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// JPEG quality from 1 to 100 for frames which can't be passed through, 80 by default.
+    pub fn set_quality(&mut self, quality: u8) {
+        self.quality = quality.clamp(1, 100);
+    }
+
+    pub fn stop_handle(&self) -> StopHandle {
+        StopHandle { addr: self.addr, broadcast: self.broadcast.clone() }
+    }
+
+    /// Captures frames for the playing sessions until stopped. Starts the camera when the
+    /// first session plays and stops it when the last one ends. The server stops as well when
+    /// the camera fails or its frames are too large for RTP/JPEG.
+    pub fn run(&self, camera: &Camera) -> io::Result<()> {
+        let result = self.broadcast.run(camera, |frame| rtp_jpeg(frame, self.quality));
+        self.stop_handle().stop();
+        result
+    }
+}
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        self.stop_handle().stop();
+    }
+}
+
+impl std::fmt::Debug for Server {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Server").field("addr", &self.addr).finish()
+    }
+}
+
+impl StopHandle {
+    /// Ends [Server::run], the sessions and the accepting of connections.
+    pub fn stop(&self) {
+        self.broadcast.stop();
+        // wakes up the accepting thread
+        let _ = TcpStream::connect(self.addr);
+    }
+}
+
+impl std::fmt::Debug for StopHandle {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("StopHandle").field("addr", &self.addr).finish()
+    }
+}
+
+/// A JPEG which RTP/JPEG can carry, the camera MJPEG if possible.
+fn rtp_jpeg(frame: &Frame, quality: u8) -> io::Result<Vec<u8>> {
+    let data = frame.native().unwrap_or_else(|| frame.data());
+    if data.format() == PixelFormat::Mjpeg && JpegScan::parse(data.data_u8()).is_some() {
+        return Ok(data.data_u8().to_vec());
+    }
+    let (w, h) = data.size_u32();
+    if w > 2040 || h > 2040 {
+        let msg = format!("{w}x{h} is too large for RTP/JPEG");
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, msg));
+    }
+    let rgb = convert::to_rgb(data.data_u8(), (w, h), data.format(), data.stride());
+    let mut jpeg = Vec::new();
+    let mut encoder = jpeg_encoder::Encoder::new(&mut jpeg, quality);
+    encoder.set_sampling_factor(jpeg_encoder::SamplingFactor::F_2_2);
+    encoder
+        .encode(&rgb, w as u16, h as u16, jpeg_encoder::ColorType::Rgb)
+        .map_err(io::Error::other)?;
+    Ok(jpeg)
+}
+
+/// The parts of a baseline JPEG which RTP/JPEG transmits.
+#[derive(Debug)]
+struct JpegScan<'a> {
+    /// 0 for 4:2:2, 1 for 4:2:0.
+    kind: u8,
+    size: (u16, u16),
+    restart_interval: u16,
+    /// The luma and the chroma quantization table in zigzag order.
+    tables: Vec<u8>,
+    /// Entropy coded data up to the end of image marker.
+    data: &'a [u8],
+}
+
+impl<'a> JpegScan<'a> {
+    fn parse(jpeg: &'a [u8]) -> Option<Self> {
+        if !jpeg.starts_with(&[0xFF, 0xD8]) {
+            return None;
+        }
+        let be16 = |data: &[u8], pos: usize| -> Option<u16> {
+            Some(u16::from_be_bytes(data.get(pos..pos + 2)?.try_into().ok()?))
+        };
+        let mut tables: [Option<&[u8]>; 4] = [None; 4];
+        let (mut kind, mut size, mut restart_interval) = (None, None, 0);
+        let mut pos = 2;
+        loop {
+            while jpeg.get(pos..pos + 2) == Some(&[0xFF, 0xFF]) {
+                pos += 1;
+            }
+            if *jpeg.get(pos)? != 0xFF {
+                return None;
+            }
+            let marker = *jpeg.get(pos + 1)?;
+            let len = be16(jpeg, pos + 2)? as usize;
+            let segment = jpeg.get(pos + 4..pos + 2 + len)?;
+            match marker {
+                // DQT
+                0xDB => {
+                    for table in segment.chunks(65) {
+                        // 16 bit tables are not supported
+                        if table[0] >> 4 != 0 || table.len() != 65 {
+                            return None;
+                        }
+                        tables[table[0] as usize & 3] = Some(&table[1..]);
+                    }
+                }
+                // baseline and extended sequential frames
+                0xC0 | 0xC1 => {
+                    let components = segment.get(6..15)?;
+                    if segment[0] != 8 || segment[5] != 3 {
+                        return None;
+                    }
+                    kind = match (components[1], components[4], components[7]) {
+                        (0x21, 0x11, 0x11) => Some(0),
+                        (0x22, 0x11, 0x11) => Some(1),
+                        _ => return None,
+                    };
+                    if (components[2], components[5], components[8]) != (0, 1, 1) {
+                        return None;
+                    }
+                    size = Some((be16(segment, 3)?, be16(segment, 1)?));
+                }
+                // progressive, lossless, arithmetic coding
+                0xC2 | 0xC3 | 0xC5..=0xC7 | 0xC9..=0xCB | 0xCD..=0xCF => return None,
+                // DHT, a JPEG with its own tables is encoded again
+                0xC4 => {
+                    let standard = huffman_tables(&STANDARD_HUFFMAN)?;
+                    let own = huffman_tables(segment)?;
+                    if !own.iter().all(|table| standard.contains(table)) {
+                        return None;
+                    }
+                }
+                // DRI
+                0xDD => restart_interval = be16(segment, 0)?,
+                // SOS
+                0xDA => {
+                    let start = pos + 2 + len;
+                    let end =
+                        if jpeg.ends_with(&[0xFF, 0xD9]) { jpeg.len() - 2 } else { jpeg.len() };
+                    let (w, h) = size?;
+                    if w > 2040 || h > 2040 {
+                        return None;
+                    }
+                    return Some(JpegScan {
+                        kind: kind?,
+                        size: (w, h),
+                        restart_interval,
+                        tables: [tables[0]?, tables[1]?].concat(),
+                        data: jpeg.get(start..end)?,
+                    });
+                }
+                _ => {}
+            }
+            pos += 2 + len;
+        }
+    }
+
+    /// RTP packets of the frame, the last one with the marker bit.
+    fn packets(&self, timestamp: u32, ssrc: u32, sequence: &mut u16) -> Vec<Vec<u8>> {
+        let mut packets = Vec::new();
+        let mut offset = 0;
+        loop {
+            let mut packet = Vec::with_capacity(MAX_PACKET);
+            packet.extend([0x80, PAYLOAD_TYPE]);
+            packet.extend(sequence.to_be_bytes());
+            packet.extend(timestamp.to_be_bytes());
+            packet.extend(ssrc.to_be_bytes());
+
+            let kind = if self.restart_interval > 0 { self.kind + 64 } else { self.kind };
+            let (w, h) = self.size;
+            packet.push(0); // type specific
+            packet.extend(&(offset as u32).to_be_bytes()[1..]);
+            // Q 255: the quantization tables follow in the first packet
+            packet.extend([kind, 255, w.div_ceil(8) as u8, h.div_ceil(8) as u8]);
+            if self.restart_interval > 0 {
+                packet.extend(self.restart_interval.to_be_bytes());
+                packet.extend([0xFF, 0xFF]); // first and last of all restart intervals
+            }
+            if offset == 0 {
+                packet.extend([0, 0]); // MBZ, 8 bit precision
+                packet.extend((self.tables.len() as u16).to_be_bytes());
+                packet.extend(&self.tables);
+            }
+
+            let end = (offset + MAX_PACKET - packet.len()).min(self.data.len());
+            packet.extend(&self.data[offset..end]);
+            offset = end;
+            *sequence = sequence.wrapping_add(1);
+            if offset == self.data.len() {
+                packet[1] |= 0x80;
+                packets.push(packet);
+                return packets;
+            }
+            packets.push(packet);
+        }
+    }
+}
+
+/// The tables of a DHT segment, each with its class and id, code counts and values.
+fn huffman_tables(mut segment: &[u8]) -> Option<Vec<&[u8]>> {
+    let mut tables = Vec::new();
+    while !segment.is_empty() {
+        let len = 17 + segment.get(1..17)?.iter().map(|&count| count as usize).sum::<usize>();
+        tables.push(segment.get(..len)?);
+        segment = &segment[len..];
+    }
+    Some(tables)
+}
+
+/// Where the RTP packets of a session go.
+#[derive(Clone)]
+enum Sink {
+    Udp(Arc<UdpSocket>, SocketAddr),
+    /// The RTSP connection and the interleaved channel.
+    Tcp(Arc<Mutex<TcpStream>>, u8),
+}
+
+impl Sink {
+    fn send(&self, packet: &[u8]) -> io::Result<()> {
+        match self {
+            Sink::Udp(socket, addr) => socket.send_to(packet, addr).map(|_| ()),
+            Sink::Tcp(stream, channel) => {
+                let mut frame = vec![b'$', *channel];
+                frame.extend((packet.len() as u16).to_be_bytes());
+                frame.extend(packet);
+                stream.lock().unwrap().write_all(&frame)
+            }
+        }
+    }
+}
+
+struct Request {
+    method: String,
+    url: String,
+    headers: Vec<(String, String)>,
+}
+
+impl Request {
+    /// The next request, skipping interleaved data of the client. `None` when the client
+    /// closed the connection.
+    fn read(reader: &mut impl BufRead) -> io::Result<Option<Self>> {
+        loop {
+            let buf = reader.fill_buf()?;
+            if buf.is_empty() {
+                return Ok(None);
+            }
+            if buf[0] != b'$' {
+                break;
+            }
+            let mut header = [0; 4];
+            reader.read_exact(&mut header)?;
+            let len = u16::from_be_bytes([header[2], header[3]]) as u64;
+            io::copy(&mut reader.take(len), &mut io::sink())?;
+        }
+
+        let mut line = String::new();
+        read_line(reader, &mut line)?;
+        let mut parts = line.split_whitespace();
+        let (method, url) = (parts.next().unwrap_or("").to_string(), parts.next().unwrap_or(""));
+        let mut request = Request { method, url: url.to_string(), headers: Vec::new() };
+        loop {
+            line.clear();
+            if read_line(reader, &mut line)? == 0 || line.trim().is_empty() {
+                break;
+            }
+            if request.headers.len() == MAX_HEADERS {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, "too many headers"));
+            }
+            if let Some((name, value)) = line.split_once(':') {
+                request.headers.push((name.trim().to_ascii_lowercase(), value.trim().to_string()));
+            }
+        }
+        let body_len = request.header("content-length").and_then(|len| len.parse().ok());
+        io::copy(&mut reader.take(body_len.unwrap_or(0)), &mut io::sink())?;
+        Ok(Some(request))
+    }
+
+    fn header(&self, name: &str) -> Option<&str> {
+        self.headers.iter().find(|(n, _)| n == name).map(|(_, value)| value.as_str())
+    }
+}
+
+/// Like [BufRead::read_line], failing after [MAX_LINE] bytes without a newline.
+fn read_line(reader: &mut impl BufRead, line: &mut String) -> io::Result<usize> {
+    let len = Read::take(reader, MAX_LINE).read_line(line)?;
+    if len as u64 == MAX_LINE && !line.ends_with('\n') {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "line too long"));
+    }
+    Ok(len)
+}
+
+fn random_u32() -> u32 {
+    RandomState::new().build_hasher().finish() as u32
+}
+
+fn handle_connection(stream: TcpStream, broadcast: Arc<Broadcast>) -> io::Result<()> {
+    let local_ip = stream.local_addr()?.ip();
+    let peer_ip = stream.peer_addr()?.ip();
+    let writer = Arc::new(Mutex::new(stream.try_clone()?));
+    let mut reader = BufReader::new(stream);
+    let session = format!("{:08X}", random_u32());
+    let ssrc = random_u32();
+    let mut sink = None;
+    let mut playing: Option<Arc<AtomicBool>> = None;
+
+    let result = loop {
+        let Some(request) = Request::read(&mut reader)? else { break Ok(()) };
+        let cseq = request.header("cseq").unwrap_or("0").to_string();
+        let respond = |status: &str, headers: &[(&str, String)], body: &str| {
+            let mut response = format!("RTSP/1.0 {status}\r\nCSeq: {cseq}\r\n");
+            for (name, value) in headers {
+                response.push_str(&format!("{name}: {value}\r\n"));
+            }
+            if !body.is_empty() {
+                response.push_str(&format!("Content-Length: {}\r\n", body.len()));
+            }
+            response.push_str("\r\n");
+            response.push_str(body);
+            writer.lock().unwrap().write_all(response.as_bytes())
+        };
+        let session_header = ("Session", format!("{session};timeout=60"));
+
+        match request.method.as_str() {
+            "OPTIONS" => {
+                let methods = "OPTIONS, DESCRIBE, SETUP, PLAY, TEARDOWN, GET_PARAMETER";
+                respond("200 OK", &[("Public", methods.to_string())], "")?
+            }
+            "DESCRIBE" => {
+                let ip_version = if local_ip.is_ipv4() { "IP4" } else { "IP6" };
+                let mut sdp = format!(
+                    "v=0\r\no=- {session} 1 IN {ip_version} {local_ip}\r\ns=kamera\r\n\
+                     c=IN {ip_version} {local_ip}\r\nt=0 0\r\na=control:*\r\n\
+                     m=video 0 RTP/AVP {PAYLOAD_TYPE}\r\na=rtpmap:{PAYLOAD_TYPE} JPEG/90000\r\n\
+                     a=control:track0\r\n"
+                );
+                // known once frames were captured
+                let fps = broadcast.state().fps();
+                if fps > 0.0 {
+                    sdp.push_str(&format!("a=framerate:{fps:.2}\r\n"));
+                }
+                let base = format!("{}/", request.url.trim_end_matches('/'));
+                let headers = [("Content-Base", base), ("Content-Type", "application/sdp".into())];
+                respond("200 OK", &headers, &sdp)?
+            }
+            "SETUP" => {
+                let transport = request.header("transport").unwrap_or("");
+                let param = |name: &str| {
+                    let value = transport.split(';').find_map(|p| p.strip_prefix(name))?;
+                    let (first, _) = value.split_once('-').unwrap_or((value, ""));
+                    first.parse::<u16>().ok()
+                };
+                let reply = if transport.contains("RTP/AVP/TCP") {
+                    let channel = param("interleaved=").unwrap_or(0);
+                    sink = Some(Sink::Tcp(writer.clone(), channel as u8));
+                    format!(
+                        "RTP/AVP/TCP;unicast;interleaved={channel}-{};ssrc={ssrc:08X}",
+                        channel + 1
+                    )
+                } else if let Some(port) = param("client_port=") {
+                    let socket = UdpSocket::bind(SocketAddr::new(local_ip, 0))?;
+                    let server_port = socket.local_addr()?.port();
+                    sink = Some(Sink::Udp(Arc::new(socket), SocketAddr::new(peer_ip, port)));
+                    // only RTP is sent, there is no RTCP port to announce
+                    format!(
+                        "RTP/AVP;unicast;client_port={port}-{};server_port={server_port};\
+                         ssrc={ssrc:08X}",
+                        port + 1
+                    )
+                } else {
+                    respond("461 Unsupported Transport", &[], "")?;
+                    continue;
+                };
+                respond("200 OK", &[("Transport", reply), session_header], "")?
+            }
+            "PLAY" => {
+                let Some(sink) = sink.clone() else {
+                    respond("455 Method Not Valid in This State", &[], "")?;
+                    continue;
+                };
+                if playing.is_none() {
+                    let stop = Arc::new(AtomicBool::new(false));
+                    playing = Some(stop.clone());
+                    let broadcast = broadcast.clone();
+                    std::thread::spawn(move || play(&broadcast, &sink, &stop, ssrc));
+                }
+                respond("200 OK", &[session_header, ("Range", "npt=0.000-".into())], "")?
+            }
+            "TEARDOWN" => {
+                if let Some(stop) = playing.take() {
+                    stop.store(true, Ordering::Relaxed);
+                }
+                sink = None;
+                respond("200 OK", &[session_header], "")?
+            }
+            "GET_PARAMETER" | "SET_PARAMETER" => respond("200 OK", &[session_header], "")?,
+            _ => respond("501 Not Implemented", &[], "")?,
+        }
+    };
+    if let Some(stop) = playing {
+        stop.store(true, Ordering::Relaxed);
+    }
+    result
+}
+
+/// Sends the frames of the broadcast until `stop` or a failed send.
+fn play(broadcast: &Broadcast, sink: &Sink, stop: &AtomicBool, ssrc: u32) {
+    let mut client = broadcast.client();
+    let (mut sequence, base) = (random_u32() as u16, random_u32());
+    let mut first = None;
+    while let Some(frame) = client.next_frame() {
+        if stop.load(Ordering::Relaxed) {
+            return;
+        }
+        let Some(scan) = JpegScan::parse(&frame.data) else { continue };
+        let first = *first.get_or_insert(frame.timestamp);
+        let ticks = frame.timestamp.saturating_sub(first).as_micros() * CLOCK_RATE / 1_000_000;
+        let timestamp = base.wrapping_add(ticks as u32);
+        for packet in scan.packets(timestamp, ssrc, &mut sequence) {
+            if sink.send(&packet).is_err() {
+                return;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{y4m, FrameBuf, Pacing, ReplayOptions};
+    use std::time::Duration;
+
+    fn jpeg(size: (u32, u32)) -> Vec<u8> {
+        let rgb: Vec<u8> = (0..size.0 * size.1 * 3).map(|i| i as u8).collect();
+        let frame =
+            FrameBuf::new(&rgb, size, PixelFormat::Rgb, size.0 as usize * 3, Duration::ZERO);
+        let mut jpeg = Vec::new();
+        let mut encoder = jpeg_encoder::Encoder::new(&mut jpeg, 80);
+        encoder.set_sampling_factor(jpeg_encoder::SamplingFactor::F_2_1);
+        encoder.set_restart_interval(2);
+        let (w, h) = (size.0 as u16, size.1 as u16);
+        encoder.encode(frame.data_u8(), w, h, jpeg_encoder::ColorType::Rgb).unwrap();
+        jpeg
+    }
+
+    #[test]
+    fn packetizes_rfc_2435() {
+        let jpeg = jpeg((100, 60));
+        let scan = JpegScan::parse(&jpeg).unwrap();
+        assert_eq!((scan.kind, scan.size, scan.restart_interval), (0, (100, 60), 2));
+        assert_eq!(scan.tables.len(), 128);
+        assert!(JpegScan::parse(b"\xFF\xD8\xFF\xDA").is_none());
+
+        let mut sequence = u16::MAX;
+        let packets = scan.packets(1234, 42, &mut sequence);
+        assert_eq!(sequence, (packets.len() - 1) as u16);
+        let mut data: Vec<u8> = Vec::new();
+        for (i, packet) in packets.iter().enumerate() {
+            assert!(packet.len() <= MAX_PACKET);
+            assert_eq!(packet[1] & 0x80 != 0, i == packets.len() - 1, "marker");
+            assert_eq!(&packet[4..8], 1234u32.to_be_bytes());
+            let offset = u32::from_be_bytes([0, packet[13], packet[14], packet[15]]) as usize;
+            assert_eq!(offset, data.len());
+            // type with restart markers, Q, width and height in 8 pixel blocks
+            assert_eq!(packet[16..20], [64, 255, 13, 8]);
+            let payload = if i == 0 { 20 + 4 + 4 + 128 } else { 20 + 4 };
+            data.extend(&packet[payload..]);
+        }
+        assert_eq!(data, scan.data);
+    }
+
+    #[test]
+    fn encodes_jpegs_with_own_huffman_tables_again() {
+        let mut jpeg = jpeg((16, 16));
+        assert!(JpegScan::parse(&jpeg).is_some(), "standard tables");
+        // the last value of the first DHT segment
+        let dht = jpeg.windows(2).position(|m| m == [0xFF, 0xC4]).unwrap();
+        let len = u16::from_be_bytes([jpeg[dht + 2], jpeg[dht + 3]]) as usize;
+        jpeg[dht + 1 + len] ^= 1;
+        assert!(JpegScan::parse(&jpeg).is_none());
+
+        let frame = FrameBuf::new(&jpeg, (16, 16), PixelFormat::Mjpeg, 0, Duration::ZERO);
+        let path = std::env::temp_dir().join(format!("kamera_rtsp_{}.avi", std::process::id()));
+        let mut recorder = crate::Recorder::create(&path).unwrap();
+        recorder.write_frame_buf(&frame).unwrap();
+        recorder.finish().unwrap();
+        let options = ReplayOptions { pacing: Pacing::AsFastAsPossible, looping: false };
+        let camera = Camera::open_file_with(&path, options).unwrap();
+        camera.start();
+        let frame = camera.wait_for_frame().unwrap();
+        assert_eq!(frame.native().unwrap().data_u8(), jpeg);
+        let sent = rtp_jpeg(&frame, 80).unwrap();
+        assert!(JpegScan::parse(&sent).is_some());
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    fn read_response(reader: &mut BufReader<TcpStream>) -> String {
+        let mut response = String::new();
+        let mut line = String::new();
+        while reader.read_line(&mut line).unwrap() > 2 {
+            response.push_str(&line);
+            line.clear();
+        }
+        let len = response
+            .lines()
+            .find_map(|l| l.strip_prefix("Content-Length: "))
+            .map_or(0, |len| len.parse().unwrap());
+        let mut body = vec![0; len];
+        reader.read_exact(&mut body).unwrap();
+        response + "\r\n" + &String::from_utf8(body).unwrap()
+    }
+
+    fn request(reader: &mut BufReader<TcpStream>, method: &str, url: &str, extra: &str) -> String {
+        let request = format!("{method} {url} RTSP/1.0\r\nCSeq: 1\r\n{extra}\r\n");
+        reader.get_mut().write_all(request.as_bytes()).unwrap();
+        read_response(reader)
+    }
+
+    fn session_header(response: &str) -> String {
+        let line = response.lines().find_map(|l| l.strip_prefix("Session: ")).unwrap();
+        format!("Session: {}\r\n", line.split(';').next().unwrap())
+    }
+
+    #[test]
+    fn rejects_endless_requests() {
+        let read = |request: Vec<u8>| Request::read(&mut io::Cursor::new(request)).err();
+        assert!(read(vec![b'a'; 3 * MAX_LINE as usize]).is_some());
+        let mut long_header = b"OPTIONS * RTSP/1.0\r\nX-Long: ".to_vec();
+        long_header.extend([b'a'; 3 * MAX_LINE as usize]);
+        assert!(read(long_header).is_some());
+        let mut many_headers = b"OPTIONS * RTSP/1.0\r\n".to_vec();
+        (0..1000).for_each(|_| many_headers.extend(b"X-Header: 1\r\n"));
+        assert!(read(many_headers).is_some());
+        assert!(read(b"OPTIONS * RTSP/1.0\r\nCSeq: 1\r\n\r\n".to_vec()).is_none());
+    }
+
+    #[test]
+    fn plays_over_tcp_and_udp() {
+        let path = std::env::temp_dir().join(format!("kamera_rtsp_{}.y4m", std::process::id()));
+        let header = y4m::Header::new((32, 16), y4m::Chroma::C420, (30, 1));
+        let mut writer = y4m::Writer::create(&path, header).unwrap();
+        for value in [0, 128, 255] {
+            let frame =
+                FrameBuf::new(&[value; 512], (32, 16), PixelFormat::Gray, 32, Duration::ZERO);
+            writer.write_frame_buf(&frame).unwrap();
+        }
+        writer.into_inner().unwrap();
+        let options = ReplayOptions { pacing: Pacing::RealTime, looping: true };
+        let camera = Camera::open_file_with(&path, options).unwrap();
+
+        let server = Server::bind("127.0.0.1:0").unwrap();
+        let (addr, stop) = (server.local_addr(), server.stop_handle());
+        let client = std::thread::spawn(move || {
+            let url = format!("rtsp://{addr}/");
+            let mut rtsp = BufReader::new(TcpStream::connect(addr).unwrap());
+            assert!(request(&mut rtsp, "OPTIONS", &url, "").contains("PLAY"));
+            let sdp = request(&mut rtsp, "DESCRIBE", &url, "");
+            assert!(sdp.contains("m=video 0 RTP/AVP 26\r\na=rtpmap:26 JPEG/90000"), "{sdp}");
+
+            let transport = "Transport: RTP/AVP/TCP;unicast;interleaved=0-1\r\n";
+            let setup = request(&mut rtsp, "SETUP", &format!("{url}track0"), transport);
+            assert!(setup.contains("interleaved=0-1"), "{setup}");
+            let session = session_header(&setup);
+            assert!(request(&mut rtsp, "PLAY", &url, &session).starts_with("RTSP/1.0 200 OK"));
+
+            let mut timestamps = Vec::new();
+            while timestamps.len() < 2 {
+                let mut header = [0; 4];
+                rtsp.read_exact(&mut header).unwrap();
+                assert_eq!(header[..2], [b'$', 0]);
+                let mut packet = vec![0; u16::from_be_bytes([header[2], header[3]]) as usize];
+                rtsp.read_exact(&mut packet).unwrap();
+                assert_eq!(packet[0], 0x80);
+                assert_eq!(packet[16..20], [1, 255, 4, 2]);
+                if packet[1] & 0x80 != 0 {
+                    timestamps.push(u32::from_be_bytes(packet[4..8].try_into().unwrap()));
+                }
+            }
+            let ticks = timestamps[1].wrapping_sub(timestamps[0]);
+            assert!((2900..3100).contains(&ticks), "{ticks} ticks between frames at 30 fps");
+            assert!(request(&mut rtsp, "TEARDOWN", &url, &session).contains("200 OK"));
+
+            let udp = UdpSocket::bind("127.0.0.1:0").unwrap();
+            let port = udp.local_addr().unwrap().port();
+            let transport =
+                format!("Transport: RTP/AVP;unicast;client_port={port}-{}\r\n", port + 1);
+            let mut rtsp = BufReader::new(TcpStream::connect(addr).unwrap());
+            let setup = request(&mut rtsp, "SETUP", &format!("{url}track0"), &transport);
+            assert!(setup.contains("server_port="), "{setup}");
+            request(&mut rtsp, "PLAY", &url, &session_header(&setup));
+            let mut packet = [0; MAX_PACKET];
+            let len = udp.recv(&mut packet).unwrap();
+            assert!(len > 20 && packet[1] & 0x7F == PAYLOAD_TYPE);
+            stop.stop();
+        });
+        server.run(&camera).unwrap();
+        client.join().unwrap();
+        std::fs::remove_file(&path).unwrap();
+    }
+}
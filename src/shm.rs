@@ -0,0 +1,762 @@
+//! Sharing one camera between processes on Linux, enabled with the `shm` feature.
+//!
+//! A [Publisher] owns the device and writes its frames into a ring of slots in shared memory.
+//! Other processes attach with [Camera::open_shm] and read the frames in place, without
+//! copying. A Unix socket hands out the memory and wakes up the readers for every frame. It
+//! also tells them when the publisher is gone, then their `wait_for_frame` returns `None`.
+//!
+//! ```no_run
+//! use kamera::{shm, Camera};
+//!
+//! // in the process which owns the device
+//! let camera = Camera::new_default_device();
+//! let publisher = shm::Publisher::bind("/tmp/kamera.sock", 8, 4 << 20).unwrap();
+//! camera.start();
+//! publisher.run(&camera).unwrap();
+//!
+//! // in any number of other processes
+//! let camera = Camera::open_shm("/tmp/kamera.sock").unwrap();
+//! camera.start();
+//! let frame = camera.wait_for_frame().unwrap();
+//! ```
+//!
+//! Frames a reader holds are leased, the publisher skips their slots and drops frames when all
+//! slots are leased. Up to 64 readers can attach.
+
+use std::io::{self, Read, Write};
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::os::unix::fs::FileTypeExt;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::ptr::NonNull;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::Duration;
+
+use crate::{convert, Camera, Colorimetry, Frame, FrameBuf, PixelFormat};
+
+const MAGIC: &[u8; 8] = b"KAMSHM01";
+/// The ring header and every slot header are 64 bytes.
+///
+/// ```text
+/// ring: [u8; 8] magic, u32 slots, u32 slot header length, u64 slot data length,
+///       u64 latest frame number << 8 | slot
+/// slot: u64 stamp, frame number << 1 with bit 0 set while writing,
+///       u64 leases, one bit per reader,
+///       [u8; 4] fourcc, u32 width, height, stride, colorspace, transfer, quantization,
+///       flags, sequence, data length, u64 timestamp in nanoseconds
+/// ```
+const HEADER_LEN: usize = 64;
+const LATEST: usize = 24;
+const HAS_SEQUENCE: u32 = 1;
+const MAX_READERS: u32 = 64;
+
+/// Writes frames into shared memory for readers in other processes.
+pub struct Publisher {
+    path: PathBuf,
+    shared: Arc<Shared>,
+    writer: Mutex<WriterState>,
+}
+
+struct Shared {
+    memfd: OwnedFd,
+    ring: Ring,
+    readers: Mutex<Vec<Reader>>,
+    stopped: AtomicBool,
+}
+
+struct Reader {
+    stream: UnixStream,
+    index: u32,
+}
+
+#[derive(Default)]
+struct WriterState {
+    number: u64,
+    slot: usize,
+    dropped: u64,
+}
+
+impl Publisher {
+    /// Creates a ring of `slots` slots of `slot_len` bytes each and listens for readers on a
+    /// Unix socket at `path`, replacing a stale socket. Frames must fit into a slot.
+    pub fn bind(path: impl AsRef<Path>, slots: usize, slot_len: usize) -> io::Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        if !(1..=255).contains(&slots) {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "1 to 255 slots"));
+        }
+        let layout = Layout { slots, slot_len };
+        let memfd = memfd(layout.len())?;
+        let ring = Ring { mapping: Mapping::new(&memfd, layout.len())?, layout };
+        ring.mapping.write(0, MAGIC);
+        ring.mapping.write(8, &(slots as u32).to_le_bytes());
+        ring.mapping.write(12, &(HEADER_LEN as u32).to_le_bytes());
+        ring.mapping.write(16, &(slot_len as u64).to_le_bytes());
+
+        if std::fs::symlink_metadata(&path).is_ok_and(|m| m.file_type().is_socket()) {
+            // a publisher still listening keeps its socket
+            match UnixStream::connect(&path) {
+                Ok(_) => {
+                    let msg = format!("a publisher is listening at {}", path.display());
+                    return Err(io::Error::new(io::ErrorKind::AddrInUse, msg));
+                }
+                Err(e) if e.kind() == io::ErrorKind::ConnectionRefused => {
+                    std::fs::remove_file(&path)?
+                }
+                Err(_) => {}
+            }
+        }
+        let listener = UnixListener::bind(&path)?;
+        let shared = Arc::new(Shared {
+            memfd,
+            ring,
+            readers: Mutex::default(),
+            stopped: AtomicBool::new(false),
+        });
+        let accepting = shared.clone();
+        std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                if accepting.stopped.load(Ordering::SeqCst) {
+                    break;
+                }
+                if let Ok(stream) = stream {
+                    accepting.attach(stream);
+                }
+            }
+        });
+        Ok(Self { path, shared, writer: Mutex::default() })
+    }
+
+    /// Writes the [Frame::native] buffer, or the converted one on platforms which only
+    /// deliver converted frames, and wakes up the readers.
+    pub fn publish(&self, frame: &Frame) -> io::Result<()> {
+        let data = frame.native().unwrap_or_else(|| frame.data());
+        let slot = SlotHeader {
+            fourcc: data.format().fourcc(),
+            size: data.size_u32(),
+            stride: data.stride(),
+            colorimetry: frame.colorimetry(),
+            sequence: frame.sequence(),
+            timestamp: frame.timestamp(),
+        };
+        self.write(&slot, data.data_u8())
+    }
+
+    pub fn publish_frame_buf(&self, frame: &FrameBuf) -> io::Result<()> {
+        let slot = SlotHeader {
+            fourcc: frame.format().fourcc(),
+            size: frame.size_u32(),
+            stride: frame.stride(),
+            colorimetry: Colorimetry::default(),
+            sequence: None,
+            timestamp: frame.timestamp(),
+        };
+        self.write(&slot, frame.data_u8())
+    }
+
+    /// Publishes the frames of the started camera until it delivers no more.
+    pub fn run(&self, camera: &Camera) -> io::Result<()> {
+        while let Some(frame) = camera.wait_for_frame() {
+            self.publish(&frame)?;
+        }
+        Ok(())
+    }
+
+    /// Number of attached readers.
+    pub fn readers(&self) -> usize {
+        self.shared.readers.lock().unwrap().len()
+    }
+
+    /// Frames which weren't published because readers held all slots.
+    pub fn dropped(&self) -> u64 {
+        self.writer.lock().unwrap().dropped
+    }
+
+    fn write(&self, header: &SlotHeader, data: &[u8]) -> io::Result<()> {
+        let ring = &self.shared.ring;
+        if data.len() > ring.layout.slot_len {
+            let msg = format!("frame of {} bytes doesn't fit into a slot", data.len());
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, msg));
+        }
+        let mut writer = self.writer.lock().unwrap();
+        let number = writer.number + 1;
+        let slots = ring.layout.slots;
+        let claimed = (1..=slots).map(|i| (writer.slot + i) % slots).find(|&slot| {
+            // readers lease before they check the stamp, the publisher claims before it
+            // checks the leases, so one of them backs off
+            let stamp = ring.stamp(slot);
+            let previous = stamp.swap(number << 1 | 1, Ordering::SeqCst);
+            if ring.leases(slot).load(Ordering::SeqCst) == 0 {
+                return true;
+            }
+            stamp.store(previous, Ordering::SeqCst);
+            false
+        });
+        let Some(slot) = claimed else {
+            writer.dropped += 1;
+            return Ok(());
+        };
+
+        let offset = ring.layout.slot_offset(slot);
+        ring.mapping.write(offset + 16, &header.to_bytes(data.len()));
+        ring.mapping.write(offset + HEADER_LEN, data);
+        ring.stamp(slot).store(number << 1, Ordering::SeqCst);
+        ring.mapping.atomic_u64(LATEST).store(number << 8 | slot as u64, Ordering::SeqCst);
+        (writer.number, writer.slot) = (number, slot);
+        drop(writer);
+        self.shared.notify();
+        Ok(())
+    }
+}
+
+impl Drop for Publisher {
+    fn drop(&mut self) {
+        self.shared.stopped.store(true, Ordering::SeqCst);
+        // wakes up the accepting thread
+        let _ = UnixStream::connect(&self.path);
+        for reader in self.shared.readers.lock().unwrap().drain(..) {
+            let _ = reader.stream.shutdown(std::net::Shutdown::Both);
+        }
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+impl std::fmt::Debug for Publisher {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Publisher").field("path", &self.path).finish()
+    }
+}
+
+impl Shared {
+    /// Hands the memory and a reader index to a new reader.
+    fn attach(&self, stream: UnixStream) {
+        let mut readers = self.readers.lock().unwrap();
+        let free = (0..MAX_READERS).find(|i| readers.iter().all(|r| r.index != *i));
+        // without a free index the reader sees the connection closing
+        let Some(index) = free else { return };
+        if send_fd(&stream, &index.to_le_bytes(), self.memfd.as_raw_fd()).is_ok()
+            && stream.set_nonblocking(true).is_ok()
+        {
+            readers.push(Reader { stream, index });
+        }
+    }
+
+    /// Wakes up the readers. Readers which are behind skip frames, readers which are gone
+    /// lose their leases.
+    fn notify(&self) {
+        let mut readers = self.readers.lock().unwrap();
+        readers.retain_mut(|reader| match reader.stream.write(&[1]) {
+            Ok(_) => true,
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => true,
+            Err(_) => {
+                for slot in 0..self.ring.layout.slots {
+                    self.ring.leases(slot).fetch_and(!(1 << reader.index), Ordering::SeqCst);
+                }
+                false
+            }
+        });
+    }
+}
+
+/// Frame metadata at offset 16 of a slot.
+struct SlotHeader {
+    fourcc: [u8; 4],
+    size: (u32, u32),
+    stride: usize,
+    colorimetry: Colorimetry,
+    sequence: Option<u32>,
+    timestamp: Duration,
+}
+
+impl SlotHeader {
+    fn to_bytes(&self, len: usize) -> [u8; 48] {
+        let mut bytes = [0; 48];
+        bytes[..4].copy_from_slice(&self.fourcc);
+        let flags = if self.sequence.is_some() { HAS_SEQUENCE } else { 0 };
+        let words = [
+            self.size.0,
+            self.size.1,
+            self.stride as u32,
+            self.colorimetry.colorspace,
+            self.colorimetry.transfer,
+            self.colorimetry.quantization,
+            flags,
+            self.sequence.unwrap_or(0),
+            len as u32,
+        ];
+        for (i, word) in words.iter().enumerate() {
+            bytes[4 + i * 4..8 + i * 4].copy_from_slice(&word.to_le_bytes());
+        }
+        bytes[40..].copy_from_slice(&(self.timestamp.as_nanos() as u64).to_le_bytes());
+        bytes
+    }
+
+    fn from_bytes(bytes: &[u8]) -> (Self, usize) {
+        let u32_at = |pos: usize| u32::from_le_bytes(bytes[pos..pos + 4].try_into().unwrap());
+        let header = SlotHeader {
+            fourcc: bytes[..4].try_into().unwrap(),
+            size: (u32_at(4), u32_at(8)),
+            stride: u32_at(12) as usize,
+            colorimetry: Colorimetry {
+                colorspace: u32_at(16),
+                transfer: u32_at(20),
+                quantization: u32_at(24),
+            },
+            sequence: (u32_at(28) & HAS_SEQUENCE != 0).then(|| u32_at(32)),
+            timestamp: Duration::from_nanos(u64::from_le_bytes(bytes[40..48].try_into().unwrap())),
+        };
+        (header, u32_at(36) as usize)
+    }
+}
+
+#[derive(Clone, Copy)]
+struct Layout {
+    slots: usize,
+    slot_len: usize,
+}
+
+impl Layout {
+    fn slot_offset(&self, slot: usize) -> usize {
+        HEADER_LEN + slot * (HEADER_LEN + self.slot_len.next_multiple_of(64))
+    }
+
+    fn len(&self) -> usize {
+        self.slot_offset(self.slots)
+    }
+}
+
+struct Ring {
+    mapping: Mapping,
+    layout: Layout,
+}
+
+impl Ring {
+    fn stamp(&self, slot: usize) -> &AtomicU64 {
+        self.mapping.atomic_u64(self.layout.slot_offset(slot))
+    }
+
+    fn leases(&self, slot: usize) -> &AtomicU64 {
+        self.mapping.atomic_u64(self.layout.slot_offset(slot) + 8)
+    }
+}
+
+/// A shared memory mapping of the whole ring.
+struct Mapping {
+    ptr: NonNull<u8>,
+    len: usize,
+}
+
+// the mapping is only accessed through atomics and through slots owned by one side
+unsafe impl Send for Mapping {}
+unsafe impl Sync for Mapping {}
+
+impl Mapping {
+    fn new(fd: &OwnedFd, len: usize) -> io::Result<Self> {
+        let prot = libc::PROT_READ | libc::PROT_WRITE;
+        let ptr = unsafe {
+            libc::mmap(std::ptr::null_mut(), len, prot, libc::MAP_SHARED, fd.as_raw_fd(), 0)
+        };
+        if ptr == libc::MAP_FAILED {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Self { ptr: NonNull::new(ptr.cast()).unwrap(), len })
+    }
+
+    fn atomic_u64(&self, offset: usize) -> &AtomicU64 {
+        assert!(offset.is_multiple_of(8) && offset + 8 <= self.len);
+        unsafe { &*self.ptr.as_ptr().add(offset).cast::<AtomicU64>() }
+    }
+
+    fn bytes(&self, offset: usize, len: usize) -> &[u8] {
+        assert!(offset + len <= self.len);
+        unsafe { std::slice::from_raw_parts(self.ptr.as_ptr().add(offset), len) }
+    }
+
+    /// Only for the header and for slots claimed by the publisher.
+    fn write(&self, offset: usize, data: &[u8]) {
+        assert!(offset + data.len() <= self.len);
+        unsafe {
+            std::ptr::copy_nonoverlapping(data.as_ptr(), self.ptr.as_ptr().add(offset), data.len())
+        }
+    }
+}
+
+impl Drop for Mapping {
+    fn drop(&mut self) {
+        unsafe { libc::munmap(self.ptr.as_ptr().cast(), self.len) };
+    }
+}
+
+fn memfd(len: usize) -> io::Result<OwnedFd> {
+    let fd = unsafe { libc::memfd_create(c"kamera".as_ptr(), libc::MFD_CLOEXEC) };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+    if unsafe { libc::ftruncate(fd.as_raw_fd(), len as libc::off_t) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(fd)
+}
+
+/// Room for one file descriptor, as u64 for the alignment of `cmsghdr`.
+fn control_buffer() -> Vec<u64> {
+    let space = unsafe { libc::CMSG_SPACE(std::mem::size_of::<RawFd>() as u32) } as usize;
+    vec![0; space.div_ceil(8)]
+}
+
+fn send_fd(stream: &UnixStream, data: &[u8], fd: RawFd) -> io::Result<()> {
+    let mut control = control_buffer();
+    let mut iov = libc::iovec { iov_base: data.as_ptr() as *mut _, iov_len: data.len() };
+    let mut msg: libc::msghdr = unsafe { std::mem::zeroed() };
+    msg.msg_iov = &mut iov;
+    msg.msg_iovlen = 1;
+    msg.msg_control = control.as_mut_ptr().cast();
+    msg.msg_controllen = (control.len() * 8) as _;
+    unsafe {
+        let cmsg = libc::CMSG_FIRSTHDR(&msg);
+        (*cmsg).cmsg_level = libc::SOL_SOCKET;
+        (*cmsg).cmsg_type = libc::SCM_RIGHTS;
+        (*cmsg).cmsg_len = libc::CMSG_LEN(std::mem::size_of::<RawFd>() as u32) as _;
+        std::ptr::write_unaligned(libc::CMSG_DATA(cmsg).cast::<RawFd>(), fd);
+        if libc::sendmsg(stream.as_raw_fd(), &msg, libc::MSG_NOSIGNAL) < 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+fn recv_fd(stream: &UnixStream, data: &mut [u8]) -> io::Result<OwnedFd> {
+    let mut control = control_buffer();
+    let mut iov = libc::iovec { iov_base: data.as_mut_ptr().cast(), iov_len: data.len() };
+    let mut msg: libc::msghdr = unsafe { std::mem::zeroed() };
+    msg.msg_iov = &mut iov;
+    msg.msg_iovlen = 1;
+    msg.msg_control = control.as_mut_ptr().cast();
+    msg.msg_controllen = (control.len() * 8) as _;
+    unsafe {
+        let len = libc::recvmsg(stream.as_raw_fd(), &mut msg, libc::MSG_CMSG_CLOEXEC);
+        if len < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let cmsg = libc::CMSG_FIRSTHDR(&msg);
+        if (len as usize) < data.len() || cmsg.is_null() || (*cmsg).cmsg_type != libc::SCM_RIGHTS {
+            return Err(io::Error::new(io::ErrorKind::ConnectionRefused, "no ring from publisher"));
+        }
+        let fd = std::ptr::read_unaligned(libc::CMSG_DATA(cmsg).cast::<RawFd>());
+        Ok(OwnedFd::from_raw_fd(fd))
+    }
+}
+
+impl Camera {
+    /// Attaches to the ring of a [Publisher] listening at `path`, in this or another process.
+    pub fn open_shm(path: impl AsRef<Path>) -> io::Result<Self> {
+        Ok(Self::from_shm(ShmCamera::open(path.as_ref())?))
+    }
+}
+
+pub(crate) struct ShmCamera {
+    name: String,
+    ring: Arc<Ring>,
+    lease: u64,
+    stream: UnixStream,
+    state: Mutex<ReaderState>,
+}
+
+#[derive(Default)]
+struct ReaderState {
+    started: bool,
+    /// Frame number of the last frame delivered.
+    last: u64,
+    /// The publisher closed the socket.
+    gone: bool,
+}
+
+impl ShmCamera {
+    fn open(path: &Path) -> io::Result<Self> {
+        let stream = UnixStream::connect(path)?;
+        let mut index = [0; 4];
+        let memfd = recv_fd(&stream, &mut index)?;
+        let invalid = |msg| io::Error::new(io::ErrorKind::InvalidData, msg);
+
+        let len = std::fs::File::from(memfd.try_clone()?).metadata()?.len() as usize;
+        if len < HEADER_LEN {
+            return Err(invalid("ring too short"));
+        }
+        let mapping = Mapping::new(&memfd, len)?;
+        let header = mapping.bytes(0, HEADER_LEN);
+        let u32_at = |pos: usize| u32::from_le_bytes(header[pos..pos + 4].try_into().unwrap());
+        if &header[..8] != MAGIC || u32_at(12) as usize != HEADER_LEN {
+            return Err(invalid("no kamera ring"));
+        }
+        let slot_len = u64::from_le_bytes(header[16..24].try_into().unwrap()) as usize;
+        let layout = Layout { slots: u32_at(8) as usize, slot_len };
+        if layout.len() > len {
+            return Err(invalid("ring too short"));
+        }
+        let index = u32::from_le_bytes(index);
+        if index >= MAX_READERS {
+            return Err(invalid("reader index out of range"));
+        }
+        Ok(Self {
+            name: path.display().to_string(),
+            ring: Arc::new(Ring { mapping, layout }),
+            lease: 1 << index,
+            stream,
+            state: Mutex::default(),
+        })
+    }
+
+    pub(crate) fn start(&self) {
+        self.state.lock().unwrap().started = true;
+    }
+
+    pub(crate) fn stop(&self) {
+        self.state.lock().unwrap().started = false;
+    }
+
+    /// The newest frame published since the last one, `None` when stopped or when the
+    /// publisher is gone.
+    pub(crate) fn wait_for_frame(&self) -> Option<ShmFrame> {
+        loop {
+            {
+                let mut state = self.state.lock().unwrap();
+                if !state.started || state.gone {
+                    return None;
+                }
+                let latest = self.ring.mapping.atomic_u64(LATEST).load(Ordering::SeqCst);
+                if latest >> 8 > state.last {
+                    if let Some(frame) = self.lease(latest) {
+                        state.last = latest >> 8;
+                        return Some(frame);
+                    }
+                }
+            }
+            // unlocked, so that stop() doesn't wait for the next frame
+            let mut wakeups = [0; 64];
+            match (&self.stream).read(&mut wakeups) {
+                Ok(0) => self.state.lock().unwrap().gone = true,
+                Ok(_) => {}
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(_) => self.state.lock().unwrap().gone = true,
+            }
+        }
+    }
+
+    /// Leases the slot of `latest` if it still holds that frame.
+    fn lease(&self, latest: u64) -> Option<ShmFrame> {
+        let (number, slot) = (latest >> 8, (latest & 0xFF) as usize);
+        if slot >= self.ring.layout.slots {
+            return None;
+        }
+        self.ring.leases(slot).fetch_or(self.lease, Ordering::SeqCst);
+        let mut frame = ShmFrame {
+            ring: self.ring.clone(),
+            slot,
+            lease: self.lease,
+            header: None,
+            bgra: OnceLock::new(),
+        };
+        if self.ring.stamp(slot).load(Ordering::SeqCst) != number << 1 {
+            // overwritten in the meantime, dropping releases the lease
+            return None;
+        }
+
+        let offset = self.ring.layout.slot_offset(slot);
+        let (header, len) = SlotHeader::from_bytes(self.ring.mapping.bytes(offset + 16, 48));
+        let format = PixelFormat::from_fourcc(header.fourcc)?;
+        let len = len.min(self.ring.layout.slot_len);
+        frame.header = Some((header, format, len));
+        Some(frame)
+    }
+
+    pub(crate) fn device_name(&self) -> String {
+        self.name.clone()
+    }
+}
+
+impl std::fmt::Debug for ShmCamera {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ShmCamera").field("name", &self.name).finish()
+    }
+}
+
+/// A frame read in place from a leased slot, converted to BGRA on first access.
+pub(crate) struct ShmFrame {
+    ring: Arc<Ring>,
+    slot: usize,
+    lease: u64,
+    /// Always set on delivered frames.
+    header: Option<(SlotHeader, PixelFormat, usize)>,
+    bgra: OnceLock<Vec<u8>>,
+}
+
+impl ShmFrame {
+    fn header(&self) -> &(SlotHeader, PixelFormat, usize) {
+        self.header.as_ref().unwrap()
+    }
+
+    /// The native pixels, their format and stride.
+    pub(crate) fn native(&self) -> (&[u8], PixelFormat, usize) {
+        let (header, format, len) = self.header();
+        let offset = self.ring.layout.slot_offset(self.slot) + HEADER_LEN;
+        (self.ring.mapping.bytes(offset, *len), *format, header.stride)
+    }
+
+    /// BGRA pixels and their stride.
+    pub(crate) fn data(&self) -> (&[u8], usize) {
+        let (native, format, stride) = self.native();
+        if format == PixelFormat::Bgra {
+            return (native, stride);
+        }
+        let size = self.size_u32();
+        let bgra = self.bgra.get_or_init(|| convert::to_bgra(native, size, format, stride));
+        (bgra, size.0 as usize * 4)
+    }
+
+    pub(crate) fn size_u32(&self) -> (u32, u32) {
+        self.header().0.size
+    }
+
+    pub(crate) fn timestamp(&self) -> Duration {
+        self.header().0.timestamp
+    }
+
+    pub(crate) fn sequence(&self) -> Option<u32> {
+        self.header().0.sequence
+    }
+
+    pub(crate) fn colorimetry(&self) -> Colorimetry {
+        self.header().0.colorimetry
+    }
+}
+
+impl Drop for ShmFrame {
+    fn drop(&mut self) {
+        self.ring.leases(self.slot).fetch_and(!self.lease, Ordering::SeqCst);
+    }
+}
+
+impl std::fmt::Debug for ShmFrame {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ShmFrame").field("slot", &self.slot).finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn socket_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("kamera_shm_{}_{name}.sock", std::process::id()))
+    }
+
+    fn gray(value: u8, millis: u64) -> FrameBuf {
+        let timestamp = Duration::from_millis(millis);
+        FrameBuf::new(&[value; 16], (4, 4), PixelFormat::Gray, 4, timestamp)
+    }
+
+    #[test]
+    fn readers_see_frames_and_metadata() {
+        let path = socket_path("metadata");
+        let publisher = Publisher::bind(&path, 4, 64).unwrap();
+        let readers = [Camera::open_shm(&path).unwrap(), Camera::open_shm(&path).unwrap()];
+        assert_eq!(publisher.readers(), 2);
+        readers.iter().for_each(Camera::start);
+
+        publisher.publish_frame_buf(&gray(7, 40)).unwrap();
+        for reader in &readers {
+            let frame = reader.wait_for_frame().unwrap();
+            let native = frame.native().unwrap();
+            assert_eq!(
+                (native.format(), native.stride(), native.data_u8()),
+                (PixelFormat::Gray, 4, &[7; 16][..])
+            );
+            assert_eq!(frame.timestamp(), Duration::from_millis(40));
+            assert_eq!((frame.size_u32(), frame.sequence()), ((4, 4), None));
+            assert_eq!(frame.data().data_u8()[..4], [7, 7, 7, 255]);
+        }
+        assert!(publisher
+            .publish_frame_buf(&FrameBuf::new(
+                &[0; 100],
+                (10, 10),
+                PixelFormat::Gray,
+                10,
+                Duration::ZERO
+            ))
+            .is_err());
+        std::fs::remove_file(&path).ok();
+    }
+
+    #[test]
+    fn leased_slots_are_not_overwritten() {
+        let path = socket_path("lease");
+        let publisher = Publisher::bind(&path, 2, 16).unwrap();
+        let reader = Camera::open_shm(&path).unwrap();
+        reader.start();
+
+        publisher.publish_frame_buf(&gray(1, 0)).unwrap();
+        let held = reader.wait_for_frame().unwrap();
+        for value in 2..6 {
+            publisher.publish_frame_buf(&gray(value, 0)).unwrap();
+        }
+        assert_eq!(held.native().unwrap().data_u8(), [1; 16]);
+        // only the newest frame is delivered to a reader which is behind
+        let newest = reader.wait_for_frame().unwrap();
+        assert_eq!(newest.native().unwrap().data_u8(), [5; 16]);
+
+        // with both slots leased frames are dropped
+        publisher.publish_frame_buf(&gray(6, 0)).unwrap();
+        assert_eq!(publisher.dropped(), 1);
+        drop((held, newest));
+        publisher.publish_frame_buf(&gray(7, 0)).unwrap();
+        assert_eq!(reader.wait_for_frame().unwrap().native().unwrap().data_u8(), [7; 16]);
+    }
+
+    #[test]
+    fn readers_notice_the_publisher_leaving() {
+        let path = socket_path("gone");
+        let publisher = Publisher::bind(&path, 2, 16).unwrap();
+        let reader = Camera::open_shm(&path).unwrap();
+        reader.start();
+        publisher.publish_frame_buf(&gray(1, 0)).unwrap();
+        drop(publisher);
+        assert!(reader.wait_for_frame().is_some(), "published before leaving");
+        assert!(reader.wait_for_frame().is_none());
+        assert!(!path.exists());
+        assert!(Camera::open_shm(&path).is_err());
+    }
+
+    #[test]
+    fn live_sockets_are_kept_and_stale_ones_replaced() {
+        let path = socket_path("live");
+        let publisher = Publisher::bind(&path, 2, 16).unwrap();
+        let err = Publisher::bind(&path, 2, 16).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::AddrInUse);
+        let reader = Camera::open_shm(&path).unwrap();
+        reader.start();
+        publisher.publish_frame_buf(&gray(1, 0)).unwrap();
+        assert!(reader.wait_for_frame().is_some(), "still reachable");
+
+        drop(UnixListener::bind(socket_path("stale")).unwrap());
+        let stale = Publisher::bind(socket_path("stale"), 2, 16).unwrap();
+        assert!(Camera::open_shm(socket_path("stale")).is_ok());
+        drop((publisher, stale));
+    }
+
+    #[test]
+    fn rejects_reader_indices_out_of_range() {
+        let publisher = Publisher::bind(socket_path("ring"), 2, 16).unwrap();
+        let path = socket_path("index");
+        let listener = UnixListener::bind(&path).unwrap();
+        let memfd = publisher.shared.memfd.as_raw_fd();
+        let serve = std::thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            send_fd(&stream, &MAX_READERS.to_le_bytes(), memfd).unwrap();
+        });
+        let err = Camera::open_shm(&path).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        serve.join().unwrap();
+        std::fs::remove_file(&path).ok();
+    }
+}
@@ -0,0 +1,402 @@
+//! Encoding frames as still images with pure Rust encoders.
+
+use std::io;
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::{convert, Camera, Frame, FrameBuf, PixelFormat};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ImageFormat {
+    Png,
+    /// Quality from 1 to 100.
+    Jpeg {
+        quality: u8,
+    },
+    /// Binary RGB portable pixmap.
+    Ppm,
+    /// Binary grayscale portable graymap.
+    Pgm,
+}
+
+impl ImageFormat {
+    /// Picks the format from the file extension: png, jpg, jpeg, ppm or pgm.
+    /// JPEG uses a quality of 90.
+    pub fn from_path(path: &Path) -> Option<Self> {
+        let ext = path.extension()?.to_str()?.to_ascii_lowercase();
+        match ext.as_str() {
+            "png" => Some(ImageFormat::Png),
+            "jpg" | "jpeg" => Some(ImageFormat::Jpeg { quality: 90 }),
+            "ppm" => Some(ImageFormat::Ppm),
+            "pgm" => Some(ImageFormat::Pgm),
+            _ => None,
+        }
+    }
+
+    pub fn extension(&self) -> &'static str {
+        match self {
+            ImageFormat::Png => "png",
+            ImageFormat::Jpeg { .. } => "jpg",
+            ImageFormat::Ppm => "ppm",
+            ImageFormat::Pgm => "pgm",
+        }
+    }
+}
+
+/// Written into PNG tEXt chunks, JPEG EXIF or PPM header comments.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ImageMetadata {
+    pub capture_time: Option<SystemTime>,
+    pub device_name: Option<String>,
+}
+
+/// An encoded still image taken with [Camera::snapshot].
+#[derive(Debug, Clone)]
+pub struct Snapshot {
+    pub format: ImageFormat,
+    pub size: (u32, u32),
+    pub metadata: ImageMetadata,
+    pub data: Vec<u8>,
+}
+
+impl Snapshot {
+    pub fn save(&self, path: impl AsRef<Path>) -> io::Result<()> {
+        std::fs::write(path, &self.data)
+    }
+}
+
+impl Camera {
+    /// Waits for the next frame of the started camera and encodes it with capture time and
+    /// device name as metadata.
+    pub fn snapshot(&self, format: ImageFormat) -> io::Result<Snapshot> {
+        let frame =
+            self.wait_for_frame().ok_or_else(|| io::Error::other("camera delivered no frame"))?;
+        let metadata = ImageMetadata {
+            capture_time: Some(SystemTime::now()),
+            device_name: Some(self.device_name()),
+        };
+        let data = frame.encode(format, &metadata)?;
+        Ok(Snapshot { format, size: frame.size_u32(), metadata, data })
+    }
+}
+
+impl Frame {
+    pub fn encode(&self, format: ImageFormat, metadata: &ImageMetadata) -> io::Result<Vec<u8>> {
+        let data = self.data();
+        let image = Image::new(data.data_u8(), data.size_u32(), data.format(), data.stride());
+        encode(&image, format, metadata)
+    }
+
+    /// Saves the frame in the format given by the file extension, see [ImageFormat::from_path].
+    pub fn save(&self, path: impl AsRef<Path>) -> io::Result<()> {
+        self.save_as(&path, format_from_path(path.as_ref())?)
+    }
+
+    pub fn save_as(&self, path: impl AsRef<Path>, format: ImageFormat) -> io::Result<()> {
+        std::fs::write(path, self.encode(format, &ImageMetadata::default())?)
+    }
+}
+
+impl FrameBuf {
+    pub fn encode(&self, format: ImageFormat, metadata: &ImageMetadata) -> io::Result<Vec<u8>> {
+        let image = Image::new(self.data_u8(), self.size_u32(), self.format(), self.stride());
+        encode(&image, format, metadata)
+    }
+
+    /// Saves the frame in the format given by the file extension, see [ImageFormat::from_path].
+    pub fn save(&self, path: impl AsRef<Path>) -> io::Result<()> {
+        self.save_as(&path, format_from_path(path.as_ref())?)
+    }
+
+    pub fn save_as(&self, path: impl AsRef<Path>, format: ImageFormat) -> io::Result<()> {
+        std::fs::write(path, self.encode(format, &ImageMetadata::default())?)
+    }
+}
+
+fn format_from_path(path: &Path) -> io::Result<ImageFormat> {
+    ImageFormat::from_path(path).ok_or_else(|| {
+        let msg = format!("unknown image file extension {path:?}");
+        io::Error::new(io::ErrorKind::InvalidInput, msg)
+    })
+}
+
+/// Tightly packed 8 bit RGB or gray pixels.
+struct Image {
+    pixels: Vec<u8>,
+    size: (u32, u32),
+    gray: bool,
+}
+
+impl Image {
+    fn new(data: &[u8], size: (u32, u32), format: PixelFormat, stride: usize) -> Self {
+        let gray = format == PixelFormat::Gray;
+        let pixels = if gray {
+            convert::to_luma(data, size, format, stride)
+        } else {
+            convert::to_rgb(data, size, format, stride)
+        };
+        Self { pixels, size, gray }
+    }
+
+    fn to_gray(&self) -> Vec<u8> {
+        if self.gray {
+            self.pixels.clone()
+        } else {
+            convert::to_luma(&self.pixels, self.size, PixelFormat::Rgb, self.size.0 as usize * 3)
+        }
+    }
+
+    fn to_rgb(&self) -> Vec<u8> {
+        if self.gray {
+            convert::to_rgb(&self.pixels, self.size, PixelFormat::Gray, self.size.0 as usize)
+        } else {
+            self.pixels.clone()
+        }
+    }
+}
+
+fn encode(image: &Image, format: ImageFormat, metadata: &ImageMetadata) -> io::Result<Vec<u8>> {
+    match format {
+        ImageFormat::Png => encode_png(image, metadata),
+        ImageFormat::Jpeg { quality } => encode_jpeg(image, quality, Some(metadata)),
+        ImageFormat::Ppm => Ok(encode_pnm(b"P6", image.size, &image.to_rgb(), metadata)),
+        ImageFormat::Pgm => Ok(encode_pnm(b"P5", image.size, &image.to_gray(), metadata)),
+    }
+}
+
+fn encode_png(image: &Image, metadata: &ImageMetadata) -> io::Result<Vec<u8>> {
+    let mut out = Vec::new();
+    let mut encoder = png::Encoder::new(&mut out, image.size.0, image.size.1);
+    encoder.set_color(if image.gray { png::ColorType::Grayscale } else { png::ColorType::Rgb });
+    encoder.set_depth(png::BitDepth::Eight);
+    for (keyword, text) in text_entries(metadata) {
+        // tEXt only takes Latin-1, iTXt takes UTF-8 but fewer readers show it
+        let added = match text.chars().all(|c| u32::from(c) <= 0xff) {
+            true => encoder.add_text_chunk(keyword.into(), text),
+            false => encoder.add_itxt_chunk(keyword.into(), text),
+        };
+        added.map_err(io::Error::other)?;
+    }
+    let mut writer = encoder.write_header().map_err(io::Error::other)?;
+    writer.write_image_data(&image.pixels).map_err(io::Error::other)?;
+    writer.finish().map_err(io::Error::other)?;
+    Ok(out)
+}
+
+/// JPEG without EXIF, as used for the frames of videos.
+pub(crate) fn jpeg(
+    data: &[u8],
+    size: (u32, u32),
+    format: PixelFormat,
+    stride: usize,
+    quality: u8,
+) -> io::Result<Vec<u8>> {
+    encode_jpeg(&Image::new(data, size, format, stride), quality, None)
+}
+
+/// PNG with only the Software text chunk.
+pub(crate) fn png(
+    data: &[u8],
+    size: (u32, u32),
+    format: PixelFormat,
+    stride: usize,
+) -> io::Result<Vec<u8>> {
+    encode_png(&Image::new(data, size, format, stride), &ImageMetadata::default())
+}
+
+fn encode_jpeg(
+    image: &Image,
+    quality: u8,
+    metadata: Option<&ImageMetadata>,
+) -> io::Result<Vec<u8>> {
+    let (w, h) = image.size;
+    let (w, h) = (w.try_into(), h.try_into());
+    let (Ok(w), Ok(h)) = (w, h) else {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "too large for JPEG"));
+    };
+    let mut out = Vec::new();
+    let mut encoder = jpeg_encoder::Encoder::new(&mut out, quality.clamp(1, 100));
+    if let Some(metadata) = metadata {
+        encoder.add_app_segment(1, &exif(metadata)).map_err(io::Error::other)?;
+    }
+    let color =
+        if image.gray { jpeg_encoder::ColorType::Luma } else { jpeg_encoder::ColorType::Rgb };
+    encoder.encode(&image.pixels, w, h, color).map_err(io::Error::other)?;
+    Ok(out)
+}
+
+fn encode_pnm(
+    magic: &[u8],
+    (w, h): (u32, u32),
+    pixels: &[u8],
+    metadata: &ImageMetadata,
+) -> Vec<u8> {
+    let mut out = magic.to_vec();
+    out.push(b'\n');
+    for (keyword, text) in text_entries(metadata) {
+        // a newline would end the comment
+        out.extend(format!("# {keyword}: {}\n", text.replace('\n', " ")).bytes());
+    }
+    out.extend(format!("{w} {h}\n255\n").bytes());
+    out.extend_from_slice(pixels);
+    out
+}
+
+fn text_entries(metadata: &ImageMetadata) -> Vec<(&'static str, String)> {
+    let mut entries = vec![("Software", "kamera".to_string())];
+    if let Some(time) = metadata.capture_time {
+        let (y, mo, d, h, mi, s) = utc(time);
+        entries.push(("Creation Time", format!("{y:04}-{mo:02}-{d:02}T{h:02}:{mi:02}:{s:02}Z")));
+    }
+    if let Some(name) = &metadata.device_name {
+        entries.push(("Source", name.clone()));
+    }
+    entries
+}
+
+/// APP1 payload with a little endian TIFF structure holding IFD0 with
+/// Model (device name), Software and DateTime.
+fn exif(metadata: &ImageMetadata) -> Vec<u8> {
+    let mut ascii_tags: Vec<(u16, Vec<u8>)> = Vec::new();
+    if let Some(name) = &metadata.device_name {
+        ascii_tags.push((0x0110, name.as_bytes().to_vec()));
+    }
+    ascii_tags.push((0x0131, b"kamera".to_vec()));
+    if let Some(time) = metadata.capture_time {
+        let (y, mo, d, h, mi, s) = utc(time);
+        let date = format!("{y:04}:{mo:02}:{d:02} {h:02}:{mi:02}:{s:02}");
+        ascii_tags.push((0x0132, date.into_bytes()));
+    }
+
+    let mut tiff = b"II\x2a\x00\x08\x00\x00\x00".to_vec();
+    let entries_len = 2 + ascii_tags.len() * 12 + 4;
+    let mut values = Vec::new();
+    tiff.extend((ascii_tags.len() as u16).to_le_bytes());
+    for (tag, mut value) in ascii_tags {
+        value.retain(|&b| b != 0);
+        value.push(0);
+        tiff.extend(tag.to_le_bytes());
+        tiff.extend(2u16.to_le_bytes()); // ASCII
+        tiff.extend((value.len() as u32).to_le_bytes());
+        if value.len() <= 4 {
+            value.resize(4, 0);
+            tiff.extend(value);
+        } else {
+            let offset = 8 + entries_len + values.len();
+            tiff.extend((offset as u32).to_le_bytes());
+            values.extend(value);
+            if values.len() % 2 == 1 {
+                values.push(0);
+            }
+        }
+    }
+    tiff.extend(0u32.to_le_bytes()); // no next IFD
+    tiff.extend(values);
+
+    let mut app1 = b"Exif\0\0".to_vec();
+    app1.extend(tiff);
+    app1
+}
+
+/// Calendar date and time in UTC as year, month, day, hour, minute, second.
+pub(crate) fn utc(time: SystemTime) -> (i64, u32, u32, u32, u32, u32) {
+    let secs = time.duration_since(UNIX_EPOCH).map(|d| d.as_secs() as i64).unwrap_or(0);
+    let (days, rem) = (secs.div_euclid(86400), secs.rem_euclid(86400) as u32);
+    // http://howardhinnant.github.io/date_algorithms.html#civil_from_days
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = yoe + era * 400 + (month <= 2) as i64;
+    (year, month, day, rem / 3600, rem % 3600 / 60, rem % 60)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn frame() -> FrameBuf {
+        let bgra: Vec<u8> = (0..4 * 3).flat_map(|i| [i * 10, 0, 255 - i * 10, 255]).collect();
+        FrameBuf::new(&bgra, (4, 3), PixelFormat::Bgra, 16, Duration::ZERO)
+    }
+
+    fn metadata() -> ImageMetadata {
+        ImageMetadata {
+            capture_time: Some(UNIX_EPOCH + Duration::from_secs(1_700_000_000)),
+            device_name: Some("Test Camera".into()),
+        }
+    }
+
+    #[test]
+    fn utc_dates() {
+        assert_eq!(utc(UNIX_EPOCH), (1970, 1, 1, 0, 0, 0));
+        assert_eq!(
+            utc(UNIX_EPOCH + Duration::from_secs(1_700_000_000)),
+            (2023, 11, 14, 22, 13, 20)
+        );
+        assert_eq!(utc(UNIX_EPOCH + Duration::from_secs(951_782_400)), (2000, 2, 29, 0, 0, 0));
+    }
+
+    #[test]
+    fn format_from_extension() {
+        assert_eq!(
+            ImageFormat::from_path(Path::new("a.JPG")),
+            Some(ImageFormat::Jpeg { quality: 90 })
+        );
+        assert_eq!(ImageFormat::from_path(Path::new("a/b.pgm")), Some(ImageFormat::Pgm));
+        assert_eq!(ImageFormat::from_path(Path::new("a.bmp")), None);
+    }
+
+    #[test]
+    fn ppm_with_comments() {
+        let ppm = frame().encode(ImageFormat::Ppm, &metadata()).unwrap();
+        let header = b"P6\n# Software: kamera\n# Creation Time: 2023-11-14T22:13:20Z\n";
+        assert!(ppm.starts_with(header));
+        assert!(ppm.ends_with(&[155, 0, 100, 145, 0, 110]));
+        let pgm = frame().encode(ImageFormat::Pgm, &ImageMetadata::default()).unwrap();
+        assert_eq!(pgm.len(), b"P5\n# Software: kamera\n4 3\n255\n".len() + 12);
+    }
+
+    #[test]
+    fn png_with_text_chunks() {
+        let png = frame().encode(ImageFormat::Png, &metadata()).unwrap();
+        let decoder = png::Decoder::new(png.as_slice());
+        let reader = decoder.read_info().unwrap();
+        let info = reader.info();
+        assert_eq!((info.width, info.height), (4, 3));
+        let texts: Vec<_> = info.uncompressed_latin1_text.iter().map(|t| &t.text).collect();
+        assert!(texts.contains(&&"Test Camera".to_string()));
+
+        let name = "Kamera – 摄像头";
+        let metadata = ImageMetadata { device_name: Some(name.into()), ..metadata() };
+        let png = frame().encode(ImageFormat::Png, &metadata).unwrap();
+        let reader = png::Decoder::new(png.as_slice()).read_info().unwrap();
+        let texts: Vec<_> = reader.info().utf8_text.iter().map(|t| t.get_text().unwrap()).collect();
+        assert_eq!(texts, [name]);
+    }
+
+    #[test]
+    fn jpeg_with_exif() {
+        let jpeg = frame().encode(ImageFormat::Jpeg { quality: 80 }, &metadata()).unwrap();
+        assert!(jpeg.starts_with(&[0xFF, 0xD8]));
+        let exif = jpeg.windows(6).position(|w| w == b"Exif\0\0").unwrap();
+        let tiff = &jpeg[exif + 6..];
+        assert!(tiff.starts_with(b"II\x2a\x00"));
+        assert!(tiff.windows(12).any(|w| w == b"Test Camera\0"));
+        assert!(tiff.windows(20).any(|w| w == b"2023:11:14 22:13:20\0"));
+    }
+
+    #[test]
+    fn save_picks_format_from_extension() {
+        let path = std::env::temp_dir().join("kamera_snapshot_test.pgm");
+        frame().save(&path).unwrap();
+        assert!(std::fs::read(&path).unwrap().starts_with(b"P5\n"));
+        std::fs::remove_file(&path).unwrap();
+        assert!(frame().save("snapshot.xyz").is_err());
+    }
+}
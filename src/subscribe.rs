@@ -0,0 +1,257 @@
+use std::collections::VecDeque;
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+
+use crate::replay::OwnedFrame;
+use crate::{Camera, Frame};
+
+/// What a [Subscriber] gets when it doesn't keep up with the camera.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Delivery {
+    /// Only the newest frame waits, older ones are dropped. For previews.
+    #[default]
+    Latest,
+    /// Up to this many frames wait, the oldest is dropped when another one arrives. For
+    /// recorders which can't miss frames during short stalls.
+    Queue(usize),
+}
+
+/// One of several consumers of a camera, see [Camera::subscribe].
+pub struct Subscriber<'a> {
+    camera: &'a Camera,
+    id: u64,
+}
+
+/// The subscribers of a camera and the frames waiting for them.
+#[derive(Default)]
+pub(crate) struct Fanout {
+    state: Mutex<State>,
+    changed: Condvar,
+}
+
+#[derive(Default)]
+struct State {
+    subscribers: Vec<Queue>,
+    next_id: u64,
+    /// A subscriber is waiting for the camera, the others wait for it.
+    capturing: bool,
+    /// The camera delivered no frame, until the next start.
+    ended: bool,
+}
+
+struct Queue {
+    id: u64,
+    delivery: Delivery,
+    frames: VecDeque<Arc<OwnedFrame>>,
+    dropped: u64,
+}
+
+impl Camera {
+    /// Shares the camera between consumers, for example a preview and a recorder on other
+    /// threads. Every captured frame goes to every subscriber according to its `delivery`.
+    /// The first subscriber starts the camera, dropping the last one stops it.
+    ///
+    /// Subscribers copy the native buffer of each frame once and share it, so frames can be
+    /// kept while the camera goes on. Don't call [Camera::wait_for_frame] while there are
+    /// subscribers, it takes frames away from them.
+    pub fn subscribe(&self, delivery: Delivery) -> Subscriber<'_> {
+        let mut state = self.fanout().state();
+        if state.subscribers.is_empty() {
+            self.start();
+            state.ended = false;
+        }
+        let id = state.next_id;
+        state.next_id += 1;
+        state.subscribers.push(Queue { id, delivery, frames: VecDeque::new(), dropped: 0 });
+        Subscriber { camera: self, id }
+    }
+}
+
+impl Fanout {
+    fn state(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap()
+    }
+}
+
+impl State {
+    fn queue(&mut self, id: u64) -> &mut Queue {
+        self.subscribers.iter_mut().find(|queue| queue.id == id).unwrap()
+    }
+}
+
+impl Queue {
+    fn push(&mut self, frame: Arc<OwnedFrame>) {
+        let capacity = match self.delivery {
+            Delivery::Latest => 1,
+            Delivery::Queue(capacity) => capacity.max(1),
+        };
+        while self.frames.len() >= capacity {
+            self.frames.pop_front();
+            self.dropped += 1;
+        }
+        self.frames.push_back(frame);
+    }
+}
+
+impl Subscriber<'_> {
+    /// The next frame for this subscriber, captured by whichever subscriber asks first.
+    /// `None` when the camera delivers no more frames.
+    pub fn wait_for_frame(&mut self) -> Option<Frame> {
+        let fanout = self.camera.fanout();
+        let mut state = fanout.state();
+        loop {
+            if let Some(frame) = state.queue(self.id).frames.pop_front() {
+                return Some(Frame::from_owned(frame));
+            }
+            if state.ended {
+                return None;
+            }
+            if state.capturing {
+                state = fanout.changed.wait(state).unwrap();
+                continue;
+            }
+
+            state.capturing = true;
+            drop(state);
+            let frame = self.camera.wait_for_frame().map(|frame| frame.to_shared());
+            state = fanout.state();
+            state.capturing = false;
+            match frame {
+                Some(frame) => state.subscribers.iter_mut().for_each(|q| q.push(frame.clone())),
+                None => state.ended = true,
+            }
+            fanout.changed.notify_all();
+        }
+    }
+
+    /// Frames this subscriber missed because of its [Delivery].
+    pub fn dropped(&self) -> u64 {
+        self.camera.fanout().state().queue(self.id).dropped
+    }
+}
+
+impl Drop for Subscriber<'_> {
+    fn drop(&mut self) {
+        let mut state = self.camera.fanout().state();
+        state.subscribers.retain(|queue| queue.id != self.id);
+        if state.subscribers.is_empty() {
+            self.camera.stop();
+        }
+    }
+}
+
+impl std::fmt::Debug for Subscriber<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Subscriber").field("id", &self.id).finish()
+    }
+}
+
+impl std::fmt::Debug for Fanout {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Fanout").field("subscribers", &self.state().subscribers.len()).finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{y4m, Fault, FrameBuf, Pacing, PixelFormat, ReplayOptions, VirtualOptions};
+    use std::time::Duration;
+
+    fn camera(name: &str, frames: u8) -> (Camera, std::path::PathBuf) {
+        let path = std::env::temp_dir().join(format!("kamera_sub_{}_{name}", std::process::id()));
+        let header = y4m::Header::new((4, 4), y4m::Chroma::Mono, (30, 1));
+        let mut writer = y4m::Writer::create(&path, header).unwrap();
+        for value in 0..frames {
+            let frame = FrameBuf::new(&[value; 16], (4, 4), PixelFormat::Gray, 4, Duration::ZERO);
+            writer.write_frame_buf(&frame).unwrap();
+        }
+        writer.into_inner().unwrap();
+        let options = ReplayOptions { pacing: Pacing::AsFastAsPossible, looping: false };
+        (Camera::open_file_with(&path, options).unwrap(), path)
+    }
+
+    fn value(frame: &Frame) -> u8 {
+        frame.native().unwrap().data_u8()[0]
+    }
+
+    #[test]
+    fn delivers_by_policy() {
+        let (camera, path) = camera("policy", 5);
+        let mut recorder = camera.subscribe(Delivery::Queue(3));
+        let mut preview = camera.subscribe(Delivery::Latest);
+
+        assert_eq!(value(&recorder.wait_for_frame().unwrap()), 0);
+        assert_eq!(value(&preview.wait_for_frame().unwrap()), 0);
+        for expected in 1..5 {
+            assert_eq!(value(&recorder.wait_for_frame().unwrap()), expected);
+        }
+        assert!(recorder.wait_for_frame().is_none());
+        // frames 1 to 3 were replaced by 4
+        assert_eq!(value(&preview.wait_for_frame().unwrap()), 4);
+        assert_eq!((recorder.dropped(), preview.dropped()), (0, 3));
+        assert!(preview.wait_for_frame().is_none());
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn shares_across_threads_and_stops_with_last() {
+        let (camera, path) = camera("threads", 20);
+        let values: Vec<Vec<u8>> = std::thread::scope(|scope| {
+            let subscribers =
+                [camera.subscribe(Delivery::Queue(20)), camera.subscribe(Delivery::Queue(20))];
+            let threads: Vec<_> = subscribers
+                .into_iter()
+                .map(|mut subscriber| {
+                    scope.spawn(move || {
+                        std::iter::from_fn(|| subscriber.wait_for_frame())
+                            .map(|frame| value(&frame))
+                            .collect()
+                    })
+                })
+                .collect();
+            threads.into_iter().map(|thread| thread.join().unwrap()).collect()
+        });
+        assert_eq!(values[0], (0..20).collect::<Vec<_>>());
+        assert_eq!(values[0], values[1]);
+        // the camera was stopped and delivers nothing without a subscriber
+        assert!(camera.wait_for_frame().is_none());
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn slow_subscriber_misses_frames_until_the_end() {
+        let options = VirtualOptions {
+            size: (32, 24),
+            format: PixelFormat::Gray,
+            pacing: Pacing::AsFastAsPossible,
+            faults: vec![Fault::Disconnect { after: 20 }],
+            ..Default::default()
+        };
+        let camera = Camera::open_virtual(options).unwrap();
+        let (recorded, (previewed, dropped)) = std::thread::scope(|scope| {
+            let mut recorder = camera.subscribe(Delivery::Queue(20));
+            let mut preview = camera.subscribe(Delivery::Latest);
+            let recorder = scope.spawn(move || {
+                std::iter::from_fn(|| recorder.wait_for_frame())
+                    .map(|frame| frame.sequence().unwrap())
+                    .collect::<Vec<_>>()
+            });
+            let preview = scope.spawn(move || {
+                let mut sequences = Vec::new();
+                while let Some(frame) = preview.wait_for_frame() {
+                    sequences.push(frame.sequence().unwrap());
+                    std::thread::sleep(Duration::from_millis(20));
+                }
+                (sequences, preview.dropped())
+            });
+            (recorder.join().unwrap(), preview.join().unwrap())
+        });
+        assert_eq!(recorded, (0..20).collect::<Vec<_>>());
+        assert!(previewed.windows(2).all(|pair| pair[0] < pair[1]), "{previewed:?}");
+        assert_eq!(previewed.last(), Some(&19), "the newest frame waits for the end");
+        assert!(dropped > 0);
+        assert_eq!(previewed.len() as u64 + dropped, 20);
+        // ended until the camera is started again
+        assert!(camera.subscribe(Delivery::Latest).wait_for_frame().is_none());
+    }
+}
@@ -0,0 +1,224 @@
+//! Taking a picture every few seconds for days, see [Timelapse].
+
+use std::io;
+use std::path::PathBuf;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use crate::{snapshot, Camera};
+
+/// Captures one frame per interval and saves it under a file name made from a template.
+/// The camera only streams while taking a picture, which saves power and USB bandwidth.
+///
+/// The template may contain `{seq}` for the number of the picture, `{seq:05}` for it padded
+/// with zeros and `{seq:5}` with spaces, `{time}` for the UTC time like `20231114-221320` and
+/// `{unix}` for the seconds since 1970. Other braces are kept as they are. The extension picks
+/// the image format, see [ImageFormat::from_path](crate::ImageFormat::from_path).
+///
+/// ```no_run
+/// use kamera::{Camera, Timelapse};
+/// use std::time::Duration;
+///
+/// let mut timelapse = Timelapse::new(Duration::from_secs(60), "lab/{time}.jpg");
+/// // reopens the camera when it stops delivering frames, runs until the process ends
+/// timelapse.run(|| Ok(Camera::new_default_device()), None).unwrap();
+/// ```
+#[derive(Debug, Clone)]
+pub struct Timelapse {
+    interval: Duration,
+    template: String,
+    warmup_frames: usize,
+    sequence: u64,
+}
+
+impl Timelapse {
+    pub fn new(interval: Duration, template: impl Into<String>) -> Self {
+        Self { interval, template: template.into(), warmup_frames: 5, sequence: 0 }
+    }
+
+    /// Frames dropped after starting the camera so auto exposure and white balance can
+    /// settle, 5 by default.
+    pub fn set_warmup_frames(&mut self, frames: usize) {
+        self.warmup_frames = frames;
+    }
+
+    /// Number of the next picture, 0 at first. For continuing an earlier series.
+    pub fn set_sequence(&mut self, sequence: u64) {
+        self.sequence = sequence;
+    }
+
+    pub fn sequence(&self) -> u64 {
+        self.sequence
+    }
+
+    /// Starts the camera, drops the warmup frames, saves the next frame and stops the camera.
+    /// `None` when the camera delivered no frame, for example because it was unplugged.
+    pub fn shoot(&mut self, camera: &Camera) -> io::Result<Option<PathBuf>> {
+        camera.start();
+        let frame = (0..=self.warmup_frames).map(|_| camera.wait_for_frame()).last().flatten();
+        camera.stop();
+        let Some(frame) = frame else { return Ok(None) };
+
+        let path = PathBuf::from(self.file_name(SystemTime::now()));
+        frame.save(&path)?;
+        self.sequence += 1;
+        Ok(Some(path))
+    }
+
+    /// Shoots every interval until `shots` pictures are saved, or forever with `None`.
+    /// Calls `open` for the first shot and again after a shot without a frame, so the series
+    /// goes on after the device comes back. Shots without a camera are skipped, shots which
+    /// take longer than the interval delay the next one.
+    ///
+    /// Returns the number of pictures saved, errors only when saving fails.
+    pub fn run(
+        &mut self,
+        mut open: impl FnMut() -> io::Result<Camera>,
+        shots: Option<u64>,
+    ) -> io::Result<u64> {
+        let mut camera = None;
+        let mut saved = 0;
+        let mut due = Instant::now();
+        while shots.is_none_or(|shots| saved < shots) {
+            std::thread::sleep(due.saturating_duration_since(Instant::now()));
+            due += self.interval;
+            if camera.is_none() {
+                camera = open().ok();
+            }
+            if let Some(current) = &camera {
+                match self.shoot(current)? {
+                    Some(_) => saved += 1,
+                    None => camera = None,
+                }
+            }
+            // slots missed by a slow shot are skipped, a zero interval shoots back to back
+            let now = Instant::now();
+            while due < now && !self.interval.is_zero() {
+                due += self.interval;
+            }
+        }
+        Ok(saved)
+    }
+
+    fn file_name(&self, time: SystemTime) -> String {
+        let mut name = String::new();
+        let mut rest = self.template.as_str();
+        while let Some(start) = rest.find('{') {
+            name.push_str(&rest[..start]);
+            let Some(end) = rest[start..].find('}') else { break };
+            let placeholder = &rest[start + 1..start + end];
+            match placeholder.split_once(':') {
+                _ if placeholder == "seq" => name.push_str(&self.sequence.to_string()),
+                Some(("seq", spec)) if spec.parse::<usize>().is_ok() => {
+                    let width = spec.parse().unwrap_or(0);
+                    let seq = match spec.starts_with('0') {
+                        true => format!("{:0width$}", self.sequence),
+                        false => format!("{:width$}", self.sequence),
+                    };
+                    name.push_str(&seq);
+                }
+                _ if placeholder == "time" => {
+                    let (y, mo, d, h, mi, s) = snapshot::utc(time);
+                    name.push_str(&format!("{y:04}{mo:02}{d:02}-{h:02}{mi:02}{s:02}"));
+                }
+                _ if placeholder == "unix" => {
+                    let secs = time.duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs());
+                    name.push_str(&secs.to_string());
+                }
+                _ => name.push_str(&rest[start..=start + end]),
+            }
+            rest = &rest[start + end + 1..];
+        }
+        name.push_str(rest);
+        name
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{y4m, FrameBuf, Pacing, PixelFormat, ReplayOptions};
+
+    #[test]
+    fn file_names_from_template() {
+        let mut timelapse = Timelapse::new(Duration::ZERO, "a/{seq}_{seq:04}_{time}_{unix}{x}.png");
+        timelapse.set_sequence(42);
+        let time = UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        assert_eq!(timelapse.file_name(time), "a/42_0042_20231114-221320_1700000000{x}.png");
+        assert_eq!(Timelapse::new(Duration::ZERO, "{seq").file_name(time), "{seq");
+        let mut timelapse = Timelapse::new(Duration::ZERO, "{seq:5}_{seq:x}");
+        timelapse.set_sequence(42);
+        assert_eq!(timelapse.file_name(time), "   42_{seq:x}");
+    }
+
+    #[test]
+    fn skips_warmup_and_reopens() {
+        let dir = std::env::temp_dir().join(format!("kamera_timelapse_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let video = dir.join("video.y4m");
+        let header = y4m::Header::new((2, 2), y4m::Chroma::Mono, (30, 1));
+        let mut writer = y4m::Writer::create(&video, header).unwrap();
+        for value in 0..4 {
+            let frame = FrameBuf::new(&[value; 4], (2, 2), PixelFormat::Gray, 2, Duration::ZERO);
+            writer.write_frame_buf(&frame).unwrap();
+        }
+        writer.into_inner().unwrap();
+
+        let mut timelapse =
+            Timelapse::new(Duration::from_millis(5), dir.join("{seq:02}.pgm").to_str().unwrap());
+        timelapse.set_warmup_frames(2);
+        let mut opened = 0;
+        let open = || {
+            opened += 1;
+            let options = ReplayOptions { pacing: Pacing::AsFastAsPossible, looping: false };
+            Camera::open_file_with(&video, options)
+        };
+        // the second shot runs out of frames, the third one uses a reopened camera
+        assert_eq!(timelapse.run(open, Some(2)).unwrap(), 2);
+        assert_eq!(opened, 2);
+        for name in ["00.pgm", "01.pgm"] {
+            let pgm = std::fs::read(dir.join(name)).unwrap();
+            assert!(pgm.ends_with(&[2; 4]), "{name} is the frame after the warmup");
+        }
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn shoots_back_to_back_without_interval() {
+        let dir = std::env::temp_dir().join(format!("kamera_no_interval_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let mut timelapse = Timelapse::new(Duration::ZERO, dir.join("{seq}.pgm").to_str().unwrap());
+        timelapse.set_warmup_frames(0);
+        let open = || {
+            let options = crate::VirtualOptions {
+                size: (4, 4),
+                format: PixelFormat::Gray,
+                pacing: Pacing::AsFastAsPossible,
+                ..Default::default()
+            };
+            Camera::open_virtual(options)
+        };
+        assert_eq!(timelapse.run(open, Some(2)).unwrap(), 2);
+        assert!(dir.join("1.pgm").exists());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn skips_shots_of_unplugged_devices() {
+        use crate::linux_v4l2::fake::{FakeCamera, FakeDevices};
+        use crate::FrameSizes;
+
+        let dir = std::env::temp_dir().join(format!("kamera_unplugged_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let gray = (*b"GREY", FrameSizes::Discrete(vec![(4, 4)]));
+        let devices = FakeDevices::new(vec![FakeCamera::new(0, "Fake Camera", vec![gray])]);
+        let camera = Camera::first_in(devices.clone()).unwrap();
+        let mut timelapse = Timelapse::new(Duration::ZERO, dir.join("{seq}.pgm").to_str().unwrap());
+        timelapse.set_warmup_frames(0);
+        assert_eq!(timelapse.shoot(&camera).unwrap(), Some(dir.join("0.pgm")));
+        devices.unplug("/dev/video0");
+        assert_eq!(timelapse.shoot(&camera).unwrap(), None);
+        assert_eq!(timelapse.sequence(), 1);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
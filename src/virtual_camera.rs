@@ -0,0 +1,304 @@
+//! A generated test pattern played through the [Camera] API, which misbehaves on demand, see
+//! [Camera::open_virtual].
+
+use std::io;
+use std::time::Duration;
+
+use crate::replay::{FrameSource, OwnedFrame, ReplayCamera};
+use crate::{convert, snapshot, Camera, FrameBuf, Pacing, PixelFormat, ReplayOptions};
+
+/// Something a [virtual camera](Camera::open_virtual) does wrong. Frames are counted from 0
+/// like [Frame::sequence](crate::Frame::sequence).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Fault {
+    /// Delivers `after` frames, then [Camera::wait_for_frame] returns `None` like for an
+    /// unplugged device.
+    Disconnect { after: u32 },
+    /// Frame `at` and all later ones come `duration` late.
+    Stall { at: u32, duration: Duration },
+    /// Frame `at` keeps only its first `len` bytes, for [PixelFormat::Mjpeg].
+    TruncatedJpeg { at: u32, len: usize },
+    /// Frame `at` has garbage in the middle of its compressed data, for [PixelFormat::Mjpeg].
+    CorruptJpeg { at: u32 },
+    /// Frame `at` and all later ones have another size.
+    Resize { at: u32, size: (u32, u32) },
+    /// Every timestamp after the first is off by up to `max` either way, but never before the
+    /// previous one. With `max` over half the frame interval some frames repeat a timestamp.
+    Jitter { max: Duration },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VirtualOptions {
+    pub size: (u32, u32),
+    /// Any but [PixelFormat::Nv12].
+    pub format: PixelFormat,
+    /// Frames per second as numerator and denominator.
+    pub frame_rate: (u32, u32),
+    pub pacing: Pacing,
+    pub faults: Vec<Fault>,
+    /// Seeds the garbage and the jitter, the same seed misbehaves the same way every run.
+    pub seed: u64,
+}
+
+impl Default for VirtualOptions {
+    fn default() -> Self {
+        Self {
+            size: (640, 480),
+            format: PixelFormat::Mjpeg,
+            frame_rate: (30, 1),
+            pacing: Pacing::RealTime,
+            faults: Vec::new(),
+            seed: 1,
+        }
+    }
+}
+
+impl Camera {
+    /// A camera without a device that shows a moving test pattern and has the [Fault]s of
+    /// `options`, to test how an application copes with cameras that misbehave.
+    pub fn open_virtual(options: VirtualOptions) -> io::Result<Self> {
+        let VirtualOptions { size, format, frame_rate, .. } = options;
+        if size.0 == 0 || size.1 == 0 || frame_rate.0 == 0 || frame_rate.1 == 0 {
+            let msg = "virtual camera without size or frame rate";
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, msg));
+        }
+        if format == PixelFormat::Nv12 {
+            return Err(io::ErrorKind::Unsupported.into());
+        }
+        let replay = ReplayOptions { pacing: options.pacing, looping: false };
+        Ok(Self::from_replay(ReplayCamera::new(Box::new(Pattern::new(options)), replay)))
+    }
+}
+
+struct Pattern {
+    options: VirtualOptions,
+    next: u32,
+    random: u64,
+    previous: Duration,
+}
+
+impl Pattern {
+    fn new(options: VirtualOptions) -> Self {
+        // xorshift gets stuck at zero
+        let random = options.seed.max(1);
+        Self { options, next: 0, random, previous: Duration::ZERO }
+    }
+
+    fn random(&mut self) -> u64 {
+        self.random ^= self.random << 13;
+        self.random ^= self.random >> 7;
+        self.random ^= self.random << 17;
+        self.random
+    }
+
+    fn timestamp(&mut self, n: u32) -> Duration {
+        let (num, den) = self.options.frame_rate;
+        let mut timestamp =
+            Duration::from_nanos(n as u64 * den as u64 * 1_000_000_000 / num as u64);
+        for fault in self.options.faults.clone() {
+            match fault {
+                Fault::Stall { at, duration } if n >= at => timestamp += duration,
+                // the first frame stays at zero, which later ones are relative to
+                Fault::Jitter { max } if n > 0 => {
+                    let offset = max.mul_f64((self.random() % 2001) as f64 / 1000.0);
+                    timestamp = (timestamp + offset).saturating_sub(max);
+                }
+                _ => {}
+            }
+        }
+        self.previous = timestamp.max(self.previous);
+        self.previous
+    }
+
+    /// Bars moving right on a gradient, another color for every frame.
+    fn pixels(&self, n: u32, size: (u32, u32)) -> Vec<u8> {
+        let (w, h) = (size.0 as usize, size.1 as usize);
+        let mut rgb = Vec::with_capacity(w * h * 3);
+        for y in 0..h {
+            for x in 0..w {
+                let bar = ((x + n as usize * 4) * 8 / w.max(8)).is_multiple_of(2);
+                let r = if bar { 235 } else { (x * 255 / w) as u8 };
+                rgb.extend([r, (y * 255 / h) as u8, (n * 8) as u8]);
+            }
+        }
+        rgb
+    }
+
+    fn encode(&self, rgb: &[u8], size: (u32, u32)) -> io::Result<(Vec<u8>, usize)> {
+        let stride = size.0 as usize * 3;
+        match self.options.format {
+            PixelFormat::Mjpeg => Ok((snapshot::jpeg(rgb, size, PixelFormat::Rgb, stride, 80)?, 0)),
+            PixelFormat::Yuyv => Ok(yuyv(rgb, size)),
+            format => convert::to_format(rgb, size, PixelFormat::Rgb, stride, format)
+                .ok_or_else(|| io::ErrorKind::Unsupported.into()),
+        }
+    }
+
+    /// Overwrites a run of bytes between the headers and the end of image marker.
+    fn corrupt(&mut self, jpeg: &mut [u8]) {
+        let start = jpeg.windows(2).position(|m| m == [0xff, 0xda]).unwrap_or(0) + 16;
+        let end = jpeg.len().saturating_sub(2);
+        if start < end {
+            let at = start + (end - start) / 3;
+            let len = ((end - start) / 4).max(1);
+            for byte in &mut jpeg[at..(at + len).min(end)] {
+                *byte = self.random() as u8;
+            }
+        }
+    }
+}
+
+impl FrameSource for Pattern {
+    fn name(&self) -> String {
+        "Virtual Camera".to_string()
+    }
+
+    fn next_frame(&mut self) -> io::Result<Option<OwnedFrame>> {
+        let n = self.next;
+        let mut size = self.options.size;
+        for fault in self.options.faults.clone() {
+            match fault {
+                Fault::Disconnect { after } if n >= after => {
+                    return Err(io::Error::new(io::ErrorKind::NotConnected, "virtual disconnect"));
+                }
+                Fault::Resize { at, size: new } if n >= at => size = new,
+                _ => {}
+            }
+        }
+        let (mut data, stride) = self.encode(&self.pixels(n, size), size)?;
+        if self.options.format == PixelFormat::Mjpeg {
+            for fault in self.options.faults.clone() {
+                match fault {
+                    Fault::TruncatedJpeg { at, len } if n == at => data.truncate(len),
+                    Fault::CorruptJpeg { at } if n == at => self.corrupt(&mut data),
+                    _ => {}
+                }
+            }
+        }
+        let timestamp = self.timestamp(n);
+        self.next += 1;
+        let native = FrameBuf::new(&data, size, self.options.format, stride, timestamp);
+        Ok(Some(OwnedFrame::new(native).with_sequence(n)))
+    }
+
+    fn rewind(&mut self) -> io::Result<()> {
+        self.next = 0;
+        self.previous = Duration::ZERO;
+        Ok(())
+    }
+}
+
+/// Packs RGB pixels as [PixelFormat::Yuyv], an odd width gets one more column.
+fn yuyv(rgb: &[u8], size: (u32, u32)) -> (Vec<u8>, usize) {
+    let (w, h) = (size.0 as usize, size.1 as usize);
+    let planes = convert::to_planar_yuv(rgb, size, PixelFormat::Rgb, w * 3, PixelFormat::I422);
+    let cw = w.div_ceil(2);
+    let (y, chroma) = planes.split_at(w * h);
+    let (u, v) = chroma.split_at(cw * h);
+    let mut out = Vec::with_capacity(cw * 4 * h);
+    for row in 0..h {
+        for c in 0..cw {
+            let y0 = y[row * w + c * 2];
+            let y1 = y[row * w + (c * 2 + 1).min(w - 1)];
+            out.extend([y0, u[row * cw + c], y1, v[row * cw + c]]);
+        }
+    }
+    (out, cw * 4)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn open(format: PixelFormat, faults: Vec<Fault>) -> Camera {
+        let options = VirtualOptions {
+            size: (64, 48),
+            format,
+            pacing: Pacing::AsFastAsPossible,
+            faults,
+            ..Default::default()
+        };
+        let camera = Camera::open_virtual(options).unwrap();
+        camera.start();
+        camera
+    }
+
+    #[test]
+    fn generates_frames_in_every_format() {
+        for format in [PixelFormat::Mjpeg, PixelFormat::Yuyv, PixelFormat::Gray, PixelFormat::I420]
+        {
+            let camera = open(format, Vec::new());
+            let frames: Vec<_> = (0..3).map(|_| camera.wait_for_frame().unwrap()).collect();
+            let timestamps: Vec<_> = frames.iter().map(|f| f.timestamp().as_millis()).collect();
+            assert_eq!(timestamps, [0, 33, 66]);
+            assert_eq!(frames[1].sequence(), Some(1));
+            assert_eq!(frames[2].native().unwrap().format(), format);
+            assert_eq!(frames[2].data().size_u32(), (64, 48));
+            assert_ne!(frames[0].data().data_u8(), frames[1].data().data_u8());
+        }
+        let options = VirtualOptions { frame_rate: (0, 1), ..Default::default() };
+        assert!(Camera::open_virtual(options).is_err());
+    }
+
+    #[test]
+    fn disconnects_stalls_and_resizes() {
+        let camera = open(
+            PixelFormat::Gray,
+            vec![
+                Fault::Stall { at: 1, duration: Duration::from_secs(1) },
+                Fault::Resize { at: 2, size: (32, 16) },
+                Fault::Disconnect { after: 3 },
+            ],
+        );
+        let frames: Vec<_> = (0..3).map(|_| camera.wait_for_frame().unwrap()).collect();
+        let timestamps: Vec<_> = frames.iter().map(|f| f.timestamp().as_millis()).collect();
+        assert_eq!(timestamps, [0, 1033, 1066]);
+        assert_eq!(frames[1].data().size_u32(), (64, 48));
+        assert_eq!(frames[2].data().size_u32(), (32, 16));
+        assert!(camera.wait_for_frame().is_none());
+        assert!(camera.wait_for_frame().is_none());
+    }
+
+    #[test]
+    fn breaks_jpegs() {
+        let jpegs = |faults| {
+            let camera = open(PixelFormat::Mjpeg, faults);
+            let frames: Vec<_> = (0..3).map(|_| camera.wait_for_frame().unwrap()).collect();
+            frames.iter().map(|f| f.native().unwrap().data_u8().to_vec()).collect::<Vec<_>>()
+        };
+        let intact = jpegs(Vec::new());
+        let broken =
+            jpegs(vec![Fault::TruncatedJpeg { at: 1, len: 100 }, Fault::CorruptJpeg { at: 2 }]);
+        assert_eq!(broken[0], intact[0]);
+        assert_eq!(broken[1], intact[1][..100]);
+        assert_eq!(broken[2].len(), intact[2].len());
+        assert_eq!(broken[2][..200], intact[2][..200], "headers are intact");
+        let decode = |jpeg: &[u8]| jpeg_decoder::Decoder::new(jpeg).decode().ok();
+        assert!(decode(&broken[1]).is_none());
+        assert_ne!(decode(&broken[2]), decode(&intact[2]));
+    }
+
+    #[test]
+    fn jitters_repeatably() {
+        let max = Duration::from_millis(10);
+        let timestamps = || {
+            let camera = open(PixelFormat::Gray, vec![Fault::Jitter { max }]);
+            (0..20).map(|_| camera.wait_for_frame().unwrap().timestamp()).collect::<Vec<_>>()
+        };
+        let jittered = timestamps();
+        assert_eq!(jittered, timestamps());
+        assert_eq!(jittered[0], Duration::ZERO);
+        let interval = Duration::from_nanos(1_000_000_000 / 30);
+        let nominal = (1..20u32).map(|n| interval * n);
+        assert!(jittered[1..]
+            .iter()
+            .zip(nominal.clone())
+            .all(|(&t, n)| t + max >= n && t <= n + max));
+        assert!(jittered[1..].iter().zip(nominal).any(|(&t, n)| t != n));
+
+        let camera = open(PixelFormat::Gray, vec![Fault::Jitter { max: interval * 3 }]);
+        let jittered: Vec<_> = (0..50).map(|_| camera.wait_for_frame().unwrap()).collect();
+        assert!(jittered.windows(2).all(|w| w[0].timestamp() <= w[1].timestamp()));
+        assert!(jittered.windows(2).any(|w| w[0].timestamp() == w[1].timestamp()));
+    }
+}
@@ -2,6 +2,8 @@ use super::mf::*;
 
 use std::{sync::mpsc::*, time::Duration};
 
+use crate::{Colorimetry, PixelFormat};
+
 use windows::Win32::Media::MediaFoundation::*;
 
 #[allow(unused)]
@@ -18,6 +20,7 @@ pub struct Camera {
 #[derive(Debug)]
 pub struct Frame {
     buffer: LockedBuffer,
+    timestamp: Duration,
 }
 
 pub struct FrameData<'a> {
@@ -66,9 +69,12 @@ impl Camera {
                 };
                 let width = mt.frame_width();
                 let height = mt.frame_height();
-                sample_to_locked_buffer(&sample, width, height).ok()
+                // sample time is in 100 nanosecond units
+                let time = unsafe { sample.GetSampleTime() }.unwrap_or_default();
+                let timestamp = Duration::from_nanos(time.max(0) as u64 * 100);
+                let buffer = sample_to_locked_buffer(&sample, width, height).ok()?;
+                Some(Frame { buffer, timestamp })
             })
-            .map(|buffer: LockedBuffer| Frame { buffer })
     }
 
     pub fn change_device(&mut self) {
@@ -94,6 +100,10 @@ impl Camera {
         self.prepare_source_sink();
         self.start(); // TODO watch out about playing state
     }
+
+    pub fn device_name(&self) -> String {
+        self.device.name()
+    }
 }
 
 impl Camera {
@@ -107,13 +117,37 @@ impl Camera {
 }
 
 impl Frame {
-    pub fn data(&self) -> FrameData {
+    pub fn data(&self) -> FrameData<'_> {
         FrameData { data: self.buffer.data() }
     }
 
     pub fn size_u32(&self) -> (u32, u32) {
         (self.buffer.width, self.buffer.height)
     }
+
+    pub fn format(&self) -> PixelFormat {
+        PixelFormat::Bgra
+    }
+
+    pub fn native(&self) -> Option<(FrameData<'_>, PixelFormat, usize)> {
+        None
+    }
+
+    pub fn stride(&self) -> usize {
+        self.buffer.pitch
+    }
+
+    pub fn timestamp(&self) -> Duration {
+        self.timestamp
+    }
+
+    pub fn sequence(&self) -> Option<u32> {
+        None
+    }
+
+    pub fn colorimetry(&self) -> Colorimetry {
+        Colorimetry::default()
+    }
 }
 
 impl<'a> FrameData<'a> {
@@ -224,6 +224,7 @@ pub fn sample_to_locked_buffer(
             width,
             height,
             scanline0,
+            pitch: pitch as usize,
             len: pitch as usize * height as usize,
         })
     }
@@ -235,6 +236,7 @@ pub struct LockedBuffer {
     pub(crate) width: u32,
     pub(crate) height: u32,
     scanline0: *mut u8,
+    pub(crate) pitch: usize,
     len: usize,
 }
 
@@ -273,6 +275,7 @@ impl Clone for LockedBuffer {
             width: self.width,
             height: self.height,
             scanline0: self.scanline0,
+            pitch: self.pitch,
             len: self.len,
         }
     }
@@ -0,0 +1,342 @@
+//! Reading and writing YUV4MPEG2 (Y4M) files, the uncompressed format which ffmpeg, x264 and
+//! rav1e accept as input.
+//!
+//! ```no_run
+//! use kamera::{y4m, Camera};
+//!
+//! let camera = Camera::new_default_device();
+//! camera.start();
+//! let frame = camera.wait_for_frame().unwrap();
+//! let header = y4m::Header::new(frame.size_u32(), y4m::Chroma::C420, (30, 1));
+//! let mut writer = y4m::Writer::create("capture.y4m", header).unwrap();
+//! writer.write_frame(&frame).unwrap();
+//! ```
+
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, BufWriter, Read, Write};
+use std::path::Path;
+use std::time::Duration;
+
+use crate::{convert, Frame, FrameBuf, PixelFormat};
+
+/// Larger frames are taken for a broken header, 8192x8192 pixels.
+const MAX_PIXELS: u64 = 1 << 26;
+
+/// Chroma subsampling of the frames in a file. 4:2:0 chroma samples are centered between
+/// the luma samples, `C420jpeg` in Y4M terms.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Chroma {
+    C420,
+    C422,
+    C444,
+    /// Luma only.
+    Mono,
+}
+
+impl Chroma {
+    /// Pixel format of the frames the [Reader] returns.
+    pub fn pixel_format(&self) -> PixelFormat {
+        match self {
+            Chroma::C420 => PixelFormat::I420,
+            Chroma::C422 => PixelFormat::I422,
+            Chroma::C444 => PixelFormat::I444,
+            Chroma::Mono => PixelFormat::Gray,
+        }
+    }
+
+    fn tags(&self) -> &'static str {
+        match self {
+            Chroma::C420 => "C420jpeg XYSCSS=420JPEG",
+            Chroma::C422 => "C422 XYSCSS=422",
+            Chroma::C444 => "C444 XYSCSS=444",
+            Chroma::Mono => "Cmono XYSCSS=400",
+        }
+    }
+}
+
+/// Stream parameters from the file header.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Header {
+    pub size: (u32, u32),
+    pub chroma: Chroma,
+    /// Frames per second as numerator and denominator.
+    pub frame_rate: (u32, u32),
+}
+
+impl Header {
+    pub fn new(size: (u32, u32), chroma: Chroma, frame_rate: (u32, u32)) -> Self {
+        Self { size, chroma, frame_rate }
+    }
+
+    /// Bytes of one frame without the `FRAME` line.
+    fn frame_len(&self) -> usize {
+        self.chroma.pixel_format().frame_len(self.size, self.size.0 as usize)
+    }
+
+    fn parse(line: &str) -> io::Result<Self> {
+        let mut params = line.split_ascii_whitespace();
+        if params.next() != Some("YUV4MPEG2") {
+            return Err(invalid_data("no YUV4MPEG2 header"));
+        }
+        let (mut w, mut h) = (None, None);
+        let mut header = Header::new((0, 0), Chroma::C420, (30, 1));
+        for param in params {
+            let mut chars = param.chars();
+            let (tag, value) = (chars.next(), chars.as_str());
+            match tag {
+                Some('W') => w = value.parse().ok(),
+                Some('H') => h = value.parse().ok(),
+                Some('F') => header.frame_rate = parse_ratio(value)?,
+                Some('C') => {
+                    header.chroma = match value {
+                        "420jpeg" | "420mpeg2" | "420paldv" | "420" => Chroma::C420,
+                        "422" => Chroma::C422,
+                        "444" => Chroma::C444,
+                        "mono" => Chroma::Mono,
+                        _ => return Err(invalid_data(&format!("unsupported colorspace {value}"))),
+                    }
+                }
+                // interlacing, aspect ratio and extensions don't change the frame layout
+                _ => {}
+            }
+        }
+        let (Some(w), Some(h)) = (w, h) else { return Err(invalid_data("no frame size")) };
+        if w == 0 || h == 0 || w as u64 * h as u64 > MAX_PIXELS {
+            return Err(invalid_data("frame size out of range"));
+        }
+        header.size = (w, h);
+        Ok(header)
+    }
+}
+
+impl std::fmt::Display for Header {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let ((w, h), (num, den)) = (self.size, self.frame_rate);
+        let tags = self.chroma.tags();
+        write!(f, "YUV4MPEG2 W{w} H{h} F{num}:{den} Ip A1:1 {tags} XCOLORRANGE=LIMITED")
+    }
+}
+
+/// Writes frames of any pixel format, converted to the chroma subsampling of the header.
+pub struct Writer<W: Write = BufWriter<File>> {
+    out: W,
+    header: Header,
+}
+
+impl Writer {
+    pub fn create(path: impl AsRef<Path>, header: Header) -> io::Result<Self> {
+        Self::new(BufWriter::new(File::create(path)?), header)
+    }
+}
+
+impl<W: Write> Writer<W> {
+    /// Writes the file header.
+    pub fn new(mut out: W, header: Header) -> io::Result<Self> {
+        writeln!(out, "{header}")?;
+        Ok(Self { out, header })
+    }
+
+    pub fn header(&self) -> &Header {
+        &self.header
+    }
+
+    /// Frames must have the size of the header. Y4M has a constant frame rate, timestamps are
+    /// not stored. Takes the [Frame::native] buffer when there is one, so YUV frames are not
+    /// converted through RGB.
+    pub fn write_frame(&mut self, frame: &Frame) -> io::Result<()> {
+        let data = frame.native().unwrap_or_else(|| frame.data());
+        self.write(data.data_u8(), data.size_u32(), data.format(), data.stride())
+    }
+
+    /// Frames must have the size of the header. Y4M has a constant frame rate, timestamps are
+    /// not stored.
+    pub fn write_frame_buf(&mut self, frame: &FrameBuf) -> io::Result<()> {
+        self.write(frame.data_u8(), frame.size_u32(), frame.format(), frame.stride())
+    }
+
+    /// Flushes and returns the underlying writer.
+    pub fn into_inner(mut self) -> io::Result<W> {
+        self.out.flush()?;
+        Ok(self.out)
+    }
+
+    fn write(
+        &mut self,
+        data: &[u8],
+        size: (u32, u32),
+        format: PixelFormat,
+        stride: usize,
+    ) -> io::Result<()> {
+        if size != self.header.size {
+            let msg = format!("frame size {size:?} differs from {:?}", self.header.size);
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, msg));
+        }
+        let target = self.header.chroma.pixel_format();
+        let planes = if format == target && stride == size.0 as usize {
+            let short = || io::Error::new(io::ErrorKind::InvalidInput, "frame data too short");
+            data.get(..self.header.frame_len()).ok_or_else(short)?.to_vec()
+        } else {
+            convert::to_planar_yuv(data, size, format, stride, target)
+        };
+        self.out.write_all(b"FRAME\n")?;
+        self.out.write_all(&planes)
+    }
+}
+
+/// Reads the frames of a Y4M file as [FrameBuf]s with tightly packed planes.
+/// The timestamps count from zero at the frame rate of the file.
+pub struct Reader<R: BufRead = BufReader<File>> {
+    input: R,
+    header: Header,
+    frame_index: u64,
+}
+
+impl Reader {
+    pub fn open(path: impl AsRef<Path>) -> io::Result<Self> {
+        Self::new(BufReader::new(File::open(path)?))
+    }
+}
+
+impl<R: BufRead> Reader<R> {
+    /// Reads the file header.
+    pub fn new(mut input: R) -> io::Result<Self> {
+        let header = Header::parse(&read_line(&mut input)?)?;
+        Ok(Self { input, header, frame_index: 0 })
+    }
+
+    pub fn header(&self) -> &Header {
+        &self.header
+    }
+
+    /// The next frame, `None` at the end of the file.
+    pub fn read_frame(&mut self) -> io::Result<Option<FrameBuf>> {
+        let line = read_line(&mut self.input)?;
+        if line.is_empty() {
+            return Ok(None);
+        }
+        if !line.starts_with("FRAME") {
+            return Err(invalid_data("no FRAME marker"));
+        }
+        // a truncated file ends the frame before all of it is allocated
+        let len = self.header.frame_len();
+        let mut data = Vec::new();
+        (&mut self.input).take(len as u64).read_to_end(&mut data)?;
+        if data.len() < len {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+
+        let (num, den) = self.header.frame_rate;
+        let nanos = self.frame_index as u128 * den as u128 * 1_000_000_000 / num.max(1) as u128;
+        let timestamp = Duration::from_nanos(nanos as u64);
+        self.frame_index += 1;
+
+        let (size, format) = (self.header.size, self.header.chroma.pixel_format());
+        Ok(Some(FrameBuf::new(&data, size, format, size.0 as usize, timestamp)))
+    }
+}
+
+impl<R: BufRead> Iterator for Reader<R> {
+    type Item = io::Result<FrameBuf>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.read_frame().transpose()
+    }
+}
+
+/// A line without the newline, empty at the end of the input.
+fn read_line(input: &mut impl BufRead) -> io::Result<String> {
+    let mut line = Vec::new();
+    input.take(4096).read_until(b'\n', &mut line)?;
+    match line.pop() {
+        None | Some(b'\n') => {}
+        Some(_) => return Err(invalid_data("unterminated header line")),
+    }
+    String::from_utf8(line).map_err(|_| invalid_data("header line is no text"))
+}
+
+fn parse_ratio(value: &str) -> io::Result<(u32, u32)> {
+    let ratio = value.split_once(':').and_then(|(n, d)| Some((n.parse().ok()?, d.parse().ok()?)));
+    ratio.filter(|&(n, d)| n > 0 && d > 0).ok_or_else(|| invalid_data("invalid frame rate"))
+}
+
+fn invalid_data(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    #[test]
+    fn header_round_trip() {
+        let header = Header::new((640, 480), Chroma::C422, (30000, 1001));
+        let line = header.to_string();
+        assert_eq!(
+            line,
+            "YUV4MPEG2 W640 H480 F30000:1001 Ip A1:1 C422 XYSCSS=422 XCOLORRANGE=LIMITED"
+        );
+        assert_eq!(Header::parse(&line).unwrap(), header);
+        let ffmpeg = Header::parse("YUV4MPEG2 W4 H2 F25:1 It A0:0 C420mpeg2").unwrap();
+        assert_eq!(ffmpeg, Header::new((4, 2), Chroma::C420, (25, 1)));
+        assert!(Header::parse("YUV4MPEG2 W4 H2 C420p10").is_err());
+        assert!(Header::parse("YUV4MPEG2 H2").is_err());
+        for size in ["W4000000000 H4000000000", "W4000000 H4000000", "W0 H2"] {
+            let err = Header::parse(&format!("YUV4MPEG2 {size}")).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidData, "{size}");
+        }
+    }
+
+    #[test]
+    fn write_and_read_frames() {
+        let header = Header::new((3, 2), Chroma::C420, (25, 1));
+        let mut writer = Writer::new(Vec::new(), header).unwrap();
+        let yuyv = [10, 100, 20, 200, 30, 110, 0, 210, 40, 120, 50, 220, 60, 130, 0, 230];
+        let frame = FrameBuf::new(&yuyv, (3, 2), PixelFormat::Yuyv, 8, Duration::ZERO);
+        writer.write_frame_buf(&frame).unwrap();
+        writer.write_frame_buf(&frame).unwrap();
+        let small = FrameBuf::new(&[0; 4], (2, 2), PixelFormat::Gray, 2, Duration::ZERO);
+        assert!(writer.write_frame_buf(&small).is_err());
+        let file = writer.into_inner().unwrap();
+        assert!(file.starts_with(b"YUV4MPEG2 W3 H2 F25:1 Ip A1:1 C420jpeg"));
+
+        let mut reader = Reader::new(Cursor::new(file)).unwrap();
+        assert_eq!(reader.header(), &header);
+        let first = reader.read_frame().unwrap().unwrap();
+        assert_eq!(first.format(), PixelFormat::I420);
+        assert_eq!(first.data_u8(), [10, 20, 30, 40, 50, 60, 110, 120, 210, 220]);
+        let second = reader.next().unwrap().unwrap();
+        assert_eq!(second.timestamp(), Duration::from_millis(40));
+        assert!(reader.next().is_none());
+    }
+
+    #[test]
+    fn truncated_frame_is_an_error() {
+        let file = b"YUV4MPEG2 W2 H2 Cmono\nFRAME\n\x01\x02".to_vec();
+        let mut reader = Reader::new(Cursor::new(file)).unwrap();
+        assert_eq!(reader.header().chroma, Chroma::Mono);
+        assert!(reader.read_frame().is_err());
+    }
+
+    #[test]
+    fn writes_native_frames() {
+        let options = crate::VirtualOptions {
+            size: (6, 4),
+            format: PixelFormat::I420,
+            pacing: crate::Pacing::AsFastAsPossible,
+            ..Default::default()
+        };
+        let camera = crate::Camera::open_virtual(options).unwrap();
+        camera.start();
+        let frame = camera.wait_for_frame().unwrap();
+        let header = Header::new((6, 4), Chroma::C420, (30, 1));
+        let mut writer = Writer::new(Vec::new(), header).unwrap();
+        writer.write_frame(&frame).unwrap();
+        let file = writer.into_inner().unwrap();
+        assert!(file.ends_with(frame.native().unwrap().data_u8()));
+
+        let mut writer = Writer::new(Vec::new(), header).unwrap();
+        let short = writer.write(&[0; 10], (6, 4), PixelFormat::I420, 6);
+        assert_eq!(short.unwrap_err().kind(), io::ErrorKind::InvalidInput);
+    }
+}
@@ -1,4 +1,4 @@
-use kamera::Camera;
+use kamera::{Camera, Delivery, ImageFormat, Recorder};
 
 #[test]
 fn new_default_device() {
@@ -92,6 +92,109 @@ fn two_cameras_start_and_wait_for_frames() {
     println!("Camera 2 {:?}", camera2.wait_for_frame());
 }
 
+#[test]
+fn two_subscribers_share_one_device() {
+    let camera = Camera::new_default_device();
+    let mut preview = camera.subscribe(Delivery::Latest);
+    let mut recorder = camera.subscribe(Delivery::Queue(8));
+    assert!(preview.wait_for_frame().is_some());
+    assert!(recorder.wait_for_frame().is_some());
+    drop(preview);
+    assert!(recorder.wait_for_frame().is_some());
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn forced_io_mode() {
+    let camera = Camera::new_default_device();
+    camera.set_io_mode(Some(kamera::IoMode::UserPtr));
+    camera.start();
+    assert_eq!(camera.io_mode(), Some(kamera::IoMode::UserPtr));
+    assert!(camera.wait_for_frame().is_some());
+    camera.stop();
+    assert_eq!(camera.io_mode(), None);
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn dmabuf_until_dropped() {
+    let camera = Camera::new_default_device();
+    camera.start();
+    let frame = camera.wait_for_frame().unwrap();
+    assert!(frame.dmabuf_fd().is_some());
+    // the held buffer doesn't stop the others
+    assert!(camera.wait_for_frame().is_some());
+    drop(frame);
+    camera.stop();
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn set_mode_from_modes() {
+    let camera = Camera::new_default_device();
+    let modes = camera.modes();
+    println!("{modes:?}");
+    let mode = modes.iter().find(|mode| mode.format.is_some()).unwrap();
+    let size = match &mode.sizes {
+        kamera::FrameSizes::Discrete(sizes) => sizes[0],
+        kamera::FrameSizes::Stepwise { min, .. } => *min,
+    };
+    let config = kamera::ModeConfig { format: mode.format, size: Some(size), frame_rate: None };
+    let applied = camera.set_mode(config).unwrap();
+    assert_eq!((applied.format, applied.size), (config.format, config.size));
+    camera.start();
+    assert_eq!(camera.wait_for_frame().unwrap().size_u32(), size);
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn set_frame_rate() {
+    let camera = Camera::new_default_device();
+    camera.start();
+    let applied = camera.set_frame_rate(15, 1).unwrap();
+    assert_eq!(camera.frame_rate(), Some(applied));
+    assert!(camera.wait_for_frame().is_some());
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn low_latency_preview() {
+    let camera = Camera::new_default_device();
+    camera.set_buffer_count(2);
+    camera.set_drain_to_newest(true);
+    camera.start();
+    let frame = camera.wait_for_frame().unwrap();
+    println!("latency {:?}", frame.latency());
+    assert!(frame.latency().unwrap() < std::time::Duration::from_secs(1));
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn open_by_stable_id() {
+    let info = Camera::devices().into_iter().next().unwrap();
+    println!("{info:?} {}", info.stable_id());
+    let camera = Camera::open_by_id(&info.stable_id()).unwrap();
+    assert_eq!(camera.device_info(), Some(info));
+    camera.start();
+    assert!(camera.wait_for_frame().is_some());
+    assert!(Camera::open_by_id("no-such-camera").is_err());
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn uvc_metadata() {
+    let devices = Camera::devices();
+    assert!(devices.iter().all(|info| Some(&info.path) != info.metadata.as_ref()));
+    let Some(info) = devices.into_iter().find(|info| info.metadata.is_some()) else { return };
+    let camera = Camera::open_by_id(&info.stable_id()).unwrap();
+    camera.set_uvc_metadata(true);
+    camera.start();
+    camera.wait_for_frame().unwrap();
+    let frame = camera.wait_for_frame().unwrap();
+    println!("{:?}", frame.uvc_metadata());
+    assert!(!frame.uvc_metadata().is_empty());
+}
+
 #[test]
 fn change_device() {
     let mut camera = Camera::new_default_device();
@@ -104,3 +207,23 @@ fn change_device() {
     assert!(camera.wait_for_frame().is_some());
     assert!(camera.wait_for_frame().is_some());
 }
+
+#[test]
+fn snapshot() {
+    let camera = Camera::new_default_device();
+    camera.start();
+    let snapshot = camera.snapshot(ImageFormat::Jpeg { quality: 80 }).unwrap();
+    println!("{:?} {:?} {} bytes", snapshot.size, snapshot.metadata, snapshot.data.len());
+    assert!(snapshot.data.starts_with(&[0xFF, 0xD8]));
+}
+
+#[test]
+fn record() {
+    let camera = Camera::new_default_device();
+    camera.start();
+    let mut recorder = Recorder::new(std::io::Cursor::new(Vec::new()));
+    recorder.record(&camera, 10).unwrap();
+    let avi = recorder.finish().unwrap().into_inner();
+    println!("{} bytes", avi.len());
+    assert!(avi.starts_with(b"RIFF"));
+}